@@ -3,6 +3,20 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use rlox::VM;
 
+pub fn arithmetic(c: &mut Criterion) {
+    let src = r#"
+fun step(n) { return n * 3 % 7; }
+var sum = 0;
+for (var i = 0; i < 100000; i = i + 1) {
+  if (i % 2 == 0) sum = sum + step(i); else sum = sum - i / 4;
+}
+"#;
+    c.bench_function("arithmetic", |b| {
+        let mut vm = VM::default();
+        b.iter(|| vm.interpret(black_box(src)));
+    });
+}
+
 pub fn binary_trees(c: &mut Criterion) {
     let src = r#"
 class Tree {
@@ -313,6 +327,7 @@ while (sum < 10000) {
 
 criterion_group!(
     basics,
+    arithmetic,
     binary_trees,
     equality,
     fib,
@@ -0,0 +1,429 @@
+//! Reading and writing compiled scripts, so a script can be compiled once and run many times
+//! without going through the compiler again.
+//!
+//! A bytecode file starts with [`BYTECODE_MAGIC`] followed by the script function. Numbers are
+//! stored in little-endian order, and strings are stored as their length followed by their
+//! UTF-8 bytes. Only the values that the compiler puts in constant tables can be stored.
+//!
+//! ```
+//! use rlox::{Compiler, SharedBuffer, VM};
+//!
+//! let src = r#"
+//!     fun greet(name, greeting = "Hello") { return greeting + ", " + name; }
+//!     var names = ["Ann", "Bob"];
+//!     for (name in names) print greet(name);
+//!     print 1.5 * 2, -3;
+//! "#;
+//! let mut compiler = Compiler::new(src);
+//! compiler.compile();
+//! let script = compiler.finish().unwrap();
+//!
+//! let mut bytes = Vec::new();
+//! rlox::write_bytecode(&script, &mut bytes).unwrap();
+//! assert!(rlox::is_bytecode(&bytes));
+//! assert!(!rlox::is_bytecode(src.as_bytes()));
+//!
+//! let script = rlox::read_bytecode(&bytes[..]).unwrap();
+//! let output = SharedBuffer::default();
+//! let mut vm = VM::default().with_output(output.clone());
+//! vm.interpret_fun(script).unwrap();
+//! assert_eq!(
+//!     output.contents(),
+//!     rlox::interpret_to_string(src).unwrap()
+//! );
+//!
+//! assert!(rlox::read_bytecode(&bytes[..bytes.len() - 1]).is_err());
+//! ```
+
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+    rc::Rc,
+};
+
+use crate::{intern, Chunk, MethodKind, ObjFun, OpCode, Position, Upvalue, Value};
+
+/// The bytes that every bytecode file starts with, the last one is the version of the format
+pub const BYTECODE_MAGIC: &[u8; 5] = b"LOXC\x02";
+
+/// Return true if the given bytes start like a bytecode file
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(BYTECODE_MAGIC)
+}
+
+/// Write a compiled script to the output. This fails if the script holds a constant that
+/// can't be stored, which the compiler never produces.
+pub fn write_bytecode<W: Write>(script: &ObjFun, output: W) -> io::Result<()> {
+    let mut writer = Writer(output);
+    writer.bytes(BYTECODE_MAGIC)?;
+    writer.fun(script)?;
+    writer.0.flush()
+}
+
+/// Read a script that was written by [`write_bytecode`]. The functions are checked with
+/// [`ObjFun::validate`] and a file that fails the checks is rejected, though the checks don't
+/// cover every way a crafted file could make the virtual machine fail.
+pub fn read_bytecode<R: Read>(input: R) -> io::Result<ObjFun> {
+    let mut reader = Reader(input);
+    let mut magic = [0; BYTECODE_MAGIC.len()];
+    reader.0.read_exact(&mut magic)?;
+    if &magic != BYTECODE_MAGIC {
+        return Err(invalid_data("Not a Lox bytecode file"));
+    }
+    let script = reader.fun()?;
+    script
+        .validate()
+        .map_err(|err| invalid_data(&err.to_string()))?;
+    Ok(script)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+struct Writer<W>(W);
+
+impl<W: Write> Writer<W> {
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.0.write_all(bytes)
+    }
+
+    fn u8(&mut self, n: u8) -> io::Result<()> {
+        self.bytes(&[n])
+    }
+
+    fn u16(&mut self, n: u16) -> io::Result<()> {
+        self.bytes(&n.to_le_bytes())
+    }
+
+    fn usize(&mut self, n: usize) -> io::Result<()> {
+        let n = u32::try_from(n).map_err(|_| invalid_data("Number is too large"))?;
+        self.bytes(&n.to_le_bytes())
+    }
+
+    fn str(&mut self, s: &str) -> io::Result<()> {
+        self.usize(s.len())?;
+        self.bytes(s.as_bytes())
+    }
+
+    fn fun(&mut self, fun: &ObjFun) -> io::Result<()> {
+        self.str(&intern::str(fun.name))?;
+        self.u8(fun.arity)?;
+        self.usize(fun.defaults.len())?;
+        for default in fun.defaults.iter() {
+            self.usize(*default)?;
+        }
+        self.usize(fun.body_start)?;
+        self.u8(fun.variadic as u8)?;
+        self.usize(fun.locals)?;
+        self.usize(fun.upvalues)?;
+        self.chunk(&fun.chunk)
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) -> io::Result<()> {
+        self.usize(chunk.const_count())?;
+        for idx in 0..chunk.const_count() {
+            self.value(chunk.read_const(idx))?;
+        }
+        self.usize(chunk.instructions_count())?;
+        for idx in 0..chunk.instructions_count() {
+            let (opcode, pos) = chunk.read_instruction(idx);
+            self.opcode(opcode)?;
+            self.usize(pos.line)?;
+            self.usize(pos.column)?;
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, value: &Value) -> io::Result<()> {
+        match value {
+            Value::Nil => self.u8(0),
+            Value::Bool(b) => {
+                self.u8(1)?;
+                self.u8(*b as u8)
+            }
+            Value::Int(n) => {
+                self.u8(2)?;
+                self.bytes(&n.to_le_bytes())
+            }
+            Value::Number(n) => {
+                self.u8(3)?;
+                self.bytes(&n.to_bits().to_le_bytes())
+            }
+            Value::Str(s) => {
+                self.u8(4)?;
+                self.str(&intern::str(*s))
+            }
+            Value::Fun(fun) => {
+                self.u8(5)?;
+                self.fun(fun)
+            }
+            value => Err(invalid_data(&format!(
+                "Can't store a {} in bytecode",
+                value.type_name()
+            ))),
+        }
+    }
+
+    fn opcode(&mut self, opcode: &OpCode) -> io::Result<()> {
+        match opcode {
+            OpCode::Constant(n) => self.bytes(&[0, *n]),
+            OpCode::SmallInt(n) => self.bytes(&[1, *n as u8]),
+            OpCode::Nil => self.u8(2),
+            OpCode::True => self.u8(3),
+            OpCode::False => self.u8(4),
+            OpCode::Pop => self.u8(5),
+            OpCode::Dup => self.u8(6),
+            OpCode::Swap => self.u8(7),
+            OpCode::GetLocal(n) => self.bytes(&[8, *n]),
+            OpCode::SetLocal(n) => self.bytes(&[9, *n]),
+            OpCode::IncLocal(n) => self.bytes(&[10, *n]),
+            OpCode::LessLocalConst(slot, n) => self.bytes(&[11, *slot, *n]),
+            OpCode::GetGlobal(n) => self.bytes(&[12, *n]),
+            OpCode::DefineGlobal(n) => self.bytes(&[13, *n]),
+            OpCode::SetGlobal(n) => self.bytes(&[14, *n]),
+            OpCode::GetGlobalSlot(n) => self.bytes(&[15, *n]),
+            OpCode::GetUpvalue(n) => self.bytes(&[16, *n]),
+            OpCode::SetUpvalue(n) => self.bytes(&[17, *n]),
+            OpCode::GetProperty(n) => self.bytes(&[18, *n]),
+            OpCode::SetProperty(n) => self.bytes(&[19, *n]),
+            OpCode::GetSuper(n) => self.bytes(&[20, *n]),
+            OpCode::Equal => self.u8(21),
+            OpCode::Greater => self.u8(22),
+            OpCode::Less => self.u8(23),
+            OpCode::Add => self.u8(24),
+            OpCode::Subtract => self.u8(25),
+            OpCode::Multiply => self.u8(26),
+            OpCode::Divide => self.u8(27),
+            OpCode::Modulo => self.u8(28),
+            OpCode::FloorDivide => self.u8(29),
+            OpCode::Not => self.u8(30),
+            OpCode::Negate => self.u8(31),
+            OpCode::BitAnd => self.u8(32),
+            OpCode::BitOr => self.u8(33),
+            OpCode::BitXor => self.u8(34),
+            OpCode::Shl => self.u8(35),
+            OpCode::Shr => self.u8(36),
+            OpCode::BitNot => self.u8(37),
+            OpCode::Print => self.u8(38),
+            OpCode::PrintN(n) => self.bytes(&[39, *n]),
+            OpCode::Jump(offset) => {
+                self.u8(40)?;
+                self.u16(*offset)
+            }
+            OpCode::JumpIfFalse(offset) => {
+                self.u8(41)?;
+                self.u16(*offset)
+            }
+            OpCode::JumpIfNil(offset) => {
+                self.u8(42)?;
+                self.u16(*offset)
+            }
+            OpCode::Loop(offset) => {
+                self.u8(43)?;
+                self.u16(*offset)
+            }
+            OpCode::Call(n) => self.bytes(&[44, *n]),
+            OpCode::Invoke(name, argc) => self.bytes(&[45, *name, *argc]),
+            OpCode::SuperInvoke(name, argc) => self.bytes(&[46, *name, *argc]),
+            OpCode::Closure(n, upvalues) => {
+                self.bytes(&[47, *n])?;
+                self.usize(upvalues.len())?;
+                for upvalue in upvalues.iter() {
+                    self.bytes(&[upvalue.index, upvalue.is_local as u8])?;
+                }
+                Ok(())
+            }
+            OpCode::CloseUpvalue => self.u8(48),
+            OpCode::Return => self.u8(49),
+            OpCode::Class(n) => self.bytes(&[50, *n]),
+            OpCode::Inherit => self.u8(51),
+            OpCode::Method(n, kind) => {
+                let kind = match kind {
+                    MethodKind::Method => 0,
+                    MethodKind::Getter => 1,
+                    MethodKind::Static => 2,
+                };
+                self.bytes(&[52, *n, kind])
+            }
+            OpCode::BuildList(n) => self.bytes(&[53, *n]),
+            OpCode::BuildMap(n) => self.bytes(&[54, *n]),
+            OpCode::Index => self.u8(55),
+            OpCode::SetIndex => self.u8(56),
+            OpCode::Len => self.u8(57),
+        }
+    }
+}
+
+struct Reader<R>(R);
+
+impl<R: Read> Reader<R> {
+    fn u8(&mut self) -> io::Result<u8> {
+        let mut buf = [0; 1];
+        self.0.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let mut buf = [0; 2];
+        self.0.read_exact(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        self.0.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn usize(&mut self) -> io::Result<usize> {
+        let mut buf = [0; 4];
+        self.0.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf) as usize)
+    }
+
+    fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data("Invalid boolean")),
+        }
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        let len = self.usize()?;
+        let mut buf = Vec::new();
+        (&mut self.0).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        String::from_utf8(buf).map_err(|_| invalid_data("Invalid string"))
+    }
+
+    fn fun(&mut self) -> io::Result<ObjFun> {
+        let mut fun = ObjFun::new(intern::id(self.str()?));
+        fun.arity = self.u8()?;
+        for _ in 0..self.usize()? {
+            fun.defaults.push(self.usize()?);
+        }
+        if fun.defaults.len() > fun.arity as usize {
+            return Err(invalid_data("Too many default values"));
+        }
+        fun.body_start = self.usize()?;
+        fun.variadic = self.bool()?;
+        fun.locals = self.usize()?;
+        fun.upvalues = self.usize()?;
+        fun.chunk = self.chunk()?;
+        Ok(fun)
+    }
+
+    fn chunk(&mut self) -> io::Result<Chunk> {
+        let mut chunk = Chunk::default();
+        for _ in 0..self.usize()? {
+            let value = self.value()?;
+            chunk.write_const(value);
+        }
+        for _ in 0..self.usize()? {
+            let opcode = self.opcode()?;
+            let (line, column) = (self.usize()?, self.usize()?);
+            chunk.write_instruction(opcode, Position { line, column });
+        }
+        Ok(chunk)
+    }
+
+    fn value(&mut self) -> io::Result<Value> {
+        let value = match self.u8()? {
+            0 => Value::Nil,
+            1 => Value::Bool(self.bool()?),
+            2 => Value::Int(self.u64()? as i64),
+            3 => Value::Number(f64::from_bits(self.u64()?)),
+            4 => Value::Str(intern::id(self.str()?)),
+            5 => Value::Fun(Rc::new(self.fun()?)),
+            tag => return Err(invalid_data(&format!("Unknown constant type {}", tag))),
+        };
+        Ok(value)
+    }
+
+    fn opcode(&mut self) -> io::Result<OpCode> {
+        let opcode = match self.u8()? {
+            0 => OpCode::Constant(self.u8()?),
+            1 => OpCode::SmallInt(self.u8()? as i8),
+            2 => OpCode::Nil,
+            3 => OpCode::True,
+            4 => OpCode::False,
+            5 => OpCode::Pop,
+            6 => OpCode::Dup,
+            7 => OpCode::Swap,
+            8 => OpCode::GetLocal(self.u8()?),
+            9 => OpCode::SetLocal(self.u8()?),
+            10 => OpCode::IncLocal(self.u8()?),
+            11 => OpCode::LessLocalConst(self.u8()?, self.u8()?),
+            12 => OpCode::GetGlobal(self.u8()?),
+            13 => OpCode::DefineGlobal(self.u8()?),
+            14 => OpCode::SetGlobal(self.u8()?),
+            15 => OpCode::GetGlobalSlot(self.u8()?),
+            16 => OpCode::GetUpvalue(self.u8()?),
+            17 => OpCode::SetUpvalue(self.u8()?),
+            18 => OpCode::GetProperty(self.u8()?),
+            19 => OpCode::SetProperty(self.u8()?),
+            20 => OpCode::GetSuper(self.u8()?),
+            21 => OpCode::Equal,
+            22 => OpCode::Greater,
+            23 => OpCode::Less,
+            24 => OpCode::Add,
+            25 => OpCode::Subtract,
+            26 => OpCode::Multiply,
+            27 => OpCode::Divide,
+            28 => OpCode::Modulo,
+            29 => OpCode::FloorDivide,
+            30 => OpCode::Not,
+            31 => OpCode::Negate,
+            32 => OpCode::BitAnd,
+            33 => OpCode::BitOr,
+            34 => OpCode::BitXor,
+            35 => OpCode::Shl,
+            36 => OpCode::Shr,
+            37 => OpCode::BitNot,
+            38 => OpCode::Print,
+            39 => OpCode::PrintN(self.u8()?),
+            40 => OpCode::Jump(self.u16()?),
+            41 => OpCode::JumpIfFalse(self.u16()?),
+            42 => OpCode::JumpIfNil(self.u16()?),
+            43 => OpCode::Loop(self.u16()?),
+            44 => OpCode::Call(self.u8()?),
+            45 => OpCode::Invoke(self.u8()?, self.u8()?),
+            46 => OpCode::SuperInvoke(self.u8()?, self.u8()?),
+            47 => {
+                let n = self.u8()?;
+                let mut upvalues = Vec::new();
+                for _ in 0..self.usize()? {
+                    let index = self.u8()?;
+                    let is_local = self.bool()?;
+                    upvalues.push(Upvalue { index, is_local });
+                }
+                OpCode::Closure(n, upvalues)
+            }
+            48 => OpCode::CloseUpvalue,
+            49 => OpCode::Return,
+            50 => OpCode::Class(self.u8()?),
+            51 => OpCode::Inherit,
+            52 => {
+                let n = self.u8()?;
+                let kind = match self.u8()? {
+                    0 => MethodKind::Method,
+                    1 => MethodKind::Getter,
+                    2 => MethodKind::Static,
+                    _ => return Err(invalid_data("Unknown method kind")),
+                };
+                OpCode::Method(n, kind)
+            }
+            53 => OpCode::BuildList(self.u8()?),
+            54 => OpCode::BuildMap(self.u8()?),
+            55 => OpCode::Index,
+            56 => OpCode::SetIndex,
+            57 => OpCode::Len,
+            tag => return Err(invalid_data(&format!("Unknown instruction {}", tag))),
+        };
+        Ok(opcode)
+    }
+}
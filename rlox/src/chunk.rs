@@ -1,4 +1,6 @@
-use crate::{OpCode, Position, Value};
+use std::{collections::BTreeMap, convert::TryFrom, fmt, mem};
+
+use crate::{MethodKind, ObjFun, OpCode, Position, Value, VerifyError};
 
 /// A chunk holds a sequence of instructions to be executes and their data
 ///
@@ -9,7 +11,7 @@ use crate::{OpCode, Position, Value};
 /// let const_id = chunk.write_const(Value::Number(1.0));
 /// assert!(matches!(chunk.read_const(const_id), &Value::Number(1.0)));
 ///
-/// chunk.write_instruction(OpCode::Constant(const_id), Position::default());
+/// chunk.write_instruction(OpCode::Constant(const_id as u8), Position::default());
 /// assert!(matches!(
 ///     chunk.read_instruction(0),
 ///     (&OpCode::Constant(cost_id), &Position { line: 1, column : 1 }),
@@ -23,6 +25,39 @@ pub struct Chunk {
 }
 
 impl Chunk {
+    /// Remove every instruction and constant while keeping the allocated memory, so the chunk can
+    /// be reused for compiling other code.
+    ///
+    /// ```
+    /// use rlox::Compiler;
+    ///
+    /// let mut compiler = Compiler::new("var a = 1; var b = 2; print a + b;");
+    /// compiler.compile();
+    /// let mut chunk = compiler.finish().unwrap().chunk;
+    /// let capacity = chunk.capacity();
+    ///
+    /// chunk.clear();
+    /// assert_eq!(chunk.instructions_count(), 0);
+    /// assert_eq!(chunk.const_count(), 0);
+    /// assert_eq!(chunk.capacity(), capacity);
+    ///
+    /// let mut compiler = Compiler::new("print 1;").with_chunk(chunk);
+    /// compiler.compile();
+    /// let fun = compiler.finish().unwrap();
+    /// assert_eq!(fun.chunk.capacity(), capacity);
+    /// assert!(rlox::VM::default().interpret_fun(fun).is_ok());
+    /// ```
+    pub fn clear(&mut self) {
+        self.instructions.clear();
+        self.constants.clear();
+        self.positions.clear();
+    }
+
+    /// Return the number of instructions that the chunk can hold without allocating more memory
+    pub fn capacity(&self) -> usize {
+        self.instructions.capacity()
+    }
+
     /// Add a new instruction to the chunk.
     pub fn write_instruction(&mut self, code: OpCode, pos: Position) {
         self.instructions.push(code);
@@ -34,21 +69,68 @@ impl Chunk {
         (&self.instructions[idx], &self.positions[idx])
     }
 
-    /// Return the index of the last written instruction.
+    /// Return the number of instructions that have been written, which is also the index of the
+    /// next instruction.
+    ///
+    /// ```
+    /// use rlox::{Chunk, OpCode, Position};
+    ///
+    /// let mut chunk = Chunk::default();
+    /// assert_eq!(chunk.instructions_count(), 0);
+    /// chunk.write_instruction(OpCode::Nil, Position::default());
+    /// chunk.write_instruction(OpCode::Return, Position::default());
+    /// assert_eq!(chunk.instructions_count(), 2);
+    /// ```
     pub fn instructions_count(&self) -> usize {
         self.instructions.len()
     }
 
     /// Replace the jump offset at the given jump instruction
+    ///
+    /// ```
+    /// use rlox::{Chunk, OpCode, Position};
+    ///
+    /// let mut chunk = Chunk::default();
+    /// chunk.write_instruction(OpCode::Jump(u16::MAX), Position::default());
+    /// chunk.write_instruction(OpCode::Nil, Position::default());
+    /// chunk.patch_jump_instruction(0, 1);
+    /// assert!(matches!(chunk.read_instruction(0).0, OpCode::Jump(1)));
+    /// ```
     pub fn patch_jump_instruction(&mut self, jump: usize, offset: u16) {
         match self.instructions[jump] {
-            OpCode::Jump(ref mut placeholder) | OpCode::JumpIfFalse(ref mut placeholder) => {
+            OpCode::Jump(ref mut placeholder)
+            | OpCode::JumpIfFalse(ref mut placeholder)
+            | OpCode::JumpIfNil(ref mut placeholder) => {
                 *placeholder = offset;
             }
             _ => unreachable!(),
         }
     }
 
+    /// Replace the last instruction, this is used for folding an instruction into the one that
+    /// comes before it
+    ///
+    /// ```
+    /// use rlox::{Chunk, OpCode, Position};
+    ///
+    /// let mut chunk = Chunk::default();
+    /// chunk.write_instruction(OpCode::True, Position::default());
+    /// chunk.replace_last_instruction(OpCode::False);
+    /// assert_eq!(chunk.instructions_count(), 1);
+    /// assert!(matches!(chunk.read_instruction(0).0, OpCode::False));
+    /// ```
+    pub fn replace_last_instruction(&mut self, code: OpCode) {
+        if let Some(last) = self.instructions.last_mut() {
+            *last = code;
+        }
+    }
+
+    /// Remove the last instruction and return it
+    pub fn pop_instruction(&mut self) -> Option<OpCode> {
+        self.positions.pop();
+        self.instructions.pop()
+    }
+
     /// Add a constant value to the chunk and return it position in the Vec
     pub fn write_const(&mut self, val: Value) -> usize {
         self.constants.push(val);
@@ -60,40 +142,471 @@ impl Chunk {
         &self.constants[idx]
     }
 
-    /// Get the number of constants stored in the chunk
+    /// Remove the last constant and return it, the instructions that load it must be removed
+    /// as well
+    pub fn pop_const(&mut self) -> Option<Value> {
+        self.constants.pop()
+    }
+
+    /// Replace the constant at the given index, every instruction that loads it gets the new
+    /// value
+    pub fn patch_const(&mut self, idx: usize, val: Value) {
+        self.constants[idx] = val;
+    }
+
+    /// Get the number of constants stored in the chunk. The compiler reuses the constant of a
+    /// string that it has already seen, whether it's a literal or a variable name.
+    ///
+    /// ```
+    /// use rlox::{Chunk, Compiler, Value};
+    ///
+    /// let mut chunk = Chunk::default();
+    /// chunk.write_const(Value::Nil);
+    /// chunk.write_const(Value::Int(1));
+    /// assert_eq!(chunk.const_count(), 2);
+    ///
+    /// let mut compiler = Compiler::new(r#"print "x"; print "x"; var x = "x";"#);
+    /// compiler.compile();
+    /// let fun = compiler.finish().unwrap();
+    /// assert_eq!(fun.chunk.const_count(), 1);
+    /// ```
     pub fn const_count(&self) -> usize {
         self.constants.len()
     }
+
+    // Check the chunk of the given function, see `ObjFun::validate`
+    pub(crate) fn validate(&self, fun: &ObjFun) -> Result<(), VerifyError> {
+        for (at, code) in self.instructions.iter().enumerate() {
+            let constant = |index: u8| {
+                self.constants
+                    .get(index as usize)
+                    .ok_or(VerifyError::InvalidConstant {
+                        at,
+                        index: index as usize,
+                    })
+            };
+            let name = |index: u8| match constant(index)? {
+                Value::Str(_) => Ok(()),
+                _ => Err(VerifyError::ConstantType {
+                    at,
+                    index: index as usize,
+                }),
+            };
+            let local = |slot: u8| {
+                if (slot as usize) < fun.locals {
+                    Ok(())
+                } else {
+                    Err(VerifyError::InvalidLocal { at, slot })
+                }
+            };
+            let upvalue = |index: u8| {
+                if (index as usize) < fun.upvalues {
+                    Ok(())
+                } else {
+                    Err(VerifyError::InvalidUpvalue { at, index })
+                }
+            };
+            let jump = |target: Option<usize>| match target {
+                Some(target) if target < self.instructions.len() => Ok(()),
+                _ => Err(VerifyError::InvalidJump { at }),
+            };
+
+            match code {
+                OpCode::Constant(index) => constant(*index).map(|_| ())?,
+                OpCode::GetGlobal(index)
+                | OpCode::DefineGlobal(index)
+                | OpCode::SetGlobal(index)
+                | OpCode::GetProperty(index)
+                | OpCode::SetProperty(index)
+                | OpCode::GetSuper(index)
+                | OpCode::Class(index)
+                | OpCode::Method(index, _)
+                | OpCode::Invoke(index, _)
+                | OpCode::SuperInvoke(index, _) => name(*index)?,
+                OpCode::GetLocal(slot) | OpCode::SetLocal(slot) | OpCode::IncLocal(slot) => {
+                    local(*slot)?
+                }
+                OpCode::LessLocalConst(slot, index) => {
+                    local(*slot)?;
+                    constant(*index)?;
+                }
+                OpCode::GetUpvalue(index) | OpCode::SetUpvalue(index) => upvalue(*index)?,
+                OpCode::Jump(offset) | OpCode::JumpIfFalse(offset) | OpCode::JumpIfNil(offset) => {
+                    jump(Some(at + 1 + *offset as usize))?
+                }
+                OpCode::Loop(offset) => jump((at + 1).checked_sub(*offset as usize))?,
+                OpCode::Closure(index, upvalues) => {
+                    let inner = match constant(*index)? {
+                        Value::Fun(inner) => inner,
+                        _ => {
+                            return Err(VerifyError::ConstantType {
+                                at,
+                                index: *index as usize,
+                            })
+                        }
+                    };
+                    // the closure is created with one upvalue for each of the captured variables
+                    if upvalues.len() != inner.upvalues {
+                        return Err(VerifyError::UpvalueCount { at });
+                    }
+                    for captured in upvalues.iter() {
+                        if captured.is_local {
+                            local(captured.index)?;
+                        } else {
+                            upvalue(captured.index)?;
+                        }
+                    }
+                    inner.validate()?;
+                }
+                _ => {}
+            }
+        }
+        match self.instructions.last() {
+            Some(OpCode::Return) => {}
+            _ => return Err(VerifyError::MissingReturn),
+        }
+        self.validate_stack(fun)
+    }
+
+    // Follow every path through the chunk to check that no instruction pops more values than
+    // the frame holds, and that the paths that meet hold the same number of values. The frame
+    // starts with the function and its arguments.
+    fn validate_stack(&self, fun: &ObjFun) -> Result<(), VerifyError> {
+        let mut depths = vec![None; self.instructions.len()];
+        let mut pending = Vec::new();
+        let required = fun.arity as usize - fun.defaults.len();
+        let entries = fun
+            .defaults
+            .iter()
+            .enumerate()
+            .map(|(i, &entry)| (entry, 1 + required + i))
+            .chain(std::iter::once((fun.body_start, 1 + fun.arity as usize)));
+        for (at, depth) in entries {
+            if at >= self.instructions.len() {
+                return Err(VerifyError::InvalidEntry { at });
+            }
+            match depths[at] {
+                None => {
+                    depths[at] = Some(depth);
+                    pending.push(at);
+                }
+                Some(seen) if seen != depth => return Err(VerifyError::StackMismatch { at }),
+                _ => {}
+            }
+        }
+
+        while let Some(at) = pending.pop() {
+            let depth = depths[at].expect("Depth not recorded");
+            let code = &self.instructions[at];
+            let local = |slot: u8| {
+                if (slot as usize) < depth {
+                    Ok(())
+                } else {
+                    Err(VerifyError::InvalidLocal { at, slot })
+                }
+            };
+            match code {
+                OpCode::GetLocal(slot)
+                | OpCode::SetLocal(slot)
+                | OpCode::IncLocal(slot)
+                | OpCode::LessLocalConst(slot, _) => local(*slot)?,
+                OpCode::Closure(_, upvalues) => {
+                    // a function can capture itself, its closure is pushed right after
+                    for captured in upvalues.iter().filter(|captured| captured.is_local) {
+                        if captured.index as usize > depth {
+                            return Err(VerifyError::InvalidLocal {
+                                at,
+                                slot: captured.index,
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            let (pops, pushes) = stack_effect(code);
+            if depth < pops {
+                return Err(VerifyError::StackUnderflow { at });
+            }
+            let next_depth = depth - pops + pushes;
+            let next = at + 1;
+            let successors = match *code {
+                OpCode::Return => [None, None],
+                OpCode::Jump(offset) => [Some(next + offset as usize), None],
+                OpCode::JumpIfFalse(offset) | OpCode::JumpIfNil(offset) => {
+                    [Some(next), Some(next + offset as usize)]
+                }
+                OpCode::Loop(offset) => [Some(next - offset as usize), None],
+                _ => [Some(next), None],
+            };
+            for successor in successors.iter().flatten().copied() {
+                match depths[successor] {
+                    None => {
+                        depths[successor] = Some(next_depth);
+                        pending.push(successor);
+                    }
+                    Some(seen) if seen != next_depth => {
+                        return Err(VerifyError::StackMismatch { at: successor })
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Get the number of values that the instruction pops from the stack, and the number of values
+// that it pushes afterward
+fn stack_effect(code: &OpCode) -> (usize, usize) {
+    match *code {
+        OpCode::Constant(_)
+        | OpCode::SmallInt(_)
+        | OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::GetLocal(_)
+        | OpCode::LessLocalConst(_, _)
+        | OpCode::GetGlobal(_)
+        | OpCode::GetGlobalSlot(_)
+        | OpCode::GetUpvalue(_)
+        | OpCode::Closure(_, _)
+        | OpCode::Class(_) => (0, 1),
+        OpCode::Jump(_) | OpCode::Loop(_) | OpCode::IncLocal(_) => (0, 0),
+        OpCode::Pop
+        | OpCode::DefineGlobal(_)
+        | OpCode::Print
+        | OpCode::CloseUpvalue
+        | OpCode::Return => (1, 0),
+        OpCode::Dup => (1, 2),
+        OpCode::Swap => (2, 2),
+        OpCode::SetLocal(_)
+        | OpCode::SetGlobal(_)
+        | OpCode::SetUpvalue(_)
+        | OpCode::GetProperty(_)
+        | OpCode::Not
+        | OpCode::Negate
+        | OpCode::BitNot
+        | OpCode::Len
+        | OpCode::JumpIfFalse(_)
+        | OpCode::JumpIfNil(_) => (1, 1),
+        OpCode::SetProperty(_)
+        | OpCode::GetSuper(_)
+        | OpCode::Equal
+        | OpCode::Greater
+        | OpCode::Less
+        | OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Modulo
+        | OpCode::FloorDivide
+        | OpCode::BitAnd
+        | OpCode::BitOr
+        | OpCode::BitXor
+        | OpCode::Shl
+        | OpCode::Shr
+        | OpCode::Inherit
+        | OpCode::Method(_, _)
+        | OpCode::Index => (2, 1),
+        OpCode::SetIndex => (3, 1),
+        OpCode::PrintN(count) => (count as usize, 0),
+        OpCode::Call(argc) | OpCode::Invoke(_, argc) => (argc as usize + 1, 1),
+        OpCode::SuperInvoke(_, argc) => (argc as usize + 2, 1),
+        OpCode::BuildList(len) => (len as usize, 1),
+        OpCode::BuildMap(len) => (2 * len as usize, 1),
+    }
+}
+
+/// Writes a chunk by hand, for code that generates bytecode without going through Lox source.
+/// Instructions are written at the position that was last set, which is the start of the
+/// source by default.
+///
+/// ```
+/// use rlox::{ChunkBuilder, ObjFun, OpCode, Value, VM};
+///
+/// let mut builder = ChunkBuilder::default();
+/// let one = builder.constant(Value::Int(1));
+/// let two = builder.constant(Value::Int(2));
+/// builder.op(OpCode::Constant(one)).op(OpCode::Constant(two)).op(OpCode::Add);
+/// // skip over the subtraction
+/// builder.op(OpCode::False);
+/// let skip = builder.jump(OpCode::JumpIfFalse);
+/// builder.op(OpCode::Pop).op(OpCode::Constant(two)).op(OpCode::Subtract);
+/// builder.patch(skip);
+/// builder.op(OpCode::Pop).op(OpCode::Return);
+///
+/// let mut fun = ObjFun::new(rlox::id("script"));
+/// fun.chunk = builder.build();
+/// let res = VM::default().interpret_fun(fun).unwrap();
+/// assert!(matches!(res, Some(Value::Int(3))));
+/// ```
+#[derive(Debug, Default)]
+pub struct ChunkBuilder {
+    chunk: Chunk,
+    pos: Position,
+}
+
+/// A jump instruction whose offset has yet to be set, see [`ChunkBuilder::patch`]
+#[derive(Debug)]
+#[must_use]
+pub struct Patch(usize);
+
+impl ChunkBuilder {
+    /// Set the source position of the instructions that are written next
+    pub fn position(&mut self, pos: Position) -> &mut Self {
+        self.pos = pos;
+        self
+    }
+
+    /// Add a constant value to the chunk and return its index for loading it with
+    /// [`OpCode::Constant`]. Panics if the chunk already has 256 constants.
+    pub fn constant(&mut self, val: Value) -> u8 {
+        let const_id = self.chunk.write_const(val);
+        u8::try_from(const_id).expect("too many constants in one chunk")
+    }
+
+    /// Add an instruction to the chunk
+    pub fn op(&mut self, code: OpCode) -> &mut Self {
+        self.chunk.write_instruction(code, self.pos);
+        self
+    }
+
+    /// Add a forward jump instruction, e.g. `OpCode::Jump`, that lands on the instruction
+    /// written after it is patched
+    pub fn jump<O: Fn(u16) -> OpCode>(&mut self, op: O) -> Patch {
+        self.op(op(u16::MAX));
+        Patch(self.chunk.instructions_count())
+    }
+
+    /// Make the jump land on the next instruction that gets written. Panics if the jump is
+    /// longer than what the instruction can hold.
+    pub fn patch(&mut self, jump: Patch) {
+        let offset = self.chunk.instructions_count() - jump.0;
+        let offset = u16::try_from(offset).expect("too much code to jump over");
+        self.chunk.patch_jump_instruction(jump.0 - 1, offset);
+    }
+
+    /// Return the chunk that was built
+    pub fn build(self) -> Chunk {
+        self.chunk
+    }
+}
+
+/// A summary of the size of a chunk, its display lists the totals followed by how many times
+/// each kind of instruction appears.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChunkStats {
+    /// The number of instructions
+    pub instructions: usize,
+    /// The number of values in the constant table
+    pub constants: usize,
+    /// The memory taken by the instructions
+    pub bytes: usize,
+    /// The number of instructions of each kind, keyed by the instruction's name
+    pub opcodes: BTreeMap<&'static str, usize>,
+}
+
+impl fmt::Display for ChunkStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "instructions: {}", self.instructions)?;
+        writeln!(f, "constants: {}", self.constants)?;
+        writeln!(f, "bytes: {}", self.bytes)?;
+        for (name, count) in self.opcodes.iter() {
+            writeln!(f, "{:-16} {:4}", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Count the instructions and constants in the chunk, which is useful for seeing how the
+/// optimizations affect the size of the code.
+///
+/// ```
+/// use rlox::Compiler;
+///
+/// let mut compiler = Compiler::new("var a = 1.5; print a; print a;");
+/// compiler.compile();
+/// let fun = compiler.finish().unwrap();
+/// let stats = rlox::chunk_stats(&fun.chunk);
+/// assert_eq!(stats.instructions, fun.chunk.instructions_count());
+/// assert_eq!(stats.instructions, 8);
+/// assert_eq!(stats.constants, 2);
+/// assert_eq!(stats.opcodes["GetGlobal"], 2);
+/// assert_eq!(stats.opcodes["Print"], 2);
+/// assert!(!stats.opcodes.contains_key("Add"));
+/// assert!(stats.to_string().starts_with("instructions: 8\nconstants: 2\n"));
+/// ```
+pub fn chunk_stats(chunk: &Chunk) -> ChunkStats {
+    let mut opcodes = BTreeMap::new();
+    for code in chunk.instructions.iter() {
+        *opcodes.entry(code.name()).or_insert(0) += 1;
+    }
+    ChunkStats {
+        instructions: chunk.instructions.len(),
+        constants: chunk.constants.len(),
+        bytes: chunk.instructions.len() * mem::size_of::<OpCode>(),
+        opcodes,
+    }
 }
 
 /// Go through the instructions in the chunk and display them in human-readable format.
 #[cfg(debug_assertions)]
 pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
-    println!("== {} ==", name);
-    for i in 0..chunk.instructions.len() {
-        disassemble_instruction(chunk, i);
-    }
+    print!("{}", chunk_disassembly(chunk, name));
 }
 
 /// Display an instruction in human readable format.
 #[cfg(debug_assertions)]
 pub fn disassemble_instruction(chunk: &Chunk, inst_idx: usize) {
-    print!("{:04} ", inst_idx);
+    print!("{}", instruction_disassembly(chunk, inst_idx));
+}
+
+/// Return the human-readable listing that [`disassemble_chunk`] displays. Jumps show the index
+/// of the instruction they land on, whether they go forward or backward. Unlike the functions
+/// that print the listing, this is also available in release builds.
+///
+/// ```
+/// use rlox::Compiler;
+///
+/// let mut compiler = Compiler::new("var i = 0;\nwhile (i < 3) i = i + 1;");
+/// compiler.compile();
+/// let fun = compiler.finish().unwrap();
+/// let listing = rlox::chunk_disassembly(&fun.chunk, "script");
+/// let lines: Vec<&str> = listing.lines().collect();
+/// assert_eq!(lines[0], "== script ==");
+/// // the condition starts at the 3rd instruction, which is where the loop jumps back to
+/// assert!(lines[3].starts_with("0002    2 OP_GET_GLOBAL"));
+/// assert!(lines[5].starts_with("0004    | OP_LESS"));
+/// assert_eq!(lines[6], "0005    | OP_JUMP_IF_FALSE    5 -> 13");
+/// assert_eq!(lines[13], "0012    | OP_LOOP            12 -> 2");
+/// assert_eq!(lines[14], "0013    | OP_POP");
+/// ```
+pub fn chunk_disassembly(chunk: &Chunk, name: &str) -> String {
+    let mut listing = format!("== {} ==\n", name);
+    for i in 0..chunk.instructions.len() {
+        listing.push_str(&instruction_disassembly(chunk, i));
+    }
+    listing
+}
+
+fn instruction_disassembly(chunk: &Chunk, inst_idx: usize) -> String {
+    let mut line = format!("{:04} ", inst_idx);
     if inst_idx > 0 && chunk.positions[inst_idx].line == chunk.positions[inst_idx - 1].line {
-        print!("   | ");
+        line.push_str("   | ");
     } else {
-        print!("{:4} ", chunk.positions[inst_idx].line);
+        line.push_str(&format!("{:4} ", chunk.positions[inst_idx].line));
     }
 
     let constant_instruction = |op_repr: &str, const_id: u8| {
-        println!(
+        format!(
             "{:-16} {:4} {}",
             op_repr,
             const_id,
             chunk.read_const(const_id as usize)
-        );
+        )
     };
-    let byte_instruction = |op_repr: &str, slot: u8| println!("{:-16} {:4}", op_repr, slot);
+    let byte_instruction = |op_repr: &str, slot: u8| format!("{:-16} {:4}", op_repr, slot);
     let jump_instruction = |op_repr: &str, jump: usize, offset: u16, fwd: bool| {
         // +1 since the instruction pointer is increased right after we read an opcode
         let jump_target = if fwd {
@@ -101,68 +614,106 @@ pub fn disassemble_instruction(chunk: &Chunk, inst_idx: usize) {
         } else {
             jump + 1 - offset as usize
         };
-        println!("{:-16} {:4} -> {}", op_repr, jump, jump_target);
+        format!("{:-16} {:4} -> {}", op_repr, jump, jump_target)
     };
     let invoke_instruction = |op_repr: &str, const_id: u8, argc: u8| {
-        println!(
+        format!(
             "{:-16} ({} args) {:4} {}",
             op_repr,
             argc,
             const_id,
             chunk.read_const(const_id as usize)
-        );
+        )
     };
 
-    match chunk.instructions[inst_idx] {
+    let op = match chunk.instructions[inst_idx] {
         OpCode::Constant(ref const_id) => constant_instruction("OP_CONSTANT", *const_id),
-        OpCode::Nil => println!("OP_NIL"),
-        OpCode::True => println!("OP_TRUE"),
-        OpCode::False => println!("OP_FALSE"),
-        OpCode::Pop => println!("OP_POP"),
+        OpCode::SmallInt(ref n) => format!("{:-16} {:4}", "OP_SMALL_INT", n),
+        OpCode::Nil => "OP_NIL".to_string(),
+        OpCode::True => "OP_TRUE".to_string(),
+        OpCode::False => "OP_FALSE".to_string(),
+        OpCode::Pop => "OP_POP".to_string(),
+        OpCode::Dup => "OP_DUP".to_string(),
+        OpCode::Swap => "OP_SWAP".to_string(),
         OpCode::GetLocal(ref slot) => byte_instruction("OP_GET_LOCAL", *slot),
         OpCode::SetLocal(ref slot) => byte_instruction("OP_SET_LOCAL", *slot),
+        OpCode::IncLocal(ref slot) => byte_instruction("OP_INC_LOCAL", *slot),
+        OpCode::LessLocalConst(ref slot, ref const_id) => format!(
+            "{:-16} {:4} {}",
+            "OP_LESS_LOCAL_CONST",
+            slot,
+            chunk.read_const(*const_id as usize)
+        ),
         OpCode::GetGlobal(ref const_id) => constant_instruction("OP_GET_GLOBAL", *const_id),
         OpCode::DefineGlobal(ref const_id) => constant_instruction("OP_DEFINE_GLOBAL", *const_id),
         OpCode::SetGlobal(ref const_id) => constant_instruction("OP_SET_GLOBAL", *const_id),
+        OpCode::GetGlobalSlot(ref slot) => byte_instruction("OP_GET_GLOBAL_SLOT", *slot),
         OpCode::GetUpvalue(ref idx) => byte_instruction("OP_GET_UPVALUE", *idx),
         OpCode::SetUpvalue(ref idx) => byte_instruction("OP_SET_UPVALUE", *idx),
         OpCode::GetProperty(ref const_id) => constant_instruction("OP_GET_PROPERTY", *const_id),
         OpCode::SetProperty(ref const_id) => constant_instruction("OP_SET_PROPERTY", *const_id),
         OpCode::GetSuper(ref const_id) => constant_instruction("OP_GET_SUPER", *const_id),
-        OpCode::Equal => println!("OP_EQUAL"),
-        OpCode::Greater => println!("OP_GREATER"),
-        OpCode::Less => println!("OP_LESS"),
-        OpCode::Add => println!("OP_ADD"),
-        OpCode::Subtract => println!("OP_SUBTRACT"),
-        OpCode::Multiply => println!("OP_MULTIPLY"),
-        OpCode::Divide => println!("OP_DIVIDE"),
-        OpCode::Not => println!("OP_NOT"),
-        OpCode::Negate => println!("OP_NEGATE"),
+        OpCode::Equal => "OP_EQUAL".to_string(),
+        OpCode::Greater => "OP_GREATER".to_string(),
+        OpCode::Less => "OP_LESS".to_string(),
+        OpCode::Add => "OP_ADD".to_string(),
+        OpCode::Subtract => "OP_SUBTRACT".to_string(),
+        OpCode::Multiply => "OP_MULTIPLY".to_string(),
+        OpCode::Divide => "OP_DIVIDE".to_string(),
+        OpCode::Modulo => "OP_MODULO".to_string(),
+        OpCode::FloorDivide => "OP_FLOOR_DIVIDE".to_string(),
+        OpCode::Not => "OP_NOT".to_string(),
+        OpCode::Negate => "OP_NEGATE".to_string(),
+        OpCode::BitAnd => "OP_BIT_AND".to_string(),
+        OpCode::BitOr => "OP_BIT_OR".to_string(),
+        OpCode::BitXor => "OP_BIT_XOR".to_string(),
+        OpCode::Shl => "OP_SHL".to_string(),
+        OpCode::Shr => "OP_SHR".to_string(),
+        OpCode::BitNot => "OP_BIT_NOT".to_string(),
         OpCode::Jump(ref offset) => jump_instruction("OP_JUMP", inst_idx, *offset, true),
         OpCode::JumpIfFalse(ref offset) => {
             jump_instruction("OP_JUMP_IF_FALSE", inst_idx, *offset, true)
         }
+        OpCode::JumpIfNil(ref offset) => {
+            jump_instruction("OP_JUMP_IF_NIL", inst_idx, *offset, true)
+        }
         OpCode::Loop(ref offset) => jump_instruction("OP_LOOP", inst_idx, *offset, false),
-        OpCode::Print => println!("OP_PRINT"),
+        OpCode::Print => "OP_PRINT".to_string(),
+        OpCode::PrintN(ref count) => byte_instruction("OP_PRINT_N", *count),
         OpCode::Call(ref idx) => byte_instruction("OP_CALL", *idx),
         OpCode::Invoke(ref idx, ref argc) => invoke_instruction("OP_INVOKE", *idx, *argc),
-        OpCode::SuperInvoke(ref idx, ref argc) => invoke_instruction("OP_SUPER_INVOKE", *idx, *argc),
+        OpCode::SuperInvoke(ref idx, ref argc) => {
+            invoke_instruction("OP_SUPER_INVOKE", *idx, *argc)
+        }
         OpCode::Closure(ref const_id, ref upvalues) => {
             let value = chunk.read_const(*const_id as usize);
-            println!("{:-16} {:4} {}", "OP_CLOSURE", const_id, value);
+            let mut op = format!("{:-16} {:4} {}", "OP_CLOSURE", const_id, value);
             for upvalue in upvalues {
-                println!(
-                    "{:04}      |                     {} {}",
+                op.push_str(&format!(
+                    "\n{:04}      |                     {} {}",
                     inst_idx,
                     if upvalue.is_local { "local" } else { "upvalue" },
                     upvalue.index,
-                )
+                ));
             }
+            op
         }
-        OpCode::CloseUpvalue => println!("OP_CLOSE_UPVALUE"),
-        OpCode::Return => println!("OP_RETURN"),
+        OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE".to_string(),
+        OpCode::Return => "OP_RETURN".to_string(),
         OpCode::Class(ref const_id) => constant_instruction("OP_CLASS", *const_id),
-        OpCode::Inherit => println!("OP_INHERIT"),
-        OpCode::Method(ref const_id) => constant_instruction("OP_METHOD", *const_id),
-    }
+        OpCode::Inherit => "OP_INHERIT".to_string(),
+        OpCode::BuildList(ref len) => byte_instruction("OP_BUILD_LIST", *len),
+        OpCode::BuildMap(ref len) => byte_instruction("OP_BUILD_MAP", *len),
+        OpCode::Index => "OP_INDEX".to_string(),
+        OpCode::SetIndex => "OP_SET_INDEX".to_string(),
+        OpCode::Len => "OP_LEN".to_string(),
+        OpCode::Method(ref const_id, ref kind) => match kind {
+            MethodKind::Method => constant_instruction("OP_METHOD", *const_id),
+            MethodKind::Getter => constant_instruction("OP_GETTER", *const_id),
+            MethodKind::Static => constant_instruction("OP_STATIC_METHOD", *const_id),
+        },
+    };
+    line.push_str(&op);
+    line.push('\n');
+    line
 }
@@ -1,8 +1,11 @@
-use std::rc::Rc;
+use std::{convert::TryFrom, rc::Rc};
+
+use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::{
-    intern, token, Chunk, ObjFun, OpCode, Position, Scanner, StrId, Token, Value,
-    MAX_CHUNK_CONSTANTS, MAX_LOCAL_VARIABLES, MAX_PARAMS, MAX_UPVALUES,
+    intern, parse_number_literal, token, Chunk, Diagnostic, MethodKind, ObjFun, OpCode, Position,
+    ScanError, Scanner, StrId, Token, Value, Warning, MAX_CHUNK_CONSTANTS, MAX_LOCAL_VARIABLES,
+    MAX_NESTING_DEPTH, MAX_PARAMS, MAX_UPVALUES,
 };
 
 #[cfg(debug_assertions)]
@@ -14,12 +17,48 @@ use crate::disassemble_chunk;
 /// We are treating the entire script as a implicit function.
 #[derive(Debug, PartialEq, Eq)]
 pub enum FunType {
-    /// The compiled chunk is of a class initializer
+    /// The compiled chunk is of a class initializer. Calling a class runs its `init` method,
+    /// which always returns the new instance and can't return any other value.
+    ///
+    /// ```
+    /// use rlox::Compiler;
+    ///
+    /// let src = r#"
+    ///     class Foo {
+    ///         init(x) {
+    ///             this.x = x;
+    ///             if (x > 1) return;
+    ///             this.x = 0;
+    ///         }
+    ///     }
+    ///     var foo = Foo(2);
+    ///     print foo;
+    ///     print foo.x;
+    ///     print foo.init(1) == foo;
+    ///     print foo.x;
+    /// "#;
+    /// assert_eq!(
+    ///     rlox::interpret_to_string(src).unwrap(),
+    ///     "Foo instance\n2\ntrue\n0\n"
+    /// );
+    ///
+    /// let mut compiler = Compiler::new("class Foo { init() { return 5; } }");
+    /// compiler.compile();
+    /// assert_eq!(
+    ///     compiler.errors(),
+    ///     ["[line 1] Error at 'return': Can't return a value from an initializer."]
+    /// );
+    /// ```
     Initializer,
     /// The compiled chunk is of a class method
     Method,
+    /// The compiled chunk is of a class getter
+    Getter,
     /// The compiled chunk is of a function
     Function,
+    /// The compiled chunk is of a block that is used as an expression, see
+    /// [`Compiler::set_block_expressions`]
+    Block,
     /// The compiled chunk is of the input script
     Script,
 }
@@ -34,7 +73,7 @@ pub enum FunType {
 ///
 /// ## Locals Stack
 ///
-/// ```
+/// ```text
 /// {
 ///     var a = 1;             // STACK: [ 1 ]
 ///     {
@@ -63,47 +102,70 @@ pub enum FunType {
 /// decl       --> classDecl
 ///              | funDecl
 ///              | varDecl
+///              | constDecl
 ///              | stmt ;
-/// classDecl  --> "class" IDENT ( "<" IDENT )? "{" function* "}" ;
+/// classDecl  --> "class" IDENT ( "<" IDENT )? "{" ( "static"? function | getter )* "}" ;
 /// funDecl    --> "fun" function ;
 /// function   --> IDENT "(" params? ")" block ;
-/// params     --> IDENT ( "," IDENT )* ;
+/// getter     --> IDENT block ;
+/// params     --> param ( "," param )* ( "," "..." IDENT )?
+///              | "..." IDENT ;
+/// param      --> IDENT ( "=" expr )? ;
 /// varDecl    --> "var" IDENT ( "=" expr )? ";" ;
+/// constDecl  --> "const" IDENT "=" expr ";" ;
 /// stmt       --> block
 ///              | exprStmt
 ///              | forStmt
 ///              | ifStmt
 ///              | printStmt
 ///              | returnStmt
-///              | whileStmt ;
+///              | whileStmt
+///              | breakStmt
+///              | continueStmt
+///              | IDENT ":" ( forStmt | whileStmt ) ;
 /// block      --> "{" decl* "}" ;
 /// exprStmt   --> expr ";" ;
-/// forStmt    --> "for" "(" ( varDecl | exprStmt | ";" ) expr? ";" expr? ")" stmt ;
+/// forStmt    --> "for" "(" ( varDecl | exprStmt | ";" ) expr? ";" expr? ")" stmt
+///              | "for" "(" IDENT "in" expr ")" stmt ;
 /// ifStmt     --> "if" "(" expr ")" stmt ( "else" stmt )? ;
-/// printStmt  --> "print" expr ";" ;
+/// printStmt  --> "print" expr ( "," expr )* ";" ;
 /// returnStmt --> "return" expr? ";" ;
 /// whileStmt  --> "while" "(" expr ")" stmt ;
+/// breakStmt  --> "break" IDENT? ";" ;
+/// continueStmt --> "continue" IDENT? ";" ;
 /// expr       --> assign ;
 /// assign     --> ( call "." )? IDENT "=" expr ";"
-///              | or ;
+///              | call "[" expr "]" "=" expr ";"
+///              | coalesce ;
+/// coalesce   --> or ( "??" or )* ;
 /// or         --> and ( "or" and )* ;
 /// and        --> equality ( "and" equality )* ;
 /// equality   --> comparison ( ( "!=" | "==" ) comparison )* ;
-/// comparison --> term ( ( ">" | ">=" | "<" | "<=" ) term )* ;
+/// comparison --> bitOr ( ( ">" | ">=" | "<" | "<=" ) bitOr )* ;
+/// bitOr      --> bitXor ( "|" bitXor )* ;
+/// bitXor     --> bitAnd ( "^" bitAnd )* ;
+/// bitAnd     --> shift ( "&" shift )* ;
+/// shift      --> term ( ( "<<" | ">>" ) term )* ;
 /// term       --> factor ( ( "-" | "+" ) factor )* ;
-/// factor     --> unary ( ( "/" | "*" ) unary )* ;
-/// unary      --> ( "!" | "-" ) unary
+/// factor     --> unary ( ( "/" | "~/" | "*" | "%" ) unary )* ;
+/// unary      --> ( "!" | "-" | "~" ) unary
 ///              | call ;
-/// call       --> primary ( "(" args? ")" | "." IDENT )* ;
+/// call       --> primary ( "(" args? ")" | ( "." | "?." ) IDENT | "[" expr "]" )* ;
 /// args       --> expr ( "," expr )* ;
 /// primary    --> IDENT | NUMBER | STRING
 ///              | "this" | "super" "." IDENT
 ///              | "true" | "false" | "nil"
-///              | "(" expr ")" ;
+///              | "(" expr ")"
+///              | "[" args? "]"
+///              | "{" ( entry ( "," entry )* )? "}"
+///              | "when" "{" ( expr "->" expr "," )* "else" "->" expr ","? "}" ;
+/// entry      --> expr ":" expr ;
 ///
 #[derive(Debug)]
 pub struct Compiler<'a> {
     scanner: Scanner<'a>,
+    // The token after the current one, when it has been looked at already
+    next_token: Option<Result<Token<'a>, ScanError>>,
     current_token: Token<'a>,
     previous_token: Token<'a>,
     had_error: bool,
@@ -112,6 +174,42 @@ pub struct Compiler<'a> {
     // https://github.com/tdp2110/crafting-interpreters-rs/blob/trunk/src/compiler.rs
     closure_levels: Vec<ClosureLevel>,
     class_levels: Vec<ClassLevel>,
+    // Names of global variables that were declared with "const"
+    const_globals: FxHashSet<StrId>,
+    // Values of the global constants that were initialized with a literal, they are used in
+    // place of the constants
+    const_literals: FxHashMap<StrId, Value>,
+    // Names of global variables that have been declared so far
+    globals: FxHashSet<StrId>,
+    // Slots that were assigned to declared global variables, shared with the VM
+    global_slots: Option<&'a mut FxHashMap<StrId, u8>>,
+    // Names of global variables that exist before the script runs, e.g. native functions
+    defined_globals: FxHashSet<StrId>,
+    // Globals that are read inside a function, they are checked once the whole script has been
+    // compiled because functions can refer to globals that are declared after them
+    global_reads: Vec<(StrId, Position)>,
+    warn_shadowing: bool,
+    warn_undefined_globals: bool,
+    print_code: bool,
+    allow_top_level_return: bool,
+    echo_expressions: bool,
+    block_expressions: bool,
+    // Whether the next prefix expression starts a clause of a for loop, where '{' is the start
+    // of the loop's body rather than a map literal
+    for_clause_start: bool,
+    // Jumps of the '?.' accesses in the chain of calls and accesses that is being parsed, they
+    // skip to the end of the chain when the receiver is nil
+    safe_jumps: Vec<usize>,
+    warnings: Vec<Warning>,
+    errors: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+    // Index of the first instruction of the operand on the left of the infix operator that is
+    // being parsed
+    left_operand_start: usize,
+    // Number of expressions that are being parsed, the parser is recursive so this is bounded
+    // to avoid overflowing the native stack
+    depth: usize,
+    max_depth: usize,
 }
 
 impl<'a> Compiler<'a> {
@@ -119,6 +217,7 @@ impl<'a> Compiler<'a> {
     pub fn new(src: &'a str) -> Self {
         Self {
             scanner: Scanner::new(src),
+            next_token: None,
             current_token: Token::placeholder(),
             previous_token: Token::placeholder(),
             had_error: false,
@@ -128,18 +227,480 @@ impl<'a> Compiler<'a> {
                 FunType::Script,
             )],
             class_levels: Vec::new(),
+            const_globals: FxHashSet::default(),
+            const_literals: FxHashMap::default(),
+            globals: FxHashSet::default(),
+            global_slots: None,
+            defined_globals: FxHashSet::default(),
+            global_reads: Vec::new(),
+            warn_shadowing: false,
+            warn_undefined_globals: false,
+            print_code: cfg!(debug_assertions),
+            allow_top_level_return: false,
+            echo_expressions: false,
+            block_expressions: false,
+            for_clause_start: false,
+            safe_jumps: Vec::new(),
+            warnings: Vec::new(),
+            errors: Vec::new(),
+            diagnostics: Vec::new(),
+            left_operand_start: 0,
+            depth: 0,
+            max_depth: MAX_NESTING_DEPTH,
         }
     }
 
-    /// Starts building the bytecode chunk
+    /// Compile the script into the given chunk instead of a new one, the chunk is cleared first.
+    /// This avoids allocating a new chunk for every line in the REPL.
+    pub fn with_chunk(mut self, mut chunk: Chunk) -> Self {
+        chunk.clear();
+        self.closure_level_mut(0).fun.chunk = chunk;
+        self
+    }
+
+    /// Assign a slot from the given table to every global variable that gets declared, later
+    /// references to the variable read it through its slot instead of looking up its name.
+    /// Slots that are already in the table are kept, so the table can be shared by several
+    /// compilations. References that come before the declaration still use the name.
+    pub fn with_global_slots(mut self, slots: &'a mut FxHashMap<StrId, u8>) -> Self {
+        self.global_slots = Some(slots);
+        self
+    }
+
+    /// Report a warning when a local variable shadows a variable in an enclosing scope. This is
+    /// disabled by default.
+    pub fn set_warn_shadowing(&mut self, enabled: bool) {
+        self.warn_shadowing = enabled;
+    }
+
+    /// Let the script use the given global variables without declaring them, so they aren't
+    /// reported by [`Compiler::set_warn_undefined_globals`].
+    pub fn with_defined_globals<I: IntoIterator<Item = StrId>>(mut self, names: I) -> Self {
+        self.defined_globals.extend(names);
+        self
+    }
+
+    /// Report a warning when a function reads a global variable that isn't declared anywhere in
+    /// the script, which is likely a typo. Functions can still refer to globals that are
+    /// declared after them. This is disabled by default.
+    ///
+    /// ```
+    /// use rlox::Compiler;
+    ///
+    /// let src = r#"
+    ///     fun isEven(n) { if (n == 0) return true; return isOdd(n - 1); }
+    ///     fun isOdd(n) { if (n == 0) return false; return isEven(n - 1); }
+    ///     print isEven(4);
+    /// "#;
+    /// let mut compiler = Compiler::new(src);
+    /// compiler.set_warn_undefined_globals(true);
+    /// compiler.compile();
+    /// assert!(compiler.warnings().is_empty());
+    ///
+    /// let src = "fun greet() {\n  return \"hi\";\n}\nfun main() {\n  print greeet();\n}";
+    /// let mut compiler = Compiler::new(src);
+    /// compiler.set_warn_undefined_globals(true);
+    /// compiler.compile();
+    /// assert_eq!(compiler.warnings().len(), 1);
+    /// assert_eq!(
+    ///     compiler.warnings()[0].to_string(),
+    ///     "[line 5] Warning: Undefined variable 'greeet'."
+    /// );
+    ///
+    /// // nothing is reported on top of the errors
+    /// for src in ["class A { m() { return super.x; } }", "fun f(a, b c) { return c; }"].iter() {
+    ///     let mut compiler = Compiler::new(src);
+    ///     compiler.set_warn_undefined_globals(true);
+    ///     compiler.compile();
+    ///     assert!(!compiler.errors().is_empty());
+    ///     assert!(compiler.warnings().is_empty());
+    /// }
+    /// ```
+    pub fn set_warn_undefined_globals(&mut self, enabled: bool) {
+        self.warn_undefined_globals = enabled;
+    }
+
+    /// Print the disassembly of each function to the standard output once it's compiled. This is
+    /// enabled by default in debug builds, release builds never print it.
+    pub fn set_print_code(&mut self, enabled: bool) {
+        self.print_code = enabled;
+    }
+
+    /// Let the top-level code use `return` to end the script, the returned value is given back
+    /// to the caller of [`VM::interpret`](crate::VM::interpret). This is disabled by default.
+    ///
+    /// ```
+    /// use rlox::Compiler;
+    ///
+    /// let mut compiler = Compiler::new("return 42;");
+    /// compiler.compile();
+    /// assert_eq!(
+    ///     compiler.errors(),
+    ///     ["[line 1] Error at 'return': Can't return from top-level code."]
+    /// );
+    ///
+    /// let mut compiler = Compiler::new("return 42;");
+    /// compiler.set_allow_top_level_return(true);
+    /// compiler.compile();
+    /// assert!(compiler.errors().is_empty());
+    /// ```
+    pub fn set_allow_top_level_return(&mut self, enabled: bool) {
+        self.allow_top_level_return = enabled;
+    }
+
+    /// Print the value of an expression statement that ends the source without a semicolon,
+    /// instead of reporting the missing semicolon. This is meant for a REPL, and it's disabled
+    /// by default.
+    ///
+    /// ```
+    /// use rlox::{Compiler, OpCode};
+    ///
+    /// let mut compiler = Compiler::new("1 + x");
+    /// compiler.set_echo_expressions(true);
+    /// compiler.compile();
+    /// let chunk = compiler.finish().unwrap().chunk;
+    /// assert!(matches!(chunk.read_instruction(3).0, OpCode::Print));
+    /// ```
+    pub fn set_echo_expressions(&mut self, enabled: bool) {
+        self.echo_expressions = enabled;
+    }
+
+    /// Let a block be used as an expression, the block evaluates to its last expression when
+    /// that expression isn't followed by a semicolon, otherwise it evaluates to `nil`. A block
+    /// expression can't contain `return`. Braces that only contain entries still make a map.
+    /// This is disabled by default.
+    ///
+    /// ```
+    /// use rlox::{Compiler, SharedBuffer, VM};
+    ///
+    /// let src = r#"
+    ///     var x = { var a = 1; a + 1 };
+    ///     print x;
+    ///     print 10 + { var b = x * 2; var c = b + 1; c };
+    ///     print { print "side effect"; };
+    ///     print { x };
+    ///     print {"x": x};
+    ///     fun counter() {
+    ///         var n = 0;
+    ///         return { fun inc() { n = n + 1; return n; } inc };
+    ///     }
+    ///     var inc = counter();
+    ///     inc();
+    ///     print inc();
+    /// "#;
+    /// let mut compiler = Compiler::new(src);
+    /// compiler.set_block_expressions(true);
+    /// compiler.compile();
+    /// let fun = compiler.finish().unwrap();
+    ///
+    /// let buf = SharedBuffer::default();
+    /// let mut vm = VM::default().with_output(buf.clone());
+    /// vm.interpret_fun(fun).unwrap();
+    /// assert_eq!(
+    ///     buf.contents(),
+    ///     "2\n15\nside effect\nnil\n2\n{x: 2}\n2\n"
+    /// );
+    ///
+    /// let mut compiler = Compiler::new("var x = { var a = 1; a + 1 };");
+    /// compiler.compile();
+    /// assert_eq!(
+    ///     compiler.errors()[0],
+    ///     "[line 1] Error at 'var': Expect expression but found 'var'."
+    /// );
+    ///
+    /// let mut compiler = Compiler::new("fun f() { var x = { return 1; }; }");
+    /// compiler.set_block_expressions(true);
+    /// compiler.compile();
+    /// assert_eq!(
+    ///     compiler.errors(),
+    ///     ["[line 1] Error at 'return': Can't return from a block expression."]
+    /// );
+    ///
+    /// let mut compiler = Compiler::new("while (true) { var x = { break; }; }");
+    /// compiler.set_block_expressions(true);
+    /// compiler.compile();
+    /// assert_eq!(
+    ///     compiler.errors(),
+    ///     ["[line 1] Error at 'break': Can't use 'break' in a block expression."]
+    /// );
+    ///
+    /// // loops inside of the block can still be left
+    /// let mut compiler = Compiler::new("var x = { while (true) { continue; break; } 1 };");
+    /// compiler.set_block_expressions(true);
+    /// compiler.compile();
+    /// assert!(compiler.errors().is_empty());
+    /// ```
+    pub fn set_block_expressions(&mut self, enabled: bool) {
+        self.block_expressions = enabled;
+    }
+
+    /// Set how deep expressions can be nested in one another before an error is reported. This
+    /// is [`MAX_NESTING_DEPTH`] by default.
+    ///
+    /// ```
+    /// use rlox::Compiler;
+    ///
+    /// let src = format!("print {}1{};", "(".repeat(5000), ")".repeat(5000));
+    /// let mut compiler = Compiler::new(&src);
+    /// compiler.compile();
+    /// assert_eq!(
+    ///     compiler.errors(),
+    ///     ["[line 1] Error at '(': Expression nesting too deep."]
+    /// );
+    ///
+    /// let mut compiler = Compiler::new("print -(-(-1));");
+    /// compiler.set_max_nesting_depth(4);
+    /// compiler.compile();
+    /// assert_eq!(compiler.errors().len(), 1);
+    /// ```
+    pub fn set_max_nesting_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    /// Reject string literals that have more than the given number of characters, see
+    /// [`Scanner::set_max_string_len`]. A limit of 0 means there's no limit, which is the default.
+    ///
+    /// ```
+    /// use rlox::Compiler;
+    ///
+    /// let mut compiler = Compiler::new("print \"a long string\";");
+    /// compiler.set_max_string_len(10);
+    /// compiler.compile();
+    /// assert_eq!(compiler.errors(), ["[line 1] Error: String too long."]);
+    /// ```
+    pub fn set_max_string_len(&mut self, len: usize) {
+        self.scanner.set_max_string_len(len);
+    }
+
+    /// Return the warnings that were reported while compiling.
+    ///
+    /// ```
+    /// use rlox::Compiler;
+    ///
+    /// let mut compiler = Compiler::new("{ var a = 1; { var a = 2; } }");
+    /// compiler.set_warn_shadowing(true);
+    /// compiler.compile();
+    /// assert_eq!(compiler.warnings().len(), 1);
+    /// assert_eq!(compiler.warnings()[0].pos.line, 1);
+    /// ```
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Return the messages of the errors that were reported while compiling. The compiler
+    /// doesn't print them, so it's up to the caller to decide where they go.
+    ///
+    /// ```
+    /// use rlox::Compiler;
+    ///
+    /// let mut compiler = Compiler::new("print 1 +;");
+    /// compiler.compile();
+    /// assert_eq!(
+    ///     compiler.errors(),
+    ///     ["[line 1] Error at ';': Expect expression but found ';'."]
+    /// );
+    ///
+    /// let mut compiler = Compiler::new("print );");
+    /// compiler.compile();
+    /// assert_eq!(
+    ///     compiler.errors(),
+    ///     ["[line 1] Error at ')': Expect expression but found ')'."]
+    /// );
+    ///
+    /// let mut compiler = Compiler::new("print");
+    /// compiler.compile();
+    /// assert_eq!(
+    ///     compiler.errors(),
+    ///     ["[line 1] Error at end: Expect expression but found end of file."]
+    /// );
+    ///
+    /// let mut compiler = Compiler::new("print 1 < 2 < 3;");
+    /// compiler.compile();
+    /// assert_eq!(
+    ///     compiler.errors(),
+    ///     ["[line 1] Error at '<': Chained comparisons are not supported; use 'and'."]
+    /// );
+    ///
+    /// // only variables, properties, and indexing can be assigned to
+    /// let invalid_targets = [
+    ///     "a() = 1;",
+    ///     "1 = 2;",
+    ///     "(a + b) = 1;",
+    ///     "class A { m() {\n this = nil; } }",
+    ///     "class A {} class B < A { m() {\n super.x = 1; } }",
+    /// ];
+    /// for src in invalid_targets.iter() {
+    ///     let mut compiler = Compiler::new(src);
+    ///     compiler.compile();
+    ///     let line = src.lines().count();
+    ///     assert_eq!(
+    ///         compiler.errors(),
+    ///         [format!("[line {}] Error at '=': Invalid assignment target.", line)]
+    ///     );
+    /// }
+    ///
+    /// // calling a literal fails before the code runs
+    /// for src in ["1();", "\"s\"();", "true();", "nil();", "const A = 1; A();"].iter() {
+    ///     let mut compiler = Compiler::new(src);
+    ///     compiler.compile();
+    ///     assert_eq!(compiler.errors().len(), 1, "{}", src);
+    ///     assert!(compiler.errors()[0].ends_with("Error at '(': Can only call functions and classes."));
+    /// }
+    /// let mut compiler = Compiler::new("x(); (1 + x)(); [1][0]();");
+    /// compiler.compile();
+    /// assert!(compiler.errors().is_empty());
+    ///
+    /// // a block that isn't closed points at where it was opened
+    /// let mut compiler = Compiler::new("fun f() {\n  if (true) {\n    var a = 1;\n}\n");
+    /// compiler.compile();
+    /// assert_eq!(
+    ///     compiler.errors(),
+    ///     ["[line 5] Error at end: Unterminated block (opened at [line 1])."]
+    /// );
+    /// ```
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    /// Return the errors that were reported while compiling along with their positions, they
+    /// can be shown with [`render_diagnostic`](crate::render_diagnostic).
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Compile the source only to find the errors in it, e.g. for showing them in an editor.
+    /// Compilation carries on after each error from the next statement, so every independent
+    /// error is reported rather than only the first one.
+    ///
+    /// ```
+    /// use rlox::Compiler;
+    ///
+    /// let src = [
+    ///     "var a = ;",
+    ///     "fun f() {",
+    ///     "    print 1 +;",
+    ///     "    return @;",
+    ///     "}",
+    ///     "class A < A {}",
+    /// ]
+    /// .join("\n");
+    /// let diagnostics = Compiler::compile_all_diagnostics(&src);
+    /// let found: Vec<_> = diagnostics
+    ///     .iter()
+    ///     .map(|d| (d.pos.line, d.message.as_str()))
+    ///     .collect();
+    /// assert_eq!(
+    ///     found,
+    ///     [
+    ///         (1, "Expect expression but found ';'"),
+    ///         (3, "Expect expression but found ';'"),
+    ///         (4, "Unexpected character"),
+    ///         (6, "A class can't inherit from itself"),
+    ///     ]
+    /// );
+    ///
+    /// assert!(Compiler::compile_all_diagnostics("print 1;").is_empty());
+    /// ```
+    pub fn compile_all_diagnostics(src: &str) -> Vec<Diagnostic> {
+        let mut compiler = Compiler::new(src);
+        compiler.compile();
+        compiler.diagnostics
+    }
+
+    /// Starts building the bytecode chunk. Malformed source is reported through
+    /// [`Compiler::errors`], the compiler only panics when one of its own invariants is broken.
+    ///
+    /// ```
+    /// use rlox::Compiler;
+    ///
+    /// let inputs = [
+    ///     "fun (", "class { init", "}}}", "var = ;", "a.b.c = = 1", "return return",
+    ///     "super.x", "this = 1;", "[1, 2", "{\"a\": }", "for (;;", "1 ?? ;", "const;",
+    /// ];
+    /// for src in inputs.iter() {
+    ///     let mut compiler = Compiler::new(src);
+    ///     compiler.compile();
+    ///     assert!(!compiler.errors().is_empty(), "{}", src);
+    ///     assert!(compiler.finish().is_none());
+    /// }
+    /// ```
     pub fn compile(&mut self) {
         self.advance();
         while !self.check(token::Type::Eof) {
             self.declaration();
         }
+        // the declarations that come after an error are missed, so the check can't be trusted
+        if self.warn_undefined_globals && self.errors.is_empty() {
+            self.check_global_reads();
+        }
     }
 
-    /// Return the compiled bytecode chunk if the process finishes without error
+    /// Return the compiled bytecode chunk if the process finishes without error. Arithmetic on
+    /// number literals is computed while compiling, and global constants that were initialized
+    /// with a literal are replaced by their value.
+    ///
+    /// ```
+    /// use rlox::{Compiler, OpCode, Value};
+    ///
+    /// let mut compiler = Compiler::new("const PI = 3.14; print PI * 2;");
+    /// compiler.compile();
+    /// let chunk = compiler.finish().unwrap().chunk;
+    /// assert!(matches!(chunk.read_instruction(1).0, OpCode::DefineGlobal(_)));
+    /// match chunk.read_instruction(2).0 {
+    ///     OpCode::Constant(id) => {
+    ///         assert!(matches!(chunk.read_const(*id as usize), Value::Number(n) if *n == 6.28))
+    ///     }
+    ///     op => panic!("unexpected {:?}", op),
+    /// }
+    /// assert!(matches!(chunk.read_instruction(3).0, OpCode::Print));
+    ///
+    /// // only constants bound to a literal are propagated
+    /// let src = "const A = 1 + 2; const B = clock() * 0; var c = 4; print A * B + c;";
+    /// let mut compiler = Compiler::new(src);
+    /// compiler.compile();
+    /// let chunk = compiler.finish().unwrap().chunk;
+    /// let global_reads = (0..chunk.instructions_count())
+    ///     .filter(|&i| matches!(chunk.read_instruction(i).0, OpCode::GetGlobal(_)))
+    ///     .count();
+    /// // `clock`, `B`, and `c` are read but `A` isn't
+    /// assert_eq!(global_reads, 3);
+    /// assert_eq!(rlox::interpret_to_string("const A = 1 + 2; print A * 2 - 1;").unwrap(), "5\n");
+    ///
+    /// let mut compiler = Compiler::new("const PI = 3.14; PI = 3;");
+    /// compiler.compile();
+    /// assert!(compiler.finish().is_none());
+    ///
+    /// // a source without any code gives a script that only returns
+    /// for src in ["", "// comment", " \t\r\n\n  "].iter() {
+    ///     let mut compiler = Compiler::new(src);
+    ///     compiler.compile();
+    ///     assert!(compiler.errors().is_empty());
+    ///     let fun = compiler.finish().unwrap();
+    ///     assert_eq!(fun.chunk.instructions_count(), 2);
+    ///     assert!(matches!(fun.chunk.read_instruction(0).0, OpCode::Nil));
+    ///     assert!(matches!(fun.chunk.read_instruction(1).0, OpCode::Return));
+    ///     assert!(matches!(rlox::VM::default().interpret(src), Ok(None)));
+    /// }
+    /// ```
+    ///
+    /// The script is given away by the first call, so finishing again is a bug in the caller
+    /// and is reported as an internal compiler error.
+    ///
+    /// ```
+    /// use rlox::Compiler;
+    /// use std::panic::{self, AssertUnwindSafe};
+    ///
+    /// let mut compiler = Compiler::new("print 1;\nprint 2;");
+    /// compiler.compile();
+    /// assert!(compiler.finish().is_some());
+    /// panic::set_hook(Box::new(|_| {}));
+    /// let err = panic::catch_unwind(AssertUnwindSafe(|| compiler.finish())).unwrap_err();
+    /// let message = err.downcast_ref::<String>().unwrap();
+    /// assert_eq!(
+    ///     message,
+    ///     "[line 2] Internal compiler error at ';': No function is being compiled."
+    /// );
+    /// ```
     pub fn finish(&mut self) -> Option<ObjFun> {
         if self.had_error {
             return None;
@@ -148,8 +709,10 @@ impl<'a> Compiler<'a> {
 
         let fun = self.closure_level_pop().fun;
 
-        #[cfg(debug_assertions)]
-        disassemble_chunk(&fun.chunk, format!("{}", fun).as_str());
+        if self.print_code {
+            #[cfg(debug_assertions)]
+            disassemble_chunk(&fun.chunk, format!("{}", fun).as_str());
+        }
 
         Some(fun)
     }
@@ -161,6 +724,8 @@ impl<'a> Compiler<'a> {
             self.fun_declaration()
         } else if self.match_type(token::Type::Var) {
             self.var_declaration()
+        } else if self.match_type(token::Type::Const) {
+            self.const_declaration()
         } else {
             self.statement()
         }
@@ -183,6 +748,46 @@ impl<'a> Compiler<'a> {
             .push(ClosureLevel::new(ObjFun::new(name), fun_t));
         self.begin_scope();
 
+        // getters don't have a parameters list
+        if self.closure_level(0).fun_t != FunType::Getter {
+            self.parameters();
+        }
+        self.consume(token::Type::LBrace, "Expect '{' before function body");
+        let opened_at = self.previous_token.pos;
+        // the implicit return is unreachable if the body ends with a return statement that
+        // isn't nested in another statement
+        let mut ends_with_return = false;
+        while !self.check(token::Type::RBrace) && !self.check(token::Type::Eof) {
+            ends_with_return = self.check(token::Type::Return);
+            self.declaration();
+        }
+        self.end_block(opened_at);
+
+        if !ends_with_return {
+            self.emit_return();
+        }
+        self.end_function();
+    }
+
+    // Finish the function that is being compiled and emit the instruction that creates its
+    // closure in the enclosing function
+    fn end_function(&mut self) {
+        let level = self.closure_level_pop();
+        let mut fun = level.fun;
+        let upvalues = level.upvalues;
+        fun.upvalues = upvalues.len();
+
+        if self.print_code {
+            #[cfg(debug_assertions)]
+            disassemble_chunk(&fun.chunk, format!("{}", fun).as_str());
+        }
+
+        let fun = Rc::new(fun);
+        let const_id = self.make_const(Value::Fun(fun));
+        self.emit(OpCode::Closure(const_id, upvalues));
+    }
+
+    fn parameters(&mut self) {
         self.consume(token::Type::LParen, "Expect '(' after function name");
         if !self.check(token::Type::RParen) {
             loop {
@@ -191,7 +796,21 @@ impl<'a> Compiler<'a> {
                 }
 
                 self.closure_level_mut(0).fun.arity += 1;
+                if self.match_type(token::Type::DotDotDot) {
+                    self.rest_parameter();
+                    break;
+                }
+
                 let ident_id = self.parse_variable();
+                if self.match_type(token::Type::Equal) {
+                    // The default value is evaluated at runtime when the argument is omitted,
+                    // the value is left on the stack at the parameter's slot.
+                    let default_start = self.chunk().instructions_count();
+                    self.closure_level_mut(0).fun.defaults.push(default_start);
+                    self.expression();
+                } else if !self.closure_level(0).fun.defaults.is_empty() {
+                    self.error("Can't have a required parameter after an optional one");
+                }
                 self.define_variable(ident_id);
 
                 if !self.match_type(token::Type::Comma) {
@@ -200,20 +819,23 @@ impl<'a> Compiler<'a> {
             }
         }
         self.consume(token::Type::RParen, "Expect ')' after parameters");
-        self.consume(token::Type::LBrace, "Expect '{' before function body");
-        self.block();
-
-        self.emit_return();
-        let level = self.closure_level_pop();
-        let fun = level.fun;
-        let upvalues = level.upvalues;
+        self.closure_level_mut(0).fun.body_start = self.chunk().instructions_count();
+    }
 
-        #[cfg(debug_assertions)]
-        disassemble_chunk(&fun.chunk, format!("{}", fun).as_str());
+    fn rest_parameter(&mut self) {
+        let ident_id = self.parse_variable();
+        // The rest parameter is an optional parameter whose default value is an empty list,
+        // extra arguments are packed into a list by the virtual machine when they are given.
+        let default_start = self.chunk().instructions_count();
+        let fun = &mut self.closure_level_mut(0).fun;
+        fun.defaults.push(default_start);
+        fun.variadic = true;
+        self.emit(OpCode::BuildList(0));
+        self.define_variable(ident_id);
 
-        let fun = Rc::new(fun);
-        let const_id = self.make_const(Value::Fun(fun));
-        self.emit(OpCode::Closure(const_id, upvalues));
+        if self.check(token::Type::Comma) {
+            self.error_current("Rest parameter must be the last parameter");
+        }
     }
 
     fn class_declaration(&mut self) {
@@ -265,18 +887,32 @@ impl<'a> Compiler<'a> {
     }
 
     fn method(&mut self) {
+        let is_static = self.match_type(token::Type::Static);
         self.consume(token::Type::Ident, "Expect method name");
         let const_id = self.identifier_constant();
 
+        // Static methods are called with the class as the receiver
+        if is_static {
+            self.function(FunType::Method);
+            self.emit(OpCode::Method(const_id, MethodKind::Static));
+            return;
+        }
+
+        // A method that is declared without a parameters list is a getter
+        if self.check(token::Type::LBrace) {
+            self.function(FunType::Getter);
+            self.emit(OpCode::Method(const_id, MethodKind::Getter));
+            return;
+        }
+
         // The method that's named "init" is the class initializer and it always
         // return a class instance when finishes.
-
         self.function(if self.previous_token.lexeme == "init" {
             FunType::Initializer
         } else {
             FunType::Method
         });
-        self.emit(OpCode::Method(const_id));
+        self.emit(OpCode::Method(const_id, MethodKind::Method));
     }
 
     fn var_declaration(&mut self) {
@@ -295,6 +931,34 @@ impl<'a> Compiler<'a> {
         self.define_variable(ident_id);
     }
 
+    fn const_declaration(&mut self) {
+        let ident_id = self.parse_variable();
+        let name = intern::id(self.previous_token.lexeme);
+        // constants must always be initialized since they can't be assigned later
+        self.consume(token::Type::Equal, "Expect '=' after constant name");
+        let init_start = self.chunk().instructions_count();
+        self.expression();
+        self.consume(
+            token::Type::Semicolon,
+            "Expect ';' after constant declaration",
+        );
+
+        if self.closure_level(0).scope_depth > 0 {
+            match self.closure_level_mut(0).locals.last_mut() {
+                Some(local) => local.immutable = true,
+                None => self.internal_error("No local was declared for the constant"),
+            }
+        } else {
+            self.const_globals.insert(name);
+            if self.chunk().instructions_count() == init_start + 1 {
+                if let Some(value) = self.last_literal() {
+                    self.const_literals.insert(name, value);
+                }
+            }
+        }
+        self.define_variable(ident_id);
+    }
+
     fn parse_variable(&mut self) -> u8 {
         self.consume(token::Type::Ident, "Expect variable name");
         self.declare_variable();
@@ -316,17 +980,29 @@ impl<'a> Compiler<'a> {
         }
 
         let scope_depth = self.closure_level(0).scope_depth;
-        self.closure_level_mut(0)
-            .locals
-            .push((name, scope_depth).into());
+        let level = self.closure_level_mut(0);
+        level.locals.push((name, scope_depth).into());
+        level.fun.locals = level.fun.locals.max(level.locals.len());
     }
 
     fn declare_variable(&mut self) {
+        let name = intern::id(self.previous_token.lexeme);
         if self.closure_level(0).scope_depth == 0 {
+            // Globals can be redefined, except for the ones that were declared as constants
+            if self.const_globals.contains(&name) {
+                self.error("Already a constant with this name in this scope");
+            }
+            self.globals.insert(name);
+            if let Some(slots) = &mut self.global_slots {
+                // Globals that don't fit in the table are only accessed by name
+                if slots.len() <= u8::MAX as usize && !slots.contains_key(&name) {
+                    let slot = slots.len() as u8;
+                    slots.insert(name, slot);
+                }
+            }
             return;
         }
 
-        let name = intern::id(self.previous_token.lexeme);
         let mut name_duplicated = false;
         for l in self.closure_level(0).locals.iter().rev() {
             if l.initialized && l.depth < self.closure_level(0).scope_depth {
@@ -340,10 +1016,27 @@ impl<'a> Compiler<'a> {
 
         if name_duplicated {
             self.error("Already a variable with this name in this scope");
+        } else if self.warn_shadowing && self.is_shadowing(name) {
+            self.warning(&format!(
+                "Variable '{}' shadows a variable in an enclosing scope",
+                intern::str(name)
+            ));
         }
         self.add_local(name)
     }
 
+    fn is_shadowing(&self, name: StrId) -> bool {
+        // Only variables within the same function are considered, globals are only checked at
+        // the top-level script since they are visible everywhere.
+        let level = self.closure_level(0);
+        let shadows_local = level
+            .locals
+            .iter()
+            .any(|l| l.name == name && l.depth < level.scope_depth);
+        let shadows_global = level.fun_t == FunType::Script && self.globals.contains(&name);
+        shadows_local || shadows_global
+    }
+
     fn define_variable(&mut self, ident_id: u8) {
         // Local variables are not looked up by name. There's no need to stuff
         // the variable name into the constant table.
@@ -358,24 +1051,29 @@ impl<'a> Compiler<'a> {
         if self.closure_level(0).scope_depth == 0 {
             return;
         }
-        self.closure_level_mut(0)
-            .locals
-            .last_mut()
-            .expect("Wrong compiler state")
-            .initialized = true;
+        match self.closure_level_mut(0).locals.last_mut() {
+            Some(local) => local.initialized = true,
+            None => self.internal_error("No local was declared to be initialized"),
+        }
     }
 
     fn statement(&mut self) {
         if self.match_type(token::Type::Print) {
             self.print_statement();
         } else if self.match_type(token::Type::For) {
-            self.for_statement();
+            self.for_statement(None);
         } else if self.match_type(token::Type::If) {
             self.if_statement();
         } else if self.match_type(token::Type::Return) {
             self.return_statement();
         } else if self.match_type(token::Type::While) {
-            self.while_statement();
+            self.while_statement(None);
+        } else if self.match_type(token::Type::Break) {
+            self.jump_statement(LoopJump::Break);
+        } else if self.match_type(token::Type::Continue) {
+            self.jump_statement(LoopJump::Continue);
+        } else if self.check(token::Type::Ident) && self.peek_type() == Some(token::Type::Colon) {
+            self.labeled_statement();
         } else if self.match_type(token::Type::LBrace) {
             self.begin_scope();
             self.block();
@@ -386,10 +1084,22 @@ impl<'a> Compiler<'a> {
     }
 
     fn block(&mut self) {
+        let opened_at = self.previous_token.pos;
         while !self.check(token::Type::RBrace) && !self.check(token::Type::Eof) {
             self.declaration();
         }
-        self.consume(token::Type::RBrace, "Expect '}' after block");
+        self.end_block(opened_at);
+    }
+
+    // Consume the closing brace of the block that was opened at the given position. A block
+    // that runs to the end of the source points at its opening brace, which is more helpful
+    // than pointing at the end.
+    fn end_block(&mut self, opened_at: Position) {
+        if self.check(token::Type::Eof) {
+            self.error_current(&format!("Unterminated block (opened at {})", opened_at));
+        } else {
+            self.consume(token::Type::RBrace, "Expect '}' after block");
+        }
     }
 
     fn begin_scope(&mut self) {
@@ -412,8 +1122,10 @@ impl<'a> Compiler<'a> {
     }
 
     fn return_statement(&mut self) {
-        if self.closure_level(0).fun_t == FunType::Script {
+        if self.closure_level(0).fun_t == FunType::Script && !self.allow_top_level_return {
             self.error("Can't return from top-level code")
+        } else if self.closure_level(0).fun_t == FunType::Block {
+            self.error("Can't return from a block expression")
         }
 
         if self.match_type(token::Type::Semicolon) {
@@ -452,7 +1164,20 @@ impl<'a> Compiler<'a> {
         self.patch_jump(else_jump);
     }
 
-    fn while_statement(&mut self) {
+    fn labeled_statement(&mut self) {
+        self.advance();
+        let label = intern::id(self.previous_token.lexeme);
+        self.advance();
+        if self.match_type(token::Type::For) {
+            self.for_statement(Some(label));
+        } else if self.match_type(token::Type::While) {
+            self.while_statement(Some(label));
+        } else {
+            self.error_current("Expect loop after label");
+        }
+    }
+
+    fn while_statement(&mut self, label: Option<StrId>) {
         let loop_start = self.chunk().instructions_count();
         self.consume(token::Type::LParen, "Expect '(' after 'while'");
         self.expression();
@@ -461,22 +1186,31 @@ impl<'a> Compiler<'a> {
         let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
         self.emit(OpCode::Pop);
 
+        self.begin_loop(label);
         self.statement();
+        self.patch_continues();
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
         self.emit(OpCode::Pop);
+        self.end_loop();
     }
 
-    fn for_statement(&mut self) {
+    fn for_statement(&mut self, label: Option<StrId>) {
         self.begin_scope();
         self.consume(token::Type::LParen, "Expect '(' after 'for'");
+        if self.check(token::Type::Ident) && self.peek_type() == Some(token::Type::In) {
+            self.for_in_statement(label);
+            self.end_scope();
+            return;
+        }
         // initializer clause
         if self.match_type(token::Type::Semicolon) {
             // no initializer
         } else if self.match_type(token::Type::Var) {
             self.var_declaration();
         } else {
+            self.for_clause_start = true;
             self.expression_statement();
         }
 
@@ -485,7 +1219,9 @@ impl<'a> Compiler<'a> {
         // conditional clause
         let exit_jump = if !self.match_type(token::Type::Semicolon) {
             // conditional expression
+            self.for_clause_start = true;
             self.expression();
+            self.fuse_loop_condition(loop_start);
             self.consume(token::Type::Semicolon, "Expect ';' after loop condition");
             // exit if consitional expression is falsey
             let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
@@ -502,9 +1238,12 @@ impl<'a> Compiler<'a> {
             let body_jump = self.emit_jump(OpCode::Jump);
             let increment_start = self.chunk().instructions_count();
             // increment expression
+            self.for_clause_start = true;
             self.expression();
             // pop expression result
-            self.emit(OpCode::Pop);
+            if !self.fuse_loop_increment(increment_start) {
+                self.emit(OpCode::Pop);
+            }
             self.consume(token::Type::RParen, "Expect ')' after for clauses");
 
             // this will loop back to the conditional after the increment expression is run
@@ -514,7 +1253,9 @@ impl<'a> Compiler<'a> {
             self.patch_jump(body_jump);
         }
 
+        self.begin_loop(label);
         self.statement();
+        self.patch_continues();
         // this will loop back to the increment expression if there is one, otherwise it loops back
         // to the conditional expression
         self.emit_loop(loop_start);
@@ -524,17 +1265,245 @@ impl<'a> Compiler<'a> {
             // pop false when get jumped into
             self.emit(OpCode::Pop);
         }
+        self.end_loop();
         self.end_scope();
     }
 
+    // Replace the condition `i < n` of a counting loop, where `i` is a local variable and `n` is
+    // a number literal, with a single instruction. The condition starts at the given index.
+    fn fuse_loop_condition(&mut self, start: usize) {
+        if self.chunk().instructions_count() != start + 3 {
+            return;
+        }
+        let slot = match self.chunk().read_instruction(start).0 {
+            OpCode::GetLocal(slot) => *slot,
+            _ => return,
+        };
+        if !matches!(self.chunk().read_instruction(start + 2).0, OpCode::Less) {
+            return;
+        }
+        let value = match self.literal_at(start + 1) {
+            Some(value) if value.is_numeric() => value,
+            _ => return,
+        };
+        // small integers are loaded without the constant table, so they have to be added to it
+        let const_id = match self.chunk().read_instruction(start + 1).0 {
+            OpCode::Constant(const_id) => Some(*const_id),
+            _ => None,
+        };
+        let const_id = match const_id {
+            Some(const_id) => const_id,
+            // the comparison is kept as it is when there's no room for the bound
+            None if self.chunk().const_count() == MAX_CHUNK_CONSTANTS => return,
+            None => self.make_const(value),
+        };
+        for _ in 0..3 {
+            self.chunk().pop_instruction();
+        }
+        self.emit(OpCode::LessLocalConst(slot, const_id));
+    }
+
+    // Replace the increment `i = i + 1` of a counting loop, where `i` is a local variable, with a
+    // single instruction that doesn't leave any value to be popped. The increment starts at the
+    // given index. Return true if the increment was replaced.
+    fn fuse_loop_increment(&mut self, start: usize) -> bool {
+        if self.chunk().instructions_count() != start + 4 {
+            return false;
+        }
+        let chunk = self.chunk();
+        let slot = match (
+            chunk.read_instruction(start).0,
+            chunk.read_instruction(start + 1).0,
+            chunk.read_instruction(start + 2).0,
+            chunk.read_instruction(start + 3).0,
+        ) {
+            (OpCode::GetLocal(get), OpCode::SmallInt(1), OpCode::Add, OpCode::SetLocal(set))
+                if get == set =>
+            {
+                *get
+            }
+            _ => return false,
+        };
+        for _ in 0..4 {
+            chunk.pop_instruction();
+        }
+        self.emit(OpCode::IncLocal(slot));
+        true
+    }
+
+    fn for_in_statement(&mut self, label: Option<StrId>) {
+        self.consume(token::Type::Ident, "Expect loop variable name");
+        let var_name = intern::id(self.previous_token.lexeme);
+        self.consume(token::Type::In, "Expect 'in' after loop variable");
+
+        // The loop is turned into an index-based loop with two hidden local variables, one
+        // holds the list and one holds the current index. Their names can't be written by users
+        // so they won't collide with any other variable.
+        self.expression();
+        self.consume(token::Type::RParen, "Expect ')' after for clauses");
+        self.add_local(intern::id("for list"));
+        self.mark_initialized();
+        let list_slot = (self.closure_level(0).locals.len() - 1) as u8;
+
+        let zero = self.make_const(Value::Int(0));
+        self.emit(OpCode::Constant(zero));
+        self.add_local(intern::id("for index"));
+        self.mark_initialized();
+        let index_slot = (self.closure_level(0).locals.len() - 1) as u8;
+
+        // exit when the index reaches the length of the list
+        let loop_start = self.chunk().instructions_count();
+        self.emit(OpCode::GetLocal(index_slot));
+        self.emit(OpCode::GetLocal(list_slot));
+        self.emit(OpCode::Len);
+        self.emit(OpCode::Less);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit(OpCode::Pop);
+
+        // the loop variable lives in its own scope so each iteration gets a fresh variable
+        self.begin_loop(label);
+        self.begin_scope();
+        self.emit(OpCode::GetLocal(list_slot));
+        self.emit(OpCode::GetLocal(index_slot));
+        self.emit(OpCode::Index);
+        self.add_local(var_name);
+        self.mark_initialized();
+        self.statement();
+        self.end_scope();
+        self.patch_continues();
+
+        // increment the index
+        let one = self.make_const(Value::Int(1));
+        self.emit(OpCode::GetLocal(index_slot));
+        self.emit(OpCode::Constant(one));
+        self.emit(OpCode::Add);
+        self.emit(OpCode::SetLocal(index_slot));
+        self.emit(OpCode::Pop);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit(OpCode::Pop);
+        self.end_loop();
+    }
+
+    // Compile a `break` or `continue` statement. The locals that were declared inside the loop
+    // are discarded before jumping, the jump gets patched once the loop is done.
+    fn jump_statement(&mut self, kind: LoopJump) {
+        let (keyword, terminator_message) = match kind {
+            LoopJump::Break => ("break", "Expect ';' after 'break'"),
+            LoopJump::Continue => ("continue", "Expect ';' after 'continue'"),
+        };
+        let label = if self.match_type(token::Type::Ident) {
+            Some(intern::id(self.previous_token.lexeme))
+        } else {
+            None
+        };
+        let loops = &self.closure_level(0).loops;
+        let target = match label {
+            Some(label) => loops.iter().rposition(|l| l.label == Some(label)),
+            None => loops.len().checked_sub(1),
+        };
+        let target = match (target, label) {
+            (Some(target), _) => target,
+            // a block expression runs as its own function, the loops around it can't be reached
+            (None, _) if self.closure_level(0).fun_t == FunType::Block => {
+                self.error(&format!("Can't use '{}' in a block expression", keyword));
+                return;
+            }
+            (None, Some(label)) => {
+                self.error(&format!("Undefined loop label '{}'", intern::str(label)));
+                return;
+            }
+            (None, None) => {
+                self.error(&format!("Can't use '{}' outside of a loop", keyword));
+                return;
+            }
+        };
+        self.consume(token::Type::Semicolon, terminator_message);
+
+        let scope_depth = self.closure_level(0).loops[target].scope_depth;
+        let discarded: Vec<_> = self
+            .closure_level(0)
+            .locals
+            .iter()
+            .rev()
+            .take_while(|l| l.depth > scope_depth)
+            .map(|l| l.captured)
+            .collect();
+        for captured in discarded {
+            if captured {
+                self.emit(OpCode::CloseUpvalue);
+            } else {
+                self.emit(OpCode::Pop);
+            }
+        }
+        let jump = self.emit_jump(OpCode::Jump);
+        let target = &mut self.closure_level_mut(0).loops[target];
+        match kind {
+            LoopJump::Break => target.breaks.push(jump),
+            LoopJump::Continue => target.continues.push(jump),
+        }
+    }
+
+    fn begin_loop(&mut self, label: Option<StrId>) {
+        let scope_depth = self.closure_level(0).scope_depth;
+        self.closure_level_mut(0).loops.push(LoopLevel {
+            label,
+            scope_depth,
+            breaks: Vec::new(),
+            continues: Vec::new(),
+        });
+    }
+
+    // Point the `continue` statements of the innermost loop to the next instruction
+    fn patch_continues(&mut self) {
+        let continues = match self.closure_level_mut(0).loops.last_mut() {
+            Some(loop_level) => std::mem::take(&mut loop_level.continues),
+            None => self.internal_error("No loop is being compiled"),
+        };
+        for jump in continues {
+            self.patch_jump(jump);
+        }
+    }
+
+    // Point the `break` statements of the innermost loop to the next instruction
+    fn end_loop(&mut self) {
+        let loop_level = match self.closure_level_mut(0).loops.pop() {
+            Some(loop_level) => loop_level,
+            None => self.internal_error("No loop is being compiled"),
+        };
+        for jump in loop_level.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
     fn print_statement(&mut self) {
         self.expression();
+        let mut count = 1;
+        while self.match_type(token::Type::Comma) {
+            if count == u8::MAX as usize {
+                self.error("Can't print more than 255 values");
+            }
+            self.expression();
+            count += 1;
+        }
         self.consume(token::Type::Semicolon, "Expect ';' after value");
-        self.emit(OpCode::Print);
+        if count == 1 {
+            self.emit(OpCode::Print);
+        } else {
+            self.emit(OpCode::PrintN(count as u8));
+        }
     }
 
     fn expression_statement(&mut self) {
         self.expression();
+        if self.echo_expressions
+            && self.closure_level(0).fun_t == FunType::Script
+            && self.check(token::Type::Eof)
+        {
+            self.emit(OpCode::Print);
+            return;
+        }
         self.consume(token::Type::Semicolon, "Expect ';' after expression");
         self.emit(OpCode::Pop);
     }
@@ -543,6 +1512,20 @@ impl<'a> Compiler<'a> {
         self.parse_precedence(Precedence::Assignment)
     }
 
+    fn coalesce(&mut self) {
+        // Short-circuit jump.
+        // Same as `or`, but only a nil value lets the right operand be evaluated.
+        let else_jump = self.emit_jump(OpCode::JumpIfNil);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        // Pop nil value if not short-circuited
+        self.emit(OpCode::Pop);
+
+        self.parse_precedence(Precedence::Coalesce);
+        self.patch_jump(end_jump);
+    }
+
     fn or(&mut self) {
         // Short-circuit jump.
         // If the value on top of the stack is falsey, we make a small jump skipping passs the jump
@@ -573,7 +1556,19 @@ impl<'a> Compiler<'a> {
 
     fn binary(&mut self) {
         let token_type = self.previous_token.typ;
-        self.parse_precedence(Precedence::of(token_type).next());
+        let precedence = Precedence::of(token_type);
+        let left_start = self.left_operand_start;
+        self.parse_precedence(precedence.next());
+        // both operands are single instructions, so nothing can jump in between them
+        if self.chunk().instructions_count() == left_start + 2 && self.fold_binary(token_type) {
+            return;
+        }
+        // `a < b < c` would compare the boolean result of `a < b` with `c`
+        if precedence == Precedence::Comparison
+            && Precedence::of(self.current_token.typ) == Precedence::Comparison
+        {
+            self.error_current("Chained comparisons are not supported; use 'and'");
+        }
         match token_type {
             token::Type::BangEqual => {
                 self.emit(OpCode::Equal);
@@ -594,21 +1589,105 @@ impl<'a> Compiler<'a> {
             token::Type::Minus => self.emit(OpCode::Subtract),
             token::Type::Star => self.emit(OpCode::Multiply),
             token::Type::Slash => self.emit(OpCode::Divide),
-            _ => unreachable!(),
+            token::Type::Percent => self.emit(OpCode::Modulo),
+            token::Type::TildeSlash => self.emit(OpCode::FloorDivide),
+            token::Type::Amp => self.emit(OpCode::BitAnd),
+            token::Type::Pipe => self.emit(OpCode::BitOr),
+            token::Type::Caret => self.emit(OpCode::BitXor),
+            token::Type::LessLess => self.emit(OpCode::Shl),
+            token::Type::GreaterGreater => self.emit(OpCode::Shr),
+            _ => self.internal_error("Unknown binary operator"),
+        }
+    }
+
+    // Apply the arithmetic operator to the two number literals that were just emitted, or join
+    // the two string literals that were just emitted, return false if they can't be folded
+    fn fold_binary(&mut self, token_type: token::Type) -> bool {
+        let last = self.chunk().instructions_count() - 1;
+        let (left, right) = match (self.literal_at(last - 1), self.literal_at(last)) {
+            (Some(left), Some(right)) if left.is_numeric() && right.is_numeric() => (left, right),
+            (Some(Value::Str(left)), Some(Value::Str(right)))
+                if token_type == token::Type::Plus =>
+            {
+                // the joined string is interned once here instead of on every run
+                let joined = intern::id(intern::str(left) + &intern::str(right));
+                self.pop_literal();
+                self.pop_literal();
+                self.emit_literal(Value::Str(joined));
+                return true;
+            }
+            _ => return false,
+        };
+        let folded = match token_type {
+            token::Type::Plus => &left + &right,
+            token::Type::Minus => &left - &right,
+            token::Type::Star => &left * &right,
+            token::Type::Slash => &left / &right,
+            token::Type::Percent => &left % &right,
+            token::Type::TildeSlash => left.floor_div(&right),
+            _ => return false,
+        };
+        match folded {
+            Ok(value) => {
+                self.pop_literal();
+                self.pop_literal();
+                self.emit_literal(value);
+                true
+            }
+            Err(_) => false,
         }
     }
 
     fn unary(&mut self) {
         let token_type = self.previous_token.typ;
+        let operand_start = self.chunk().instructions_count();
         self.parse_precedence(Precedence::Unary);
+        // an operand that is a single instruction can only be a literal or a variable, nothing
+        // can jump in between so it's safe to fold the operator into it
+        if self.chunk().instructions_count() == operand_start + 1 && self.fold_unary(token_type) {
+            return;
+        }
         match token_type {
             token::Type::Bang => self.emit(OpCode::Not),
             token::Type::Minus => self.emit(OpCode::Negate),
-            _ => unreachable!(),
+            token::Type::Tilde => self.emit(OpCode::BitNot),
+            _ => self.internal_error("Unknown unary operator"),
         }
     }
 
+    // Apply the operator to the literal that was just emitted, return false if the operand
+    // isn't a literal that the operator can be folded into
+    fn fold_unary(&mut self, token_type: token::Type) -> bool {
+        let last = self.chunk().instructions_count() - 1;
+        let operand = self.chunk().read_instruction(last).0.clone();
+        let folded = match (token_type, operand) {
+            (token::Type::Minus, OpCode::Constant(const_id)) => {
+                // number constants are never shared, so the constant can be negated in place
+                match -self.chunk().read_const(const_id as usize) {
+                    Ok(negated) => self.chunk().patch_const(const_id as usize, negated),
+                    Err(_) => return false,
+                }
+                return true;
+            }
+            // negating zero gives a floating-point number, it's left to the VM
+            (token::Type::Minus, OpCode::SmallInt(n)) if n != 0 => match n.checked_neg() {
+                Some(n) => OpCode::SmallInt(n),
+                None => return false,
+            },
+            (token::Type::Bang, OpCode::True) => OpCode::False,
+            (token::Type::Bang, OpCode::False) | (token::Type::Bang, OpCode::Nil) => OpCode::True,
+            _ => return false,
+        };
+        self.chunk().replace_last_instruction(folded);
+        true
+    }
+
     fn call(&mut self) {
+        // a callee that is a single literal is known to not be callable
+        let callee_start = self.left_operand_start;
+        if self.chunk().instructions_count() == callee_start + 1 && self.last_literal().is_some() {
+            self.error("Can only call functions and classes");
+        }
         let arg_count = self.argument_list();
         self.emit(OpCode::Call(arg_count));
     }
@@ -648,6 +1727,143 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    fn safe_dot(&mut self) {
+        // A nil receiver is left on the stack as the result of the whole chain, the jump gets
+        // patched once the chain ends
+        let nil_jump = self.emit_jump(OpCode::JumpIfNil);
+        // assigning through '?.' isn't allowed
+        self.dot(false);
+        self.safe_jumps.push(nil_jump);
+    }
+
+    fn end_safe_chain(&mut self) {
+        for jump in std::mem::take(&mut self.safe_jumps) {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(token::Type::RBracket, "Expect ']' after index");
+
+        if can_assign && self.match_type(token::Type::Equal) {
+            self.expression();
+            self.emit(OpCode::SetIndex);
+        } else {
+            self.emit(OpCode::Index);
+        }
+    }
+
+    fn list(&mut self) {
+        let mut len = 0;
+        if !self.check(token::Type::RBracket) {
+            loop {
+                self.expression();
+                if len == u8::MAX as usize {
+                    self.error("Can't have more than 255 elements in a list literal");
+                }
+                len += 1;
+                if !self.match_type(token::Type::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(token::Type::RBracket, "Expect ']' after list elements");
+        self.emit(OpCode::BuildList(len as u8));
+    }
+
+    fn map(&mut self) {
+        let opened_at = self.previous_token.pos;
+        if self.block_expressions && self.starts_statement() {
+            self.block_expression(opened_at);
+            return;
+        }
+        let mut len = 0;
+        if !self.check(token::Type::RBrace) {
+            loop {
+                self.expression();
+                let ends_statement =
+                    self.check(token::Type::Semicolon) || self.check(token::Type::RBrace);
+                if len == 0 && self.block_expressions && ends_statement {
+                    // The first entry turned out to be an expression at the start of a block,
+                    // it can't refer to the block's locals since none has been declared yet.
+                    if self.match_type(token::Type::Semicolon) {
+                        self.emit(OpCode::Pop);
+                        self.block_expression(opened_at);
+                    } else {
+                        self.consume(token::Type::RBrace, "Expect '}' after block");
+                    }
+                    return;
+                }
+                self.consume(token::Type::Colon, "Expect ':' after map key");
+                self.expression();
+                if len == u8::MAX as usize {
+                    self.error("Can't have more than 255 entries in a map literal");
+                }
+                len += 1;
+                if !self.match_type(token::Type::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(token::Type::RBrace, "Expect '}' after map entries");
+        self.emit(OpCode::BuildMap(len as u8));
+    }
+
+    // A block in expression position is compiled as a function that gets called right away,
+    // so its locals have their own frame no matter which temporaries are below them on the
+    // stack. The opening brace has already been consumed.
+    fn block_expression(&mut self, opened_at: Position) {
+        self.closure_levels.push(ClosureLevel::new(
+            ObjFun::new(intern::id("block")),
+            FunType::Block,
+        ));
+        self.begin_scope();
+        while !self.check(token::Type::RBrace) && !self.check(token::Type::Eof) {
+            if self.starts_statement() {
+                self.declaration();
+                continue;
+            }
+            self.expression();
+            if self.check(token::Type::RBrace) {
+                // the last expression is the value of the block
+                self.consume(token::Type::RBrace, "Expect '}' after block");
+                self.emit(OpCode::Return);
+                self.end_function();
+                self.emit(OpCode::Call(0));
+                return;
+            }
+            self.consume(token::Type::Semicolon, "Expect ';' after expression");
+            self.emit(OpCode::Pop);
+            if self.panic {
+                self.synchronize();
+            }
+        }
+        self.end_block(opened_at);
+        self.emit_return();
+        self.end_function();
+        self.emit(OpCode::Call(0));
+    }
+
+    // Return true if the current token can only start a declaration or a statement
+    fn starts_statement(&self) -> bool {
+        matches!(
+            self.current_token.typ,
+            token::Type::Var
+                | token::Type::Fun
+                | token::Type::Class
+                | token::Type::Const
+                | token::Type::Print
+                | token::Type::If
+                | token::Type::While
+                | token::Type::For
+                | token::Type::Return
+                | token::Type::Break
+                | token::Type::Continue
+                | token::Type::LBrace
+        )
+    }
+
     fn super_(&mut self) {
         if self.class_levels.is_empty() {
             self.error("Can't use 'super' outside of a class");
@@ -685,23 +1901,81 @@ impl<'a> Compiler<'a> {
     }
 
     fn named_variable(&mut self, var_name: StrId, can_assign: bool) {
+        let pos = self.previous_token.pos;
+        // a global constant that was initialized with a literal is replaced by the literal
+        if let Some(value) = self.const_literals.get(&var_name).cloned() {
+            let is_global = self.resolve_local(0, var_name).is_none()
+                && self.resolve_upvalue(0, var_name).is_none();
+            if is_global && !self.check(token::Type::Equal) {
+                self.emit_literal(value);
+                return;
+            }
+        }
         let (op_get, op_set) = if let Some(local) = self.resolve_local(0, var_name) {
             (OpCode::GetLocal(local), OpCode::SetLocal(local))
         } else if let Some(upval) = self.resolve_upvalue(0, var_name) {
             (OpCode::GetUpvalue(upval), OpCode::SetUpvalue(upval))
         } else {
             let ident_id = self.make_const(Value::Str(var_name));
-            (OpCode::GetGlobal(ident_id), OpCode::SetGlobal(ident_id))
+            let slot = self
+                .global_slots
+                .as_ref()
+                .and_then(|slots| slots.get(&var_name).copied());
+            match slot {
+                Some(slot) => (OpCode::GetGlobalSlot(slot), OpCode::SetGlobal(ident_id)),
+                None => (OpCode::GetGlobal(ident_id), OpCode::SetGlobal(ident_id)),
+            }
         };
 
         if can_assign && self.match_type(token::Type::Equal) {
+            if self.is_const(var_name) {
+                self.error(&format!(
+                    "Cannot assign to const '{}'",
+                    intern::str(var_name)
+                ));
+            }
             self.expression();
             self.emit(op_set);
         } else {
+            // `this` and `super` outside of a class are reported as errors already
+            let in_function = self.closure_level(0).fun_t != FunType::Script;
+            let keyword = var_name == intern::id("this") || var_name == intern::id("super");
+            let is_global = matches!(op_get, OpCode::GetGlobal(_) | OpCode::GetGlobalSlot(_));
+            if in_function && !keyword && is_global {
+                self.global_reads.push((var_name, pos));
+            }
             self.emit(op_get);
         }
     }
 
+    fn check_global_reads(&mut self) {
+        for (name, pos) in std::mem::take(&mut self.global_reads) {
+            let declared = self.globals.contains(&name)
+                || self.defined_globals.contains(&name)
+                || self
+                    .global_slots
+                    .as_ref()
+                    .is_some_and(|slots| slots.contains_key(&name));
+            if !declared {
+                self.warnings.push(Warning {
+                    pos,
+                    message: format!("Undefined variable '{}'", intern::str(name)),
+                });
+            }
+        }
+    }
+
+    fn is_const(&self, name: StrId) -> bool {
+        // Variables are looked up in the same order as they are resolved, the innermost
+        // function that declares the name is the one that owns the variable.
+        for level in self.closure_levels.iter().rev() {
+            if let Some(local) = level.locals.iter().rev().find(|l| l.name == name) {
+                return local.immutable;
+            }
+        }
+        self.const_globals.contains(&name)
+    }
+
     fn resolve_local(&mut self, level: usize, name: StrId) -> Option<u8> {
         self.closure_level(level)
             .locals
@@ -753,18 +2027,78 @@ impl<'a> Compiler<'a> {
     }
 
     fn string(&mut self) {
-        let value =
-            intern::id(&self.previous_token.lexeme[1..self.previous_token.lexeme.len() - 1]);
+        let lexeme = self.previous_token.lexeme;
+        let lexeme = lexeme.strip_prefix('r').unwrap_or(lexeme);
+        let content = &lexeme[1..lexeme.len() - 1];
+        // line breaks in the source become "\n" no matter which convention the file uses
+        let value = if content.contains('\r') {
+            intern::id(content.replace("\r\n", "\n").replace('\r', "\n"))
+        } else {
+            intern::id(content)
+        };
         let constant = self.make_const(Value::Str(value));
         self.emit(OpCode::Constant(constant));
     }
 
     fn number(&mut self) {
-        let value = intern::str(intern::id(self.previous_token.lexeme))
-            .parse()
-            .expect("Unreachable");
-        let constant = self.make_const(Value::Number(value));
-        self.emit(OpCode::Constant(constant));
+        let value = match parse_number_literal(self.previous_token.lexeme) {
+            Some(value) => value,
+            None => self.internal_error("Invalid number literal"),
+        };
+        self.emit_literal(value);
+    }
+
+    // Return the value that the last instruction loads if it's a literal
+    fn last_literal(&mut self) -> Option<Value> {
+        let last = self.chunk().instructions_count().checked_sub(1)?;
+        self.literal_at(last)
+    }
+
+    // Return the value that the instruction at the given index loads if it's a literal
+    fn literal_at(&mut self, idx: usize) -> Option<Value> {
+        let chunk = self.chunk();
+        match chunk.read_instruction(idx).0 {
+            OpCode::Constant(const_id) => match chunk.read_const(*const_id as usize) {
+                value @ Value::Int(_) | value @ Value::Number(_) | value @ Value::Str(_) => {
+                    Some(value.clone())
+                }
+                _ => None,
+            },
+            OpCode::SmallInt(n) => Some(Value::Int(*n as i64)),
+            OpCode::True => Some(Value::Bool(true)),
+            OpCode::False => Some(Value::Bool(false)),
+            OpCode::Nil => Some(Value::Nil),
+            _ => None,
+        }
+    }
+
+    // Remove the last instruction, which loads a literal. Number constants are never shared, so
+    // the constant is removed as well if it's the last one.
+    fn pop_literal(&mut self) {
+        let chunk = self.chunk();
+        if let Some(OpCode::Constant(const_id)) = chunk.pop_instruction() {
+            let is_number = matches!(
+                chunk.read_const(const_id as usize),
+                Value::Int(_) | Value::Number(_)
+            );
+            if is_number && const_id as usize == chunk.const_count() - 1 {
+                chunk.pop_const();
+            }
+        }
+    }
+
+    // Emit the instruction that loads the given literal value
+    fn emit_literal(&mut self, value: Value) {
+        match value {
+            Value::Nil => self.emit(OpCode::Nil),
+            Value::Bool(true) => self.emit(OpCode::True),
+            Value::Bool(false) => self.emit(OpCode::False),
+            Value::Int(n) if i8::try_from(n).is_ok() => self.emit(OpCode::SmallInt(n as i8)),
+            value => {
+                let constant = self.make_const(value);
+                self.emit(OpCode::Constant(constant));
+            }
+        }
     }
 
     fn literal(&mut self) {
@@ -772,7 +2106,44 @@ impl<'a> Compiler<'a> {
             token::Type::False => self.emit(OpCode::False),
             token::Type::Nil => self.emit(OpCode::Nil),
             token::Type::True => self.emit(OpCode::True),
-            _ => unreachable!(),
+            _ => self.internal_error("Unknown literal"),
+        }
+    }
+
+    // The branches are compiled like chained conditionals, every condition that is falsey
+    // jumps to the next one and every branch that is taken jumps to the end.
+    fn when_expression(&mut self) {
+        self.consume(token::Type::LBrace, "Expect '{' after 'when'");
+        let mut end_jumps = Vec::new();
+        loop {
+            if self.match_type(token::Type::Else) {
+                self.consume(token::Type::MinusGreater, "Expect '->' after 'else'");
+                self.expression();
+                self.match_type(token::Type::Comma);
+                break;
+            }
+            if self.check(token::Type::RBrace) || self.check(token::Type::Eof) {
+                self.error_current("Expect 'else' branch in 'when' expression");
+                return;
+            }
+            self.expression();
+            self.consume(token::Type::MinusGreater, "Expect '->' after condition");
+
+            let next_jump = self.emit_jump(OpCode::JumpIfFalse);
+            self.emit(OpCode::Pop);
+            self.expression();
+            end_jumps.push(self.emit_jump(OpCode::Jump));
+            self.patch_jump(next_jump);
+            self.emit(OpCode::Pop);
+
+            if !self.match_type(token::Type::Comma) && !self.check(token::Type::RBrace) {
+                self.error_current("Expect ',' after 'when' branch");
+                return;
+            }
+        }
+        self.consume(token::Type::RBrace, "Expect '}' after 'when' branches");
+        for jump in end_jumps {
+            self.patch_jump(jump);
         }
     }
 
@@ -782,14 +2153,34 @@ impl<'a> Compiler<'a> {
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
+        if self.depth == self.max_depth {
+            self.error_current("Expression nesting too deep");
+            return;
+        }
+        self.depth += 1;
+        self.parse_precedence_unbounded(precedence);
+        self.depth -= 1;
+    }
+
+    fn parse_precedence_unbounded(&mut self, precedence: Precedence) {
         self.advance();
         let can_assign = precedence <= Precedence::Assignment;
+        let start = self.chunk().instructions_count();
+        // the operands that are parsed here have their own chains
+        let outer_safe_jumps = std::mem::take(&mut self.safe_jumps);
         self.prefix_rule(can_assign);
 
         while precedence <= Precedence::of(self.current_token.typ) {
+            // the chain ends with the first operator that isn't an access or a call
+            if Precedence::of(self.current_token.typ) < Precedence::Call {
+                self.end_safe_chain();
+            }
             self.advance();
+            self.left_operand_start = start;
             self.infix_rule(can_assign);
         }
+        self.end_safe_chain();
+        self.safe_jumps = outer_safe_jumps;
 
         if can_assign && self.match_type(token::Type::Equal) {
             self.error("Invalid assignment target");
@@ -797,17 +2188,25 @@ impl<'a> Compiler<'a> {
     }
 
     fn prefix_rule(&mut self, can_assign: bool) {
+        let for_clause_start = std::mem::take(&mut self.for_clause_start);
         match self.previous_token.typ {
             token::Type::LParen => self.grouping(),
-            token::Type::Minus | token::Type::Bang => self.unary(),
+            token::Type::LBrace if !for_clause_start => self.map(),
+            token::Type::LBracket => self.list(),
+            token::Type::Minus | token::Type::Bang | token::Type::Tilde => self.unary(),
             token::Type::Super => self.super_(),
             token::Type::This => self.this(),
             token::Type::Ident => self.variable(can_assign),
-            token::Type::String => self.string(),
+            token::Type::String | token::Type::RawString => self.string(),
             token::Type::Number => self.number(),
             token::Type::True | token::Type::False | token::Type::Nil => self.literal(),
+            token::Type::When => self.when_expression(),
             _ => {
-                self.error("Expect expression");
+                let found = match self.previous_token.lexeme {
+                    "" => "end of file".to_string(),
+                    lexeme => format!("'{}'", lexeme),
+                };
+                self.error(&format!("Expect expression but found {}", found));
             }
         }
     }
@@ -815,13 +2214,23 @@ impl<'a> Compiler<'a> {
     fn infix_rule(&mut self, can_assign: bool) {
         match self.previous_token.typ {
             token::Type::Dot => self.dot(can_assign),
+            token::Type::QuestionDot => self.safe_dot(),
+            token::Type::LBracket => self.index(can_assign),
             token::Type::LParen => self.call(),
             token::Type::Or => self.or(),
+            token::Type::QuestionQuestion => self.coalesce(),
             token::Type::And => self.and(),
             token::Type::Minus
             | token::Type::Plus
             | token::Type::Slash
             | token::Type::Star
+            | token::Type::Percent
+            | token::Type::TildeSlash
+            | token::Type::Amp
+            | token::Type::Pipe
+            | token::Type::Caret
+            | token::Type::LessLess
+            | token::Type::GreaterGreater
             | token::Type::BangEqual
             | token::Type::EqualEqual
             | token::Type::Greater
@@ -842,10 +2251,13 @@ impl<'a> Compiler<'a> {
                 token::Type::Class
                 | token::Type::Fun
                 | token::Type::Var
+                | token::Type::Const
                 | token::Type::For
                 | token::Type::If
                 | token::Type::While
                 | token::Type::Print
+                | token::Type::Break
+                | token::Type::Continue
                 | token::Type::Return => return,
                 _ => {}
             }
@@ -855,9 +2267,17 @@ impl<'a> Compiler<'a> {
 
     fn advance(&mut self) {
         loop {
-            match self.scanner.scan() {
+            let next = match self.next_token.take() {
+                Some(next) => next,
+                None => self.scanner.scan(),
+            };
+            match next {
                 Err(err) => {
-                    eprintln!("{}", err);
+                    self.errors.push(err.to_string());
+                    self.diagnostics.push(Diagnostic {
+                        pos: err.pos(),
+                        message: err.message().to_string(),
+                    });
                     self.had_error = true;
                     self.panic = true;
                 }
@@ -869,6 +2289,13 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    /// Get the type of the token that comes after the current token
+    fn peek_type(&mut self) -> Option<token::Type> {
+        let scanner = &mut self.scanner;
+        let next = self.next_token.get_or_insert_with(|| scanner.scan());
+        next.as_ref().ok().map(|tok| tok.typ)
+    }
+
     fn match_type(&mut self, typ: token::Type) -> bool {
         if !self.check(typ) {
             return false;
@@ -902,17 +2329,27 @@ impl<'a> Compiler<'a> {
         // level 1 --> enclosing
         // level 2 --> enlosing of enclosing
         // level 3 --> so on...
-        let idx = self.closure_levels.len() - lvl - 1;
+        let idx = self.closure_level_index(lvl);
         &self.closure_levels[idx]
     }
 
     fn closure_level_mut(&mut self, lvl: usize) -> &mut ClosureLevel {
-        let idx = self.closure_levels.len() - lvl - 1;
+        let idx = self.closure_level_index(lvl);
         &mut self.closure_levels[idx]
     }
 
+    fn closure_level_index(&self, lvl: usize) -> usize {
+        match self.closure_levels.len().checked_sub(lvl + 1) {
+            Some(idx) => idx,
+            None => self.internal_error("No function is being compiled"),
+        }
+    }
+
     fn closure_level_pop(&mut self) -> ClosureLevel {
-        self.closure_levels.pop().expect("Wrong compiler state")
+        match self.closure_levels.pop() {
+            Some(level) => level,
+            None => self.internal_error("No function is being compiled"),
+        }
     }
 
     fn class_level(&self, lvl: usize) -> &ClassLevel {
@@ -926,10 +2363,21 @@ impl<'a> Compiler<'a> {
     }
 
     fn class_level_pop(&mut self) {
-        self.class_levels.pop().expect("Wrong compiler state");
+        if self.class_levels.pop().is_none() {
+            self.internal_error("No class is being compiled");
+        }
     }
 
     fn make_const(&mut self, v: Value) -> u8 {
+        // strings are interned, so an identical string constant can be reused
+        if let Value::Str(id) = v {
+            let chunk = self.chunk();
+            let existing = (0..chunk.const_count())
+                .find(|&i| matches!(chunk.read_const(i), Value::Str(other) if *other == id));
+            if let Some(const_id) = existing {
+                return const_id as u8;
+            }
+        }
         if self.chunk().const_count() == MAX_CHUNK_CONSTANTS {
             self.error("Too many constants in one chunk");
             return MAX_CHUNK_CONSTANTS as u8;
@@ -978,26 +2426,48 @@ impl<'a> Compiler<'a> {
         self.emit(OpCode::Loop(offset as u16));
     }
 
-    fn error(&mut self, message: &'static str) {
+    fn warning(&mut self, message: &str) {
+        let warning = Warning {
+            pos: self.previous_token.pos,
+            message: message.to_string(),
+        };
+        self.warnings.push(warning);
+    }
+
+    fn error(&mut self, message: &str) {
         self.error_at(self.previous_token.pos, self.previous_token.lexeme, message)
     }
 
-    fn error_current(&mut self, message: &'static str) {
+    fn error_current(&mut self, message: &str) {
         self.error_at(self.current_token.pos, self.current_token.lexeme, message)
     }
 
-    fn error_at(&mut self, pos: Position, lexeme: &str, message: &'static str) {
+    /// Abort compilation because an invariant of the compiler doesn't hold. This is a bug in
+    /// the compiler rather than in the source, so the report includes where the compiler was
+    /// in the source to help reproducing it.
+    fn internal_error(&self, message: &str) -> ! {
+        panic!(
+            "{} Internal compiler error at '{}': {}.",
+            self.previous_token.pos, self.previous_token.lexeme, message
+        )
+    }
+
+    fn error_at(&mut self, pos: Position, lexeme: &str, message: &str) {
         if self.panic {
             return;
         }
         self.had_error = true;
         self.panic = true;
 
-        if lexeme.is_empty() {
-            eprintln!("{} Error at end: {}.", pos, message)
+        self.errors.push(if lexeme.is_empty() {
+            format!("{} Error at end: {}.", pos, message)
         } else {
-            eprintln!("{} Error at '{}': {}.", pos, lexeme, message)
-        }
+            format!("{} Error at '{}': {}.", pos, lexeme, message)
+        });
+        self.diagnostics.push(Diagnostic {
+            pos,
+            message: message.to_string(),
+        });
     }
 }
 
@@ -1013,6 +2483,7 @@ struct ClosureLevel {
     locals: Vec<Local>,
     upvalues: Vec<Upvalue>,
     scope_depth: usize,
+    loops: Vec<LoopLevel>,
 }
 
 impl ClosureLevel {
@@ -1022,7 +2493,7 @@ impl ClosureLevel {
         // + With method call, the slot stores the receiver of the method
         let mut locals = Vec::with_capacity(MAX_LOCAL_VARIABLES);
 
-        if fun_t != FunType::Function {
+        if fun_t != FunType::Function && fun_t != FunType::Block {
             locals.push(Local::from((intern::id("this"), 0, true)));
         } else {
             locals.push(Local::from((intern::id(""), 0, true)));
@@ -1034,10 +2505,27 @@ impl ClosureLevel {
             locals,
             upvalues: Vec::with_capacity(MAX_UPVALUES),
             scope_depth: 0,
+            loops: Vec::new(),
         }
     }
 }
 
+/// A loop that `break` and `continue` statements can jump out of
+#[derive(Debug)]
+struct LoopLevel {
+    label: Option<StrId>,
+    // Locals that are deeper than this are declared inside the loop
+    scope_depth: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum LoopJump {
+    Break,
+    Continue,
+}
+
 /// An upvalue refers to a local variable in an enclosing function. Every closure
 /// maintains an array of upvalues, one for each surrounding local variables that
 /// the clossure uses.
@@ -1057,6 +2545,7 @@ struct Local {
     depth: usize,
     initialized: bool,
     captured: bool,
+    immutable: bool,
 }
 
 impl From<(StrId, usize)> for Local {
@@ -1066,6 +2555,7 @@ impl From<(StrId, usize)> for Local {
             depth,
             initialized: false,
             captured: false,
+            immutable: false,
         }
     }
 }
@@ -1076,6 +2566,7 @@ impl From<(StrId, usize, bool)> for Local {
             depth,
             initialized,
             captured: false,
+            immutable: false,
         }
     }
 }
@@ -1091,6 +2582,8 @@ enum Precedence {
     None,
     /// Operator `=`
     Assignment,
+    /// Operator `??`
+    Coalesce,
     /// Operator `or`
     Or,
     /// Operator `and`
@@ -1099,11 +2592,19 @@ enum Precedence {
     Equality,
     /// Operator `<` `>` `<=` `>=`
     Comparison,
+    /// Operator `|`
+    BitOr,
+    /// Operator `^`
+    BitXor,
+    /// Operator `&`
+    BitAnd,
+    /// Operator `<<` `>>`
+    Shift,
     /// Operator `+` `-`
     Term,
     /// Operator `*` `/`
     Factor,
-    /// Operator `!` `-`
+    /// Operator `!` `-` `~`
     Unary,
     /// Operator `.` `()`
     Call,
@@ -1116,11 +2617,16 @@ impl Precedence {
     fn next(&self) -> Self {
         match self {
             Self::None => Self::Assignment,
-            Self::Assignment => Self::Or,
+            Self::Assignment => Self::Coalesce,
+            Self::Coalesce => Self::Or,
             Self::Or => Self::And,
             Self::And => Self::Equality,
             Self::Equality => Self::Comparison,
-            Self::Comparison => Self::Term,
+            Self::Comparison => Self::BitOr,
+            Self::BitOr => Self::BitXor,
+            Self::BitXor => Self::BitAnd,
+            Self::BitAnd => Self::Shift,
+            Self::Shift => Self::Term,
             Self::Term => Self::Factor,
             Self::Factor => Self::Unary,
             Self::Unary => Self::Call,
@@ -1131,6 +2637,7 @@ impl Precedence {
 
     fn of(typ: token::Type) -> Self {
         match typ {
+            token::Type::QuestionQuestion => Precedence::Coalesce,
             token::Type::Or => Precedence::Or,
             token::Type::And => Precedence::And,
             token::Type::BangEqual | token::Type::EqualEqual => Precedence::Equality,
@@ -1138,9 +2645,19 @@ impl Precedence {
             | token::Type::GreaterEqual
             | token::Type::Less
             | token::Type::LessEqual => Precedence::Comparison,
+            token::Type::Pipe => Precedence::BitOr,
+            token::Type::Caret => Precedence::BitXor,
+            token::Type::Amp => Precedence::BitAnd,
+            token::Type::LessLess | token::Type::GreaterGreater => Precedence::Shift,
             token::Type::Minus | token::Type::Plus => Precedence::Term,
-            token::Type::Slash | token::Type::Star => Precedence::Factor,
-            token::Type::LParen | token::Type::Dot => Precedence::Call,
+            token::Type::Slash
+            | token::Type::Star
+            | token::Type::Percent
+            | token::Type::TildeSlash => Precedence::Factor,
+            token::Type::LParen
+            | token::Type::Dot
+            | token::Type::QuestionDot
+            | token::Type::LBracket => Precedence::Call,
             _ => Self::None,
         }
     }
@@ -1,6 +1,6 @@
 use std::fmt::{self, Debug};
 
-use crate::Position;
+use crate::{scan::numbered_lines, Position};
 
 /// Lox virtual machine errors
 #[derive(Debug)]
@@ -9,19 +9,291 @@ pub enum Error {
     Runtime,
     /// A compilation error happened
     Compile,
+    /// The program stopped itself by calling `exit` with the given status
+    Exit(i32),
+    /// The program executed more instructions than the budget allows, see
+    /// [`VM::set_instruction_budget`](crate::VM::set_instruction_budget). It's reported like
+    /// a runtime error.
+    BudgetExceeded,
 }
 
 /// Virtual machine errors
 #[derive(Debug)]
-pub struct RuntimeError(pub String);
+pub enum RuntimeError {
+    /// The operands of an operation have types that it can't work with
+    TypeMismatch(String),
+    /// A function was called with the wrong number of arguments
+    ///
+    /// ```
+    /// assert_eq!(
+    ///     rlox::interpret_to_string("fun f(a, b) {} f(1);").unwrap_err(),
+    ///     "Expected 2 arguments but got 1.\n[line 1] in script.\n"
+    /// );
+    /// assert_eq!(
+    ///     rlox::interpret_to_string("fun f(a) {} f(1, 2);").unwrap_err(),
+    ///     "Expected 1 arguments but got 2.\n[line 1] in script.\n"
+    /// );
+    /// assert_eq!(
+    ///     rlox::interpret_to_string("var x = 1; x();").unwrap_err(),
+    ///     "Can only call functions and classes.\n[line 1] in script.\n"
+    /// );
+    /// assert_eq!(
+    ///     rlox::interpret_to_string("fun f(a, b = 1) {} f();").unwrap_err(),
+    ///     "Expected 1 to 2 arguments but got 0.\n[line 1] in script.\n"
+    /// );
+    /// assert_eq!(
+    ///     rlox::interpret_to_string("fun f(a, ...rest) {} f();").unwrap_err(),
+    ///     "Expected at least 1 arguments but got 0.\n[line 1] in script.\n"
+    /// );
+    /// ```
+    ArityMismatch {
+        /// The number of parameters that must be given an argument
+        expected: u8,
+        /// The largest number of arguments that the function accepts, or `None` if it takes
+        /// any number of extra arguments
+        max: Option<u8>,
+        /// The number of arguments that were given
+        got: u8,
+    },
+    /// Any other error with a message describing it
+    Message(String),
+    /// The virtual machine executed more instructions than its budget allows, see
+    /// [`VM::set_instruction_budget`](crate::VM::set_instruction_budget)
+    BudgetExceeded,
+    /// The program asked to stop with the given status, this unwinds the virtual machine like
+    /// an error but it isn't reported as one
+    Exit(i32),
+}
+
+/// A problem found during compilation that does not stop the source from being compiled
+#[derive(Debug, Clone)]
+pub struct Warning {
+    /// The position at which the problem was found
+    pub pos: Position,
+    /// Description of the problem
+    pub message: String,
+}
+
+/// An error found in the source during compilation, see [`Compiler::diagnostics`]
+///
+/// [`Compiler::diagnostics`]: crate::Compiler::diagnostics
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    /// The position at which the error was found
+    pub pos: Position,
+    /// Description of the error
+    pub message: String,
+}
+
+/// Show the line of the source where the diagnostic was found, with a caret under the column
+/// that it points at.
+///
+/// ```
+/// use rlox::Compiler;
+///
+/// let src = "var a = 1;\nprint a +;\n";
+/// let mut compiler = Compiler::new(src);
+/// compiler.compile();
+/// let rendered = rlox::render_diagnostic(src, &compiler.diagnostics()[0]);
+/// let expected = [
+///     "error: Expect expression but found ';'.",
+///     " --> line 2, column 10",
+///     "  |",
+///     "2 | print a +;",
+///     "  |          ^",
+///     "",
+/// ];
+/// assert_eq!(rendered, expected.join("\n"));
+///
+/// // lines are counted the same way as the scanner counts them
+/// for src in ["var a = 1;\rprint a +;", "\n\r\n#line 1\nvar a = 1;\nprint a +;"].iter() {
+///     let mut compiler = Compiler::new(src);
+///     compiler.compile();
+///     let rendered = rlox::render_diagnostic(src, &compiler.diagnostics()[0]);
+///     assert_eq!(rendered, expected.join("\n"));
+/// }
+/// ```
+pub fn render_diagnostic(source: &str, diagnostic: &Diagnostic) -> String {
+    let Position { line, column } = diagnostic.pos;
+    let gutter = " ".repeat(line.to_string().len());
+    let mut rendered = format!(
+        "error: {}.\n{}--> line {}, column {}\n",
+        diagnostic.message, gutter, line, column
+    );
+    // `#line` directives can give the same number to several lines, skip the ones that are
+    // too short to contain the column
+    let text = numbered_lines(source)
+        .into_iter()
+        .find_map(|(number, text)| {
+            if number == line && text.chars().count() >= column.saturating_sub(1) {
+                Some(text)
+            } else {
+                None
+            }
+        });
+    if let Some(text) = text {
+        // tabs are kept so the caret lines up however wide they are displayed
+        let indent: String = text
+            .chars()
+            .take(column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        rendered.push_str(&format!("{} |\n", gutter));
+        rendered.push_str(&format!("{} | {}\n", line, text));
+        rendered.push_str(&format!("{} | {}^\n", gutter, indent));
+    }
+    rendered
+}
 
 /// Error while scanning Lox source code
 #[derive(Debug, Clone)]
 pub enum ScanError {
-    /// A string literal is unterminated
-    UnterminatedString(Position),
-    /// Invalid character
-    UnexpectedCharacter(Position),
+    /// A string literal is unterminated, the position points at its opening quote
+    ///
+    /// ```
+    /// use rlox::{ScanError, Scanner};
+    ///
+    /// let mut scanner = Scanner::new("print\n  \"abc");
+    /// scanner.scan().unwrap();
+    /// let err = scanner.scan().unwrap_err();
+    /// assert!(matches!(
+    ///     err,
+    ///     ScanError::UnterminatedString { pos } if pos.line == 2 && pos.column == 3
+    /// ));
+    /// ```
+    UnterminatedString {
+        /// The position of the opening quote
+        pos: Position,
+    },
+    /// A character that can't start any token
+    ///
+    /// ```
+    /// use rlox::{ScanError, Scanner};
+    ///
+    /// let mut scanner = Scanner::new("var a =\n  @;");
+    /// for _ in 0..3 {
+    ///     scanner.scan().unwrap();
+    /// }
+    /// let err = scanner.scan().unwrap_err();
+    /// assert!(matches!(
+    ///     err,
+    ///     ScanError::UnexpectedChar { ch: '@', pos } if pos.line == 2 && pos.column == 3
+    /// ));
+    /// assert_eq!(err.to_string(), "[line 2] Error: Unexpected character.");
+    /// ```
+    UnexpectedChar {
+        /// The unexpected character
+        ch: char,
+        /// The position of the character
+        pos: Position,
+    },
+    /// A number literal that is directly followed by letters, such as `123abc`
+    ///
+    /// ```
+    /// use rlox::{ScanError, Scanner};
+    ///
+    /// let mut scanner = Scanner::new("123abc");
+    /// let err = scanner.scan().unwrap_err();
+    /// assert!(matches!(err, ScanError::InvalidNumber { pos } if pos.column == 1));
+    /// ```
+    InvalidNumber {
+        /// The position of the first digit
+        pos: Position,
+    },
+    /// A string literal is longer than the limit given to
+    /// [`Scanner::set_max_string_len`](crate::Scanner::set_max_string_len), the position
+    /// points at its opening quote
+    StringTooLong {
+        /// The position of the opening quote
+        pos: Position,
+    },
+}
+
+/// A problem found in a function by [`ObjFun::validate`], which would make the virtual machine
+/// fail in unexpected ways if the function was run. Each problem refers to the index of the
+/// instruction at which it's found.
+///
+/// [`ObjFun::validate`]: crate::ObjFun::validate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The chunk doesn't end with a return instruction
+    MissingReturn,
+    /// An instruction loads a constant that isn't in the chunk
+    InvalidConstant {
+        /// The index of the instruction
+        at: usize,
+        /// The index of the constant
+        index: usize,
+    },
+    /// An instruction uses a constant of the wrong type, e.g. a global variable whose name
+    /// isn't a string
+    ConstantType {
+        /// The index of the instruction
+        at: usize,
+        /// The index of the constant
+        index: usize,
+    },
+    /// A jump lands outside of the chunk
+    InvalidJump {
+        /// The index of the instruction
+        at: usize,
+    },
+    /// An instruction uses a local variable slot that the function's frame doesn't have
+    InvalidLocal {
+        /// The index of the instruction
+        at: usize,
+        /// The slot of the local variable
+        slot: u8,
+    },
+    /// An instruction uses an upvalue that the function's closures don't have
+    InvalidUpvalue {
+        /// The index of the instruction
+        at: usize,
+        /// The index of the upvalue
+        index: u8,
+    },
+    /// A closure captures a different number of variables than its function uses
+    UpvalueCount {
+        /// The index of the instruction
+        at: usize,
+    },
+    /// The function starts executing outside of its chunk
+    InvalidEntry {
+        /// The index at which the function starts
+        at: usize,
+    },
+    /// An instruction pops more values than the function's frame holds
+    StackUnderflow {
+        /// The index of the instruction
+        at: usize,
+    },
+    /// The paths that reach an instruction leave a different number of values on the stack
+    StackMismatch {
+        /// The index of the instruction
+        at: usize,
+    },
+}
+
+impl ScanError {
+    /// Return the position at which the error was found
+    pub fn pos(&self) -> Position {
+        match *self {
+            Self::UnterminatedString { pos }
+            | Self::UnexpectedChar { pos, .. }
+            | Self::InvalidNumber { pos }
+            | Self::StringTooLong { pos } => pos,
+        }
+    }
+
+    /// Return the description of the error
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::UnterminatedString { .. } => "Unterminated string",
+            Self::UnexpectedChar { .. } => "Unexpected character",
+            Self::InvalidNumber { .. } => "Invalid number",
+            Self::StringTooLong { .. } => "String too long",
+        }
+    }
 }
 
 impl std::error::Error for Error {}
@@ -30,6 +302,8 @@ impl fmt::Display for Error {
         match self {
             Self::Runtime => write!(f, "Runtime error(s) occured."),
             Self::Compile => write!(f, "Compilation error(s) occured."),
+            Self::Exit(status) => write!(f, "Exited with status {}.", status),
+            Self::BudgetExceeded => write!(f, "Instruction budget exceeded."),
         }
     }
 }
@@ -37,18 +311,90 @@ impl fmt::Display for Error {
 impl std::error::Error for RuntimeError {}
 impl fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        write!(f, "{}.", self.0)
+        match self {
+            Self::TypeMismatch(msg) | Self::Message(msg) => write!(f, "{}.", msg),
+            Self::ArityMismatch { expected, max, got } => match max {
+                Some(max) if max == expected => {
+                    write!(f, "Expected {} arguments but got {}.", expected, got)
+                }
+                Some(max) => write!(
+                    f,
+                    "Expected {} to {} arguments but got {}.",
+                    expected, max, got
+                ),
+                None => write!(
+                    f,
+                    "Expected at least {} arguments but got {}.",
+                    expected, got
+                ),
+            },
+            Self::BudgetExceeded => write!(f, "Instruction budget exceeded."),
+            Self::Exit(status) => write!(f, "Exited with status {}.", status),
+        }
     }
 }
 
-impl std::error::Error for ScanError {}
-impl fmt::Display for ScanError {
+impl std::error::Error for VerifyError {}
+impl fmt::Display for VerifyError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::UnterminatedString(ref pos) => write!(f, "{} Error: Unterminated string.", pos),
-            Self::UnexpectedCharacter(ref pos) => {
-                write!(f, "{} Error: Unexpected character.", pos)
+            Self::MissingReturn => write!(f, "Chunk doesn't end with a return."),
+            Self::InvalidConstant { at, index } => {
+                write!(f, "Instruction {} uses missing constant {}.", at, index)
+            }
+            Self::ConstantType { at, index } => write!(
+                f,
+                "Instruction {} uses constant {} of the wrong type.",
+                at, index
+            ),
+            Self::InvalidJump { at } => {
+                write!(f, "Instruction {} jumps outside of the chunk.", at)
+            }
+            Self::InvalidLocal { at, slot } => {
+                write!(f, "Instruction {} uses missing local slot {}.", at, slot)
+            }
+            Self::InvalidUpvalue { at, index } => {
+                write!(f, "Instruction {} uses missing upvalue {}.", at, index)
             }
+            Self::UpvalueCount { at } => write!(
+                f,
+                "Instruction {} captures the wrong number of upvalues.",
+                at
+            ),
+            Self::InvalidEntry { at } => {
+                write!(f, "Function starts outside of the chunk at {}.", at)
+            }
+            Self::StackUnderflow { at } => {
+                write!(
+                    f,
+                    "Instruction {} pops more values than the stack holds.",
+                    at
+                )
+            }
+            Self::StackMismatch { at } => write!(
+                f,
+                "Instruction {} is reached with different stack sizes.",
+                at
+            ),
         }
     }
 }
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Error: {}.", self.pos, self.message)
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Warning: {}.", self.pos, self.message)
+    }
+}
+
+impl std::error::Error for ScanError {}
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Error: {}.", self.pos(), self.message())
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+};
+
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{MapEntries, ObjClass, ObjClosure, ObjInstance, ObjUpvalue, Value};
+
+/// The number of tracked objects that triggers the first collection
+const INITIAL_NEXT_GC: usize = 1024;
+
+/// Objects are reference counted, so most of them are freed as soon as they become unused. The
+/// heap keeps track of the objects that can be mutated to point to each other, because those
+/// are the only ones that can end up in a reference cycle that is never freed.
+///
+/// Collection works like a mark-and-sweep collector: everything that can be reached from the
+/// roots gets marked, and the tracked objects that weren't marked have their content cleared.
+/// That breaks the cycles they are part of, and reference counting frees them. There's no free
+/// list since memory is still managed by `Rc`.
+///
+/// The host can hold on to objects that the program can't reach anymore, e.g. the value given
+/// back by [`VM::interpret`](crate::VM::interpret). An unmarked object that has more strong
+/// references than the unmarked objects hold to it is referenced from outside of the heap, so
+/// it's treated as a root until the host drops it.
+#[derive(Debug)]
+pub(crate) struct Heap {
+    objects: Vec<HeapObject>,
+    next_gc: usize,
+}
+
+#[derive(Debug)]
+enum HeapObject {
+    List(Weak<RefCell<Vec<Value>>>),
+    Map(Weak<RefCell<MapEntries>>),
+    Instance(Weak<RefCell<ObjInstance>>),
+    Class(Weak<RefCell<ObjClass>>),
+    Upvalue(Weak<RefCell<ObjUpvalue>>),
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            objects: Vec::new(),
+            next_gc: INITIAL_NEXT_GC,
+        }
+    }
+}
+
+impl Heap {
+    /// Keep track of the object that the value holds if it can be part of a cycle
+    pub(crate) fn track(&mut self, value: &Value) {
+        let object = match value {
+            Value::List(l) => HeapObject::List(Rc::downgrade(l)),
+            Value::Map(m) => HeapObject::Map(Rc::downgrade(m)),
+            Value::Instance(i) => HeapObject::Instance(Rc::downgrade(i)),
+            Value::Class(c) => HeapObject::Class(Rc::downgrade(c)),
+            _ => return,
+        };
+        self.objects.push(object);
+    }
+
+    /// Keep track of an upvalue
+    pub(crate) fn track_upvalue(&mut self, upvalue: &Rc<RefCell<ObjUpvalue>>) {
+        self.objects
+            .push(HeapObject::Upvalue(Rc::downgrade(upvalue)));
+    }
+
+    /// Return true if enough objects have been allocated since the last collection
+    pub(crate) fn should_collect(&self) -> bool {
+        self.objects.len() >= self.next_gc
+    }
+
+    /// Return the number of tracked objects that are still alive
+    pub(crate) fn object_count(&self) -> usize {
+        self.objects.iter().filter(|o| o.is_alive()).count()
+    }
+
+    /// Clear the content of every tracked object that wasn't marked, and return the number of
+    /// objects that got freed
+    pub(crate) fn sweep(&mut self, marker: &mut Marker) -> usize {
+        self.mark_escaped(marker);
+        let count = self.object_count();
+        for object in self.objects.iter() {
+            object.clear_unless(marker);
+        }
+        self.objects.retain(HeapObject::is_alive);
+        self.next_gc = INITIAL_NEXT_GC.max(self.objects.len() * 2);
+        count - self.objects.len()
+    }
+
+    // Mark the unmarked objects that are referenced from outside of the heap, along with
+    // everything they can reach
+    fn mark_escaped(&self, marker: &mut Marker) {
+        let unmarked: Vec<&HeapObject> = self
+            .objects
+            .iter()
+            .filter(|o| o.is_alive() && !marker.marked.contains(&o.as_ptr()))
+            .collect();
+        let mut references = FxHashMap::default();
+        for object in unmarked.iter() {
+            object.count_references(&mut references);
+        }
+        for object in unmarked {
+            let internal = references.get(&object.as_ptr()).copied().unwrap_or(0);
+            if object.strong_count() > internal {
+                object.mark(marker);
+            }
+        }
+        marker.trace();
+    }
+}
+
+impl HeapObject {
+    fn as_ptr(&self) -> *const () {
+        match self {
+            Self::List(l) => l.as_ptr() as *const (),
+            Self::Map(m) => m.as_ptr() as *const (),
+            Self::Instance(i) => i.as_ptr() as *const (),
+            Self::Class(c) => c.as_ptr() as *const (),
+            Self::Upvalue(u) => u.as_ptr() as *const (),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Self::List(l) => l.strong_count(),
+            Self::Map(m) => m.strong_count(),
+            Self::Instance(i) => i.strong_count(),
+            Self::Class(c) => c.strong_count(),
+            Self::Upvalue(u) => u.strong_count(),
+        }
+    }
+
+    fn mark(&self, marker: &mut Marker) {
+        match self {
+            Self::List(l) => l.upgrade().map(|l| marker.mark_value(&Value::List(l))),
+            Self::Map(m) => m.upgrade().map(|m| marker.mark_value(&Value::Map(m))),
+            Self::Instance(i) => i.upgrade().map(|i| marker.mark_value(&Value::Instance(i))),
+            Self::Class(c) => c.upgrade().map(|c| marker.mark_value(&Value::Class(c))),
+            Self::Upvalue(u) => u.upgrade().map(|u| marker.mark_upvalue(&u)),
+        };
+    }
+
+    // Count the references that the object holds to tracked objects
+    fn count_references(&self, references: &mut FxHashMap<*const (), usize>) {
+        match self {
+            Self::List(l) => {
+                if let Some(l) = l.upgrade() {
+                    l.borrow()
+                        .iter()
+                        .for_each(|v| count_reference(v, references));
+                }
+            }
+            Self::Map(m) => {
+                if let Some(m) = m.upgrade() {
+                    m.borrow()
+                        .values()
+                        .for_each(|v| count_reference(v, references));
+                }
+            }
+            Self::Instance(i) => {
+                if let Some(i) = i.upgrade() {
+                    let instance = i.borrow();
+                    *references
+                        .entry(Rc::as_ptr(&instance.class) as *const ())
+                        .or_default() += 1;
+                    instance
+                        .fields
+                        .values()
+                        .for_each(|v| count_reference(v, references));
+                }
+            }
+            Self::Class(c) => {
+                if let Some(c) = c.upgrade() {
+                    let class = c.borrow();
+                    class
+                        .methods
+                        .values()
+                        .chain(class.getters.values())
+                        .chain(class.static_methods.values())
+                        .for_each(|v| count_reference(v, references));
+                }
+            }
+            Self::Upvalue(u) => {
+                if let Some(u) = u.upgrade() {
+                    if let ObjUpvalue::Closed(ref value) = *u.borrow() {
+                        count_reference(value, references);
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            Self::List(l) => l.strong_count() > 0,
+            Self::Map(m) => m.strong_count() > 0,
+            Self::Instance(i) => i.strong_count() > 0,
+            Self::Class(c) => c.strong_count() > 0,
+            Self::Upvalue(u) => u.strong_count() > 0,
+        }
+    }
+
+    fn clear_unless(&self, marker: &Marker) {
+        match self {
+            Self::List(l) => {
+                if let Some(l) = l.upgrade().filter(|l| !marker.is_marked(l)) {
+                    l.borrow_mut().clear();
+                }
+            }
+            Self::Map(m) => {
+                if let Some(m) = m.upgrade().filter(|m| !marker.is_marked(m)) {
+                    m.borrow_mut().clear();
+                }
+            }
+            Self::Instance(i) => {
+                if let Some(i) = i.upgrade().filter(|i| !marker.is_marked(i)) {
+                    i.borrow_mut().fields.clear();
+                }
+            }
+            Self::Class(c) => {
+                if let Some(c) = c.upgrade().filter(|c| !marker.is_marked(c)) {
+                    let mut class = c.borrow_mut();
+                    class.methods.clear();
+                    class.getters.clear();
+                    class.static_methods.clear();
+                }
+            }
+            Self::Upvalue(u) => {
+                if let Some(u) = u.upgrade().filter(|u| !marker.is_marked(u)) {
+                    u.replace(ObjUpvalue::Closed(Value::Nil));
+                }
+            }
+        }
+    }
+}
+
+fn count_reference(value: &Value, references: &mut FxHashMap<*const (), usize>) {
+    let ptr = match value {
+        Value::List(l) => Rc::as_ptr(l) as *const (),
+        Value::Map(m) => Rc::as_ptr(m) as *const (),
+        Value::Instance(i) => Rc::as_ptr(i) as *const (),
+        Value::Class(c) => Rc::as_ptr(c) as *const (),
+        // Closures and bound methods aren't tracked, one that isn't shared only belongs to the
+        // object holding it, so what it holds is counted as held by the object
+        Value::Closure(c) if Rc::strong_count(c) == 1 => {
+            count_closure_references(c, references);
+            return;
+        }
+        Value::BoundMethod(b) if Rc::strong_count(b) == 1 => {
+            count_reference(&b.receiver, references);
+            if Rc::strong_count(&b.method) == 1 {
+                count_closure_references(&b.method, references);
+            }
+            return;
+        }
+        _ => return,
+    };
+    *references.entry(ptr).or_default() += 1;
+}
+
+fn count_closure_references(closure: &ObjClosure, references: &mut FxHashMap<*const (), usize>) {
+    for upvalue in closure.upvalues.iter() {
+        *references
+            .entry(Rc::as_ptr(upvalue) as *const ())
+            .or_default() += 1;
+    }
+}
+
+/// Finds every object that can be reached from the roots
+#[derive(Debug, Default)]
+pub(crate) struct Marker {
+    marked: FxHashSet<*const ()>,
+    gray: Vec<Value>,
+    gray_upvalues: Vec<Rc<RefCell<ObjUpvalue>>>,
+}
+
+impl Marker {
+    /// Mark a root value
+    pub(crate) fn mark_value(&mut self, value: &Value) {
+        let ptr = match value {
+            Value::List(l) => Rc::as_ptr(l) as *const (),
+            Value::Map(m) => Rc::as_ptr(m) as *const (),
+            Value::Instance(i) => Rc::as_ptr(i) as *const (),
+            Value::Class(c) => Rc::as_ptr(c) as *const (),
+            Value::Closure(c) => Rc::as_ptr(c) as *const (),
+            Value::BoundMethod(b) => Rc::as_ptr(b) as *const (),
+            _ => return,
+        };
+        if self.marked.insert(ptr) {
+            self.gray.push(value.clone());
+        }
+    }
+
+    /// Mark a root upvalue
+    pub(crate) fn mark_upvalue(&mut self, upvalue: &Rc<RefCell<ObjUpvalue>>) {
+        if self.marked.insert(Rc::as_ptr(upvalue) as *const ()) {
+            self.gray_upvalues.push(Rc::clone(upvalue));
+        }
+    }
+
+    /// Mark a root closure
+    pub(crate) fn mark_closure(&mut self, closure: &Rc<ObjClosure>) {
+        self.mark_value(&Value::Closure(Rc::clone(closure)));
+    }
+
+    /// Mark everything that can be reached from the objects that have been marked
+    pub(crate) fn trace(&mut self) {
+        loop {
+            if let Some(upvalue) = self.gray_upvalues.pop() {
+                if let ObjUpvalue::Closed(ref value) = *upvalue.borrow() {
+                    self.mark_value(value);
+                }
+                continue;
+            }
+            let value = match self.gray.pop() {
+                Some(value) => value,
+                None => break,
+            };
+            match value {
+                Value::List(l) => l.borrow().iter().for_each(|v| self.mark_value(v)),
+                Value::Map(m) => m.borrow().values().for_each(|v| self.mark_value(v)),
+                Value::Instance(i) => {
+                    let instance = i.borrow();
+                    self.mark_value(&Value::Class(Rc::clone(&instance.class)));
+                    instance.fields.values().for_each(|v| self.mark_value(v));
+                }
+                Value::Class(c) => {
+                    let class = c.borrow();
+                    class.methods.values().for_each(|v| self.mark_value(v));
+                    class.getters.values().for_each(|v| self.mark_value(v));
+                    class
+                        .static_methods
+                        .values()
+                        .for_each(|v| self.mark_value(v));
+                }
+                Value::Closure(c) => c.upvalues.iter().for_each(|u| self.mark_upvalue(u)),
+                Value::BoundMethod(b) => {
+                    self.mark_value(&b.receiver);
+                    self.mark_closure(&b.method);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn is_marked<T>(&self, object: &Rc<T>) -> bool {
+        self.marked.contains(&(Rc::as_ptr(object) as *const ()))
+    }
+}
@@ -33,3 +33,50 @@ pub fn str(id: StrId) -> String {
             .to_string()
     })
 }
+
+/// Get the number of distinct strings in the global interner. Strings stay interned for as
+/// long as the thread lives, so this can be watched to see how the strings of a program grow.
+///
+/// ```
+/// let before = rlox::interned_len();
+/// let a = rlox::id("interned_len_a");
+/// rlox::id("interned_len_b");
+/// rlox::id("interned_len_c");
+/// assert_eq!(rlox::interned_len(), before + 3);
+///
+/// assert_eq!(rlox::id("interned_len_a"), a);
+/// assert_eq!(rlox::interned_len(), before + 3);
+/// ```
+pub fn interned_len() -> usize {
+    INTERN.with(|intern| intern.borrow().len())
+}
+
+/// Release the memory that the global interner reserved but doesn't use.
+///
+/// ```
+/// let a = rlox::id("shrink_interner_to_fit");
+/// rlox::shrink_interner_to_fit();
+/// assert_eq!(rlox::str(a), "shrink_interner_to_fit");
+/// ```
+pub fn shrink_interner_to_fit() {
+    INTERN.with(|intern| intern.borrow_mut().shrink_to_fit())
+}
+
+/// Run the function with an empty global interner. The strings that it interns are dropped
+/// once it returns, and the interner that was used before is put back, even on a panic. Ids
+/// that were given out while the function ran must not be used afterward.
+pub(crate) fn with_scoped_interner<T>(f: impl FnOnce() -> T) -> T {
+    struct Restore(Option<StringInterner>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            if let Some(previous) = self.0.take() {
+                INTERN.with(|intern| *intern.borrow_mut() = previous);
+            }
+        }
+    }
+
+    let previous = INTERN.with(|intern| intern.replace(StringInterner::new()));
+    let _restore = Restore(Some(previous));
+    f()
+}
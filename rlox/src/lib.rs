@@ -3,17 +3,22 @@
 #![warn(missing_debug_implementations)]
 #![deny(missing_docs)]
 
+mod bytecode;
 mod chunk;
 mod compile;
 mod error;
+mod heap;
 mod object;
 mod scan;
 mod token;
 mod value;
+#[cfg(feature = "serde")]
+mod value_serde;
 mod vm;
 
 mod intern;
 
+pub use bytecode::*;
 pub use chunk::*;
 pub use compile::*;
 pub use error::*;
@@ -41,3 +46,6 @@ pub const MAX_CHUNK_CONSTANTS: usize = 256;
 
 /// Maximum number of upvalues a closure can have
 pub const MAX_UPVALUES: usize = 256;
+
+/// Default maximum number of expressions that can be nested in one another
+pub const MAX_NESTING_DEPTH: usize = 1000;
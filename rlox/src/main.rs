@@ -1,7 +1,8 @@
 use std::io::Write;
 use std::{
-    env,
+    env, fs,
     io::{self, BufRead, BufReader},
+    path::Path,
     process,
 };
 
@@ -9,18 +10,25 @@ use rlox::Error;
 
 fn main() {
     let args: Vec<String> = env::args().skip(1).collect();
-    if args.is_empty() {
-        run_repl()
-    } else if args.len() == 1 {
-        run_file(&args[0])
-    } else {
-        println!("Usage: rlox [path]\n");
-        process::exit(64);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] => run_repl(),
+        ["run", path] | [path] => run_file(path, false),
+        ["run", "--warn-globals", path] => run_file(path, true),
+        ["compile", path] => compile_file(path, &Path::new(path).with_extension("loxc")),
+        ["compile", path, "-o", output] => compile_file(path, Path::new(output)),
+        _ => {
+            println!("Usage: rlox [path]");
+            println!("       rlox run [--warn-globals] <path>");
+            println!("       rlox compile <path> [-o <output>]\n");
+            process::exit(64);
+        }
     }
 }
 
 fn run_repl() {
     let mut vm = rlox::VM::default();
+    vm.set_echo_expressions(true);
     let mut reader = BufReader::new(io::stdin());
     loop {
         print!("> ");
@@ -39,25 +47,80 @@ fn run_repl() {
                 if n == 0 {
                     break;
                 }
-                vm.interpret(&line).ok();
+                if let Err(Error::Exit(status)) = vm.interpret(&line) {
+                    process::exit(status);
+                }
             }
         }
     }
 }
 
-fn run_file(path: &str) {
-    let src = match std::fs::read_to_string(path) {
+fn read_source(path: &str) -> String {
+    match fs::read_to_string(path) {
         Ok(s) => s,
         Err(err) => {
             eprintln!("{}", err);
             process::exit(74);
         }
+    }
+}
+
+// The file is run as bytecode if it starts with the bytecode header, otherwise it's compiled
+fn run_file(path: &str, warn_globals: bool) {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(74);
+        }
     };
 
     let mut vm = rlox::VM::default();
-    match vm.interpret(&src) {
-        Ok(()) => {}
-        Err(Error::Runtime) => process::exit(70),
+    vm.set_warn_undefined_globals(warn_globals);
+    let result = if rlox::is_bytecode(&bytes) {
+        match rlox::read_bytecode(bytes.as_slice()) {
+            Ok(script) => vm.interpret_fun(script),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(65);
+            }
+        }
+    } else {
+        match String::from_utf8(bytes) {
+            Ok(src) => vm.interpret(&src),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(65);
+            }
+        }
+    };
+    match result {
+        Ok(_) => {}
+        Err(Error::Runtime) | Err(Error::BudgetExceeded) => process::exit(70),
         Err(Error::Compile) => process::exit(65),
+        Err(Error::Exit(status)) => process::exit(status),
+    }
+}
+
+fn compile_file(path: &str, output: &Path) {
+    let src = read_source(path);
+    let mut compiler = rlox::Compiler::new(&src);
+    compiler.compile();
+    for warning in compiler.warnings() {
+        eprintln!("{}", warning);
+    }
+    for err in compiler.errors() {
+        eprintln!("{}", err);
+    }
+    let script = match compiler.finish() {
+        Some(script) => script,
+        None => process::exit(65),
+    };
+
+    let written = fs::File::create(output)
+        .and_then(|file| rlox::write_bytecode(&script, io::BufWriter::new(file)));
+    if let Err(err) = written {
+        eprintln!("{}", err);
+        process::exit(74);
     }
 }
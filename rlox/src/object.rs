@@ -2,7 +2,7 @@ use std::{cell::RefCell, fmt, rc::Rc};
 
 use rustc_hash::FxHashMap;
 
-use crate::{intern, Chunk, StrId, Value};
+use crate::{intern, Chunk, StrId, Value, VerifyError};
 
 /// A structure for class instance information
 #[derive(Debug)]
@@ -36,6 +36,10 @@ pub struct ObjClass {
     pub name: StrId,
     /// Mapping of all methods defined on the class
     pub methods: FxHashMap<StrId, Value>,
+    /// Mapping of all getters defined on the class
+    pub getters: FxHashMap<StrId, Value>,
+    /// Mapping of all static methods defined on the class
+    pub static_methods: FxHashMap<StrId, Value>,
 }
 
 impl ObjClass {
@@ -44,6 +48,8 @@ impl ObjClass {
         Self {
             name,
             methods: FxHashMap::default(),
+            getters: FxHashMap::default(),
+            static_methods: FxHashMap::default(),
         }
     }
 }
@@ -114,6 +120,77 @@ pub struct ObjFun {
     pub name: StrId,
     /// Number of parameters the function has
     pub arity: u8,
+    /// Indices of the instructions at which the evaluations of the default values start, one for
+    /// each of the optional parameters. Each default value is pushed on to the stack right where
+    /// its parameter's slot is, so the function can skip over the ones whose arguments are given.
+    ///
+    /// ```
+    /// use rlox::{Compiler, Value};
+    ///
+    /// let mut compiler = Compiler::new(r#"fun greet(name, greeting = "Hello") {}"#);
+    /// compiler.compile();
+    /// let script = compiler.finish().unwrap();
+    ///
+    /// let greet = (0..script.chunk.const_count())
+    ///     .find_map(|idx| match script.chunk.read_const(idx) {
+    ///         Value::Fun(fun) => Some(fun.clone()),
+    ///         _ => None,
+    ///     })
+    ///     .unwrap();
+    /// assert_eq!(greet.arity, 2);
+    /// assert_eq!(greet.defaults, vec![0]);
+    /// assert_eq!(greet.body_start, 1);
+    ///
+    /// // a default value can use the parameters that come before it
+    /// let src = "fun f(a, b = a * 2) { print a + b; } f(1); f(1, 5);";
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "3\n6\n");
+    /// ```
+    pub defaults: Vec<usize>,
+    /// Index of the first instruction of the function's body
+    pub body_start: usize,
+    /// True if the last parameter collects all the remaining arguments into a list.
+    ///
+    /// ```
+    /// use rlox::{Compiler, Value};
+    ///
+    /// let mut compiler = Compiler::new("fun sum(first, ...rest) {}");
+    /// compiler.compile();
+    /// let script = compiler.finish().unwrap();
+    ///
+    /// let sum = (0..script.chunk.const_count())
+    ///     .find_map(|idx| match script.chunk.read_const(idx) {
+    ///         Value::Fun(fun) => Some(fun.clone()),
+    ///         _ => None,
+    ///     })
+    ///     .unwrap();
+    /// assert!(sum.variadic);
+    /// assert_eq!(sum.arity, 2);
+    /// assert_eq!(sum.required_arity(), 1);
+    ///
+    /// let src = r#"
+    ///     fun sum(first, ...rest) {
+    ///         var total = first;
+    ///         for (x in rest) total = total + x;
+    ///         return total;
+    ///     }
+    ///     print sum(1);
+    ///     print sum(1, 2, 3);
+    ///     fun all(...items) { return items; }
+    ///     print all();
+    ///     print all(1, "a");
+    /// "#;
+    /// assert_eq!(
+    ///     rlox::interpret_to_string(src).unwrap(),
+    ///     "1\n6\n[]\n[1, a]\n"
+    /// );
+    /// ```
+    pub variadic: bool,
+    /// The number of local variable slots in the function's frame, including the slot that
+    /// holds the function itself, which is the first one
+    pub locals: usize,
+    /// The number of variables that the function captures from the functions enclosing it,
+    /// each of its closures holds one upvalue for each of them
+    pub upvalues: usize,
     /// The bytecode chunk of this function
     pub chunk: Chunk,
 }
@@ -125,11 +202,126 @@ impl ObjFun {
         Self {
             name,
             arity: 0,
+            defaults: Vec::new(),
+            body_start: 0,
+            variadic: false,
+            locals: 1,
+            upvalues: 0,
             chunk: Chunk::default(),
         }
     }
 }
 
+impl ObjFun {
+    /// Get the minimum number of arguments that the function must be called with
+    pub fn required_arity(&self) -> u8 {
+        self.arity - self.defaults.len() as u8
+    }
+
+    /// Get the index of the instruction at which the function starts executing when it's called
+    /// with the given number of arguments
+    pub fn entry(&self, argc: u8) -> usize {
+        let omitted = (self.arity - argc) as usize;
+        if omitted == 0 {
+            self.body_start
+        } else {
+            self.defaults[self.defaults.len() - omitted]
+        }
+    }
+
+    /// Check that the function can be run without the virtual machine failing in unexpected
+    /// ways, which can happen when its chunk was built by hand or loaded from somewhere that
+    /// can't be trusted. The functions that it creates are checked as well. The first problem
+    /// that is found is returned.
+    ///
+    /// Besides the operands of each instruction, every path through the chunk is followed to
+    /// check that the instructions never pop more values than the frame holds.
+    ///
+    /// ```
+    /// use rlox::{ChunkBuilder, Compiler, ObjFun, OpCode, Upvalue, Value, VerifyError};
+    ///
+    /// let src = "fun f(a) { var b = a; for (var i = 0; i < 3; i = i + 1) b = b + i; return b; }";
+    /// let mut compiler = Compiler::new(src);
+    /// compiler.compile();
+    /// assert_eq!(compiler.finish().unwrap().validate(), Ok(()));
+    ///
+    /// let validate = |builder: ChunkBuilder| {
+    ///     let mut fun = ObjFun::new(rlox::id("script"));
+    ///     fun.chunk = builder.build();
+    ///     fun.validate()
+    /// };
+    ///
+    /// let mut builder = ChunkBuilder::default();
+    /// builder.op(OpCode::Constant(3)).op(OpCode::Return);
+    /// assert_eq!(
+    ///     validate(builder),
+    ///     Err(VerifyError::InvalidConstant { at: 0, index: 3 })
+    /// );
+    ///
+    /// let mut builder = ChunkBuilder::default();
+    /// let name = builder.constant(Value::Int(1));
+    /// builder.op(OpCode::GetGlobal(name)).op(OpCode::Return);
+    /// assert_eq!(
+    ///     validate(builder),
+    ///     Err(VerifyError::ConstantType { at: 0, index: 0 })
+    /// );
+    ///
+    /// let mut builder = ChunkBuilder::default();
+    /// builder.op(OpCode::Nil).op(OpCode::Jump(5)).op(OpCode::Return);
+    /// assert_eq!(validate(builder), Err(VerifyError::InvalidJump { at: 1 }));
+    ///
+    /// let mut builder = ChunkBuilder::default();
+    /// builder.op(OpCode::Loop(2)).op(OpCode::Return);
+    /// assert_eq!(validate(builder), Err(VerifyError::InvalidJump { at: 0 }));
+    ///
+    /// let mut builder = ChunkBuilder::default();
+    /// builder.op(OpCode::GetLocal(1)).op(OpCode::Return);
+    /// assert_eq!(validate(builder), Err(VerifyError::InvalidLocal { at: 0, slot: 1 }));
+    ///
+    /// let mut builder = ChunkBuilder::default();
+    /// builder.op(OpCode::Nil);
+    /// assert_eq!(validate(builder), Err(VerifyError::MissingReturn));
+    ///
+    /// // the script has no upvalues
+    /// let mut builder = ChunkBuilder::default();
+    /// builder.op(OpCode::GetUpvalue(3)).op(OpCode::Return);
+    /// assert_eq!(validate(builder), Err(VerifyError::InvalidUpvalue { at: 0, index: 3 }));
+    ///
+    /// let mut builder = ChunkBuilder::default();
+    /// builder.op(OpCode::Nil).op(OpCode::SetUpvalue(0)).op(OpCode::Return);
+    /// assert_eq!(validate(builder), Err(VerifyError::InvalidUpvalue { at: 1, index: 0 }));
+    ///
+    /// // a closure can't capture the upvalue of a function that has none
+    /// let mut inner = ObjFun::new(rlox::id("f"));
+    /// inner.upvalues = 1;
+    /// let mut builder = ChunkBuilder::default();
+    /// builder.op(OpCode::GetUpvalue(0)).op(OpCode::Return);
+    /// inner.chunk = builder.build();
+    /// let mut builder = ChunkBuilder::default();
+    /// let inner = builder.constant(Value::Fun(inner.into()));
+    /// let captured = vec![Upvalue { index: 0, is_local: false }];
+    /// builder.op(OpCode::Closure(inner, captured)).op(OpCode::Return);
+    /// assert_eq!(validate(builder), Err(VerifyError::InvalidUpvalue { at: 0, index: 0 }));
+    ///
+    /// // the frame of the script only holds the script itself
+    /// let mut builder = ChunkBuilder::default();
+    /// builder.op(OpCode::Pop).op(OpCode::Pop).op(OpCode::Add).op(OpCode::Return);
+    /// assert_eq!(validate(builder), Err(VerifyError::StackUnderflow { at: 1 }));
+    ///
+    /// // one path pushes a value that the other doesn't
+    /// let mut builder = ChunkBuilder::default();
+    /// builder.op(OpCode::True);
+    /// let skip = builder.jump(OpCode::JumpIfFalse);
+    /// builder.op(OpCode::Nil);
+    /// builder.patch(skip);
+    /// builder.op(OpCode::Return);
+    /// assert_eq!(validate(builder), Err(VerifyError::StackMismatch { at: 3 }));
+    /// ```
+    pub fn validate(&self) -> Result<(), VerifyError> {
+        self.chunk.validate(self)
+    }
+}
+
 impl fmt::Display for ObjFun {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         let name_str = intern::str(self.name);
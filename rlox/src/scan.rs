@@ -1,40 +1,137 @@
 use std::str::Chars;
 
 use itertools::{self, MultiPeek};
+use unicode_xid::UnicodeXID;
 
 use crate::{
     token::{self, Token},
-    Position, ScanError,
+    Position, ScanError, Value,
 };
 
 /// Scanner reads characters from the source code and groups them in to a sequence of tokens.
-#[derive(Debug)]
+/// Every newline moves the scanner's position to the next line, including the ones that are
+/// inside of string literals. A newline can be `\n`, `\r\n`, or a lone `\r`.
+///
+/// ```
+/// use rlox::{ScanError, Scanner, Type};
+///
+/// let mut scanner = Scanner::new("var s = \"line1\nline2\";\n@");
+/// let mut tok = scanner.scan().unwrap();
+/// while tok.typ != Type::Semicolon {
+///     tok = scanner.scan().unwrap();
+/// }
+/// assert_eq!(tok.pos.line, 2);
+/// assert!(matches!(scanner.scan(), Err(ScanError::UnexpectedChar { pos, .. }) if pos.line == 3));
+///
+/// fn lines(src: &str) -> Vec<usize> {
+///     let mut scanner = Scanner::new(src);
+///     let mut lines = Vec::new();
+///     loop {
+///         let tok = scanner.scan().unwrap();
+///         lines.push(tok.pos.line);
+///         if tok.typ == Type::Eof {
+///             return lines;
+///         }
+///     }
+/// }
+/// let src = "// comment\nvar a = \"x\ny\";\n\nprint a;\n";
+/// assert_eq!(lines(&src.replace('\n', "\r\n")), lines(src));
+/// assert_eq!(lines(&src.replace('\n', "\r")), lines(src));
+/// ```
+#[derive(Debug, Clone)]
 pub struct Scanner<'s> {
     src: &'s str,
     src_iter: MultiPeek<Chars<'s>>,
     pos: Position,
+    // File name given by the last `#line` directive
+    file: Option<&'s str>,
+    // Maximum number of characters in a string literal, 0 if there's no limit
+    max_string_len: usize,
 
+    lexeme_pos: Position,
     lexeme_begin: usize,
     lexeme_end: usize,
 }
 
 impl<'s> Scanner<'s> {
-    /// Create a new scanner
+    /// Create a new scanner. A UTF-8 byte-order mark at the very start of the source is
+    /// ignored. A shebang line at the very start of the source is skipped, but it's still
+    /// counted so later tokens report the correct lines.
+    ///
+    /// ```
+    /// use rlox::{Scanner, Type};
+    ///
+    /// let mut scanner = Scanner::new("#!/usr/bin/env rlox\nprint 1;");
+    /// let tok = scanner.scan().unwrap();
+    /// assert_eq!(tok.typ, Type::Print);
+    /// assert_eq!(tok.pos.line, 2);
+    ///
+    /// let mut vm = rlox::VM::default();
+    /// assert!(vm.interpret("\u{FEFF}print 1;").is_ok());
+    /// assert!(vm.interpret("print 1;\u{FEFF}").is_err());
+    /// ```
     pub fn new(src: &'s str) -> Self {
+        let src = src.strip_prefix('\u{FEFF}').unwrap_or(src);
         let src_iter = itertools::multipeek(src.chars());
-        Self {
+        let mut scanner = Self {
             src,
             src_iter,
             pos: Default::default(),
+            file: None,
+            max_string_len: 0,
+            lexeme_pos: Default::default(),
             lexeme_begin: 0,
             lexeme_end: 0,
+        };
+        if src.starts_with("#!") {
+            scanner.skip_line();
         }
+        scanner
+    }
+
+    /// Reject string literals that have more than the given number of characters with
+    /// [`ScanError::StringTooLong`]. A limit of 0 means there's no limit, which is the default.
+    ///
+    /// ```
+    /// use rlox::{ScanError, Scanner, Type};
+    ///
+    /// let mut scanner = Scanner::new("print \"0123456789\";\nprint \"0123456789a\";");
+    /// scanner.set_max_string_len(10);
+    /// assert_eq!(scanner.scan().unwrap().typ, Type::Print);
+    /// assert_eq!(scanner.scan().unwrap().typ, Type::String);
+    /// assert_eq!(scanner.scan().unwrap().typ, Type::Semicolon);
+    /// assert_eq!(scanner.scan().unwrap().typ, Type::Print);
+    /// let err = scanner.scan().unwrap_err();
+    /// assert!(matches!(
+    ///     err,
+    ///     ScanError::StringTooLong { pos } if pos.line == 2 && pos.column == 7
+    /// ));
+    /// assert_eq!(err.to_string(), "[line 2] Error: String too long.");
+    /// // scanning carries on after the literal
+    /// assert_eq!(scanner.scan().unwrap().typ, Type::Semicolon);
+    /// ```
+    pub fn set_max_string_len(&mut self, len: usize) {
+        self.max_string_len = len;
     }
 
-    /// Consume and return the next token from source.
+    /// Consume and return the next token from source. Identifiers can contain Unicode letters,
+    /// but keywords are always ASCII.
+    ///
+    /// ```
+    /// use rlox::{Scanner, Type};
+    ///
+    /// let mut scanner = Scanner::new("var café = 1;");
+    /// assert_eq!(scanner.scan().unwrap().typ, Type::Var);
+    /// assert_eq!(scanner.scan().unwrap().lexeme, "café");
+    ///
+    /// let mut scanner = Scanner::new("var 😀 = 1;");
+    /// assert_eq!(scanner.scan().unwrap().typ, Type::Var);
+    /// assert!(scanner.scan().is_err());
+    /// ```
     pub fn scan(&mut self) -> Result<Token<'s>, ScanError> {
         self.skip_whitespace();
         self.lexeme_begin = self.lexeme_end;
+        self.lexeme_pos = self.pos;
         let c = match self.advance() {
             None => {
                 return Ok(Token {
@@ -51,13 +148,31 @@ impl<'s> Scanner<'s> {
             ')' => self.make_token(token::Type::RParen),
             '{' => self.make_token(token::Type::LBrace),
             '}' => self.make_token(token::Type::RBrace),
+            '[' => self.make_token(token::Type::LBracket),
+            ']' => self.make_token(token::Type::RBracket),
             ';' => self.make_token(token::Type::Semicolon),
+            ':' => self.make_token(token::Type::Colon),
             ',' => self.make_token(token::Type::Comma),
-            '.' => self.make_token(token::Type::Dot),
+            '.' => {
+                if self.peek_check(|c| c == '.') && self.peek_next_check(|c| c == '.') {
+                    self.advance();
+                    self.advance();
+                    self.make_token(token::Type::DotDotDot)
+                } else {
+                    self.make_token(token::Type::Dot)
+                }
+            }
+            '-' if self.consume('>') => self.make_token(token::Type::MinusGreater),
             '-' => self.make_token(token::Type::Minus),
             '+' => self.make_token(token::Type::Plus),
             '/' => self.make_token(token::Type::Slash),
             '*' => self.make_token(token::Type::Star),
+            '%' => self.make_token(token::Type::Percent),
+            '&' => self.make_token(token::Type::Amp),
+            '|' => self.make_token(token::Type::Pipe),
+            '^' => self.make_token(token::Type::Caret),
+            '~' if self.consume('/') => self.make_token(token::Type::TildeSlash),
+            '~' => self.make_token(token::Type::Tilde),
             '!' => {
                 if self.consume('=') {
                     self.make_token(token::Type::BangEqual)
@@ -75,6 +190,8 @@ impl<'s> Scanner<'s> {
             '<' => {
                 if self.consume('=') {
                     self.make_token(token::Type::LessEqual)
+                } else if self.consume('<') {
+                    self.make_token(token::Type::LessLess)
                 } else {
                     self.make_token(token::Type::Less)
                 }
@@ -82,28 +199,67 @@ impl<'s> Scanner<'s> {
             '>' => {
                 if self.consume('=') {
                     self.make_token(token::Type::GreaterEqual)
+                } else if self.consume('>') {
+                    self.make_token(token::Type::GreaterGreater)
                 } else {
                     self.make_token(token::Type::Greater)
                 }
             }
-            '"' => self.string()?,
-            n if is_digit(n) => self.number(),
+            '?' if self.consume('?') => self.make_token(token::Type::QuestionQuestion),
+            '?' if self.consume('.') => self.make_token(token::Type::QuestionDot),
+            '"' => self.string(token::Type::String)?,
+            'r' if self.peek_check(|c| c == '"') => {
+                self.advance();
+                self.string(token::Type::RawString)?
+            }
+            n if is_digit(n) => self.number()?,
             c if is_alpha(c) => self.identity(),
-            _ => {
-                return Err(ScanError::UnexpectedCharacter(self.pos));
+            ch => {
+                return Err(ScanError::UnexpectedChar {
+                    ch,
+                    pos: self.lexeme_pos,
+                });
             }
         })
     }
 
+    /// Return the file name that was given by the last `#line` directive. A line that only
+    /// contains `#line <number>` or `#line <number> "<file>"` sets the line number of the
+    /// following line, so code generated from another file can report errors at their origin.
+    /// The directive doesn't produce any token.
+    ///
+    /// ```
+    /// use rlox::{Compiler, Scanner, Type};
+    ///
+    /// let mut scanner = Scanner::new("print 1;\n#line 42 \"gen.lox\"\nprint 2;");
+    /// while scanner.scan().unwrap().typ != Type::Semicolon {}
+    /// assert_eq!(scanner.file(), None);
+    /// let tok = scanner.scan().unwrap();
+    /// assert_eq!(tok.typ, Type::Print);
+    /// assert_eq!(tok.pos.line, 42);
+    /// assert_eq!(scanner.file(), Some("gen.lox"));
+    ///
+    /// let mut compiler = Compiler::new("#line 100\nprint 1 +;");
+    /// compiler.compile();
+    /// assert_eq!(compiler.errors(), ["[line 100] Error at ';': Expect expression but found ';'."]);
+    /// ```
+    pub fn file(&self) -> Option<&'s str> {
+        self.file
+    }
+
     fn identity(&mut self) -> Token<'s> {
-        while self.peek_check(|c| is_alpha(c) || is_digit(c)) {
+        while self.peek_check(is_alpha_numeric) {
             self.advance();
         }
         self.make_token(match &self.src[self.lexeme_begin..self.lexeme_end] {
             "and" => token::Type::And,
+            "break" => token::Type::Break,
             "class" => token::Type::Class,
+            "const" => token::Type::Const,
+            "continue" => token::Type::Continue,
             "else" => token::Type::Else,
             "if" => token::Type::If,
+            "in" => token::Type::In,
             "false" => token::Type::False,
             "for" => token::Type::For,
             "fun" => token::Type::Fun,
@@ -111,37 +267,73 @@ impl<'s> Scanner<'s> {
             "or" => token::Type::Or,
             "print" => token::Type::Print,
             "return" => token::Type::Return,
+            "static" => token::Type::Static,
             "super" => token::Type::Super,
             "this" => token::Type::This,
             "true" => token::Type::True,
             "var" => token::Type::Var,
+            "when" => token::Type::When,
             "while" => token::Type::While,
             _ => token::Type::Ident,
         })
     }
 
-    fn number(&mut self) -> Token<'s> {
-        while self.peek_check(is_digit) {
-            self.advance();
-        }
+    // The whole word that starts with a digit is taken as the number, so that its format is only
+    // checked by `parse_number_literal`. Scanning resumes after the word if it's invalid.
+    fn number(&mut self) -> Result<Token<'s>, ScanError> {
+        self.number_part();
         if self.peek_check(|c| c == '.') && self.peek_next_check(is_digit) {
             self.advance();
-            while self.peek_check(is_digit) {
+            self.number_part();
+        }
+        let token = self.make_token(token::Type::Number);
+        if parse_number_literal(token.lexeme).is_none() {
+            return Err(ScanError::InvalidNumber {
+                pos: self.lexeme_pos,
+            });
+        }
+        Ok(token)
+    }
+
+    fn number_part(&mut self) {
+        loop {
+            while self.peek_check(is_alpha_numeric) {
                 self.advance();
             }
+            // the sign of a decimal exponent is part of the number
+            let lexeme = &self.src[self.lexeme_begin..self.lexeme_end];
+            let is_exponent = lexeme.ends_with(['e', 'E'])
+                && !lexeme.starts_with("0x")
+                && !lexeme.starts_with("0X");
+            if is_exponent
+                && self.peek_check(|c| c == '+' || c == '-')
+                && self.peek_next_check(is_digit)
+            {
+                self.advance();
+                continue;
+            }
+            break;
         }
-        self.make_token(token::Type::Number)
     }
 
-    fn string(&mut self) -> Result<Token<'s>, ScanError> {
+    fn string(&mut self, typ: token::Type) -> Result<Token<'s>, ScanError> {
+        let mut len = 0;
         while self.peek_check(|c| c != '"') {
             self.advance();
+            len += 1;
         }
         if self.peek().is_none() {
-            return Err(ScanError::UnterminatedString(self.pos));
+            return Err(ScanError::UnterminatedString {
+                pos: self.lexeme_pos,
+            });
         }
         self.advance();
-        Ok(self.make_token(token::Type::String))
+        if self.max_string_len > 0 && len > self.max_string_len {
+            return Err(ScanError::StringTooLong {
+                pos: self.lexeme_pos,
+            });
+        }
+        Ok(self.make_token(typ))
     }
 
     fn skip_whitespace(&mut self) {
@@ -154,8 +346,11 @@ impl<'s> Scanner<'s> {
                     if !self.peek_next_check(|c| c == '/') {
                         return;
                     }
-                    while self.peek_check(|c| c != '\n') {
-                        self.advance();
+                    self.skip_line();
+                }
+                '#' => {
+                    if !self.line_directive() {
+                        return;
                     }
                 }
                 _ => return,
@@ -163,6 +358,32 @@ impl<'s> Scanner<'s> {
         }
     }
 
+    // Skip a `#line` directive and apply it, return false if the line isn't a valid directive
+    fn line_directive(&mut self) -> bool {
+        let rest = &self.src[self.lexeme_end..];
+        let line = rest.split(is_line_end).next().unwrap_or("");
+        let (number, file) = match parse_line_directive(line) {
+            Some(directive) => directive,
+            None => return false,
+        };
+
+        self.skip_line();
+        self.consume('\r');
+        self.consume('\n');
+        self.pos.line = number;
+        if file.is_some() {
+            self.file = file;
+        }
+        true
+    }
+
+    // Advance until the end of the current line without consuming the newline
+    fn skip_line(&mut self) {
+        while self.peek_check(|c| !is_line_end(c)) {
+            self.advance();
+        }
+    }
+
     fn peek_check<F: Fn(char) -> bool>(&mut self, check: F) -> bool {
         self.peek().map(check).unwrap_or(false)
     }
@@ -185,14 +406,14 @@ impl<'s> Scanner<'s> {
     }
 
     fn advance(&mut self) -> Option<char> {
-        self.src_iter.next().map(|c| {
+        self.src_iter.next().inspect(|&c| {
             self.lexeme_end += c.len_utf8();
-            if c == '\n' {
+            // the line only changes once for "\r\n"
+            if c == '\n' || (c == '\r' && !self.peek_check(|c| c == '\n')) {
                 self.pos.next_line();
             } else {
                 self.pos.next_column();
             }
-            c
         })
     }
 
@@ -211,15 +432,244 @@ impl<'s> Scanner<'s> {
         Token {
             typ,
             lexeme: &self.src[self.lexeme_begin..self.lexeme_end],
-            pos: self.pos,
+            pos: self.lexeme_pos,
+        }
+    }
+}
+
+/// Scan the whole source and describe the tokens as a JSON array. Each token becomes an object
+/// with its type, lexeme, and the line and column at which it starts, the last one is always
+/// the `Eof` token. Scanning errors become objects of type `Error` with a message instead of a
+/// lexeme.
+///
+/// ```
+/// let json = rlox::tokens_to_json("1 + 2");
+/// assert_eq!(
+///     json,
+///     concat!(
+///         r#"[{"type":"Number","lexeme":"1","line":1,"column":1},"#,
+///         r#"{"type":"Plus","lexeme":"+","line":1,"column":3},"#,
+///         r#"{"type":"Number","lexeme":"2","line":1,"column":5},"#,
+///         r#"{"type":"Eof","lexeme":"","line":1,"column":6}]"#,
+///     )
+/// );
+/// ```
+pub fn tokens_to_json(src: &str) -> String {
+    let mut scanner = Scanner::new(src);
+    let mut objects = Vec::new();
+    loop {
+        match scanner.scan() {
+            Ok(tok) => {
+                objects.push(format!(
+                    r#"{{"type":"{:?}","lexeme":{},"line":{},"column":{}}}"#,
+                    tok.typ,
+                    json_string(tok.lexeme),
+                    tok.pos.line,
+                    tok.pos.column
+                ));
+                if tok.typ == token::Type::Eof {
+                    break;
+                }
+            }
+            Err(err) => {
+                let pos = err.pos();
+                objects.push(format!(
+                    r#"{{"type":"Error","message":{},"line":{},"column":{}}}"#,
+                    json_string(err.message()),
+                    pos.line,
+                    pos.column
+                ));
+            }
+        }
+    }
+    format!("[{}]", objects.join(","))
+}
+
+/// Return the value of a number written in the same format as a number literal, or `None` if
+/// the text isn't a number. The scanner and the compiler both go through this function, so
+/// they always agree on which numbers are valid.
+///
+/// Numbers can be written in decimal, with an optional fractional part and exponent, or as
+/// integers in hexadecimal with `0x` and in binary with `0b`. Underscores can separate the
+/// digits. Numbers without a fractional part or exponent are integers, unless they don't fit
+/// in one.
+///
+/// ```
+/// use rlox::{parse_number_literal, Value};
+///
+/// let int = |text| match parse_number_literal(text) {
+///     Some(Value::Int(n)) => Some(n),
+///     _ => None,
+/// };
+/// let float = |text| match parse_number_literal(text) {
+///     Some(Value::Number(n)) => Some(n),
+///     _ => None,
+/// };
+///
+/// assert_eq!(int("42"), Some(42));
+/// assert_eq!(float("3.25"), Some(3.25));
+/// assert_eq!(int("0xff"), Some(255));
+/// assert_eq!(int("0XFF"), Some(255));
+/// assert_eq!(int("0b101"), Some(5));
+/// assert_eq!(float("1e3"), Some(1000.0));
+/// assert_eq!(float("2.5E-1"), Some(0.25));
+/// assert_eq!(float("1e+2"), Some(100.0));
+/// assert_eq!(int("1_000_000"), Some(1_000_000));
+/// assert_eq!(float("1_000.000_1"), Some(1000.0001));
+/// assert_eq!(int("0xdead_beef"), Some(0xdead_beef));
+/// assert_eq!(float("99999999999999999999"), Some(1e20));
+///
+/// for text in [
+///     "", "1.", ".5", "1e", "1e+", "0x", "0b2", "0x1.5", "12abc", "_1", "1_", "1__0", "1_.5",
+///     "0x_1", "-1",
+/// ]
+/// .iter()
+/// {
+///     assert!(parse_number_literal(text).is_none(), "{}", text);
+/// }
+/// ```
+pub fn parse_number_literal(text: &str) -> Option<Value> {
+    let radix_digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .map(|digits| (16, digits))
+        .or_else(|| {
+            text.strip_prefix("0b")
+                .or_else(|| text.strip_prefix("0B"))
+                .map(|digits| (2, digits))
+        });
+    if let Some((radix, digits)) = radix_digits {
+        let digits = without_separators(digits, |c| c.is_digit(radix))?;
+        return Some(match i64::from_str_radix(&digits, radix) {
+            Ok(n) => Value::Int(n),
+            Err(_) => Value::Number(digits.chars().fold(0.0, |n, c| {
+                n * radix as f64 + c.to_digit(radix).unwrap_or(0) as f64
+            })),
+        });
+    }
+
+    let (mantissa, exponent) = match text.find(['e', 'E']) {
+        Some(idx) => (&text[..idx], Some(&text[idx + 1..])),
+        None => (text, None),
+    };
+    let (int_part, frac_part) = match mantissa.find('.') {
+        Some(idx) => (&mantissa[..idx], Some(&mantissa[idx + 1..])),
+        None => (mantissa, None),
+    };
+    let mut number = without_separators(int_part, is_digit)?;
+    if let Some(frac_part) = frac_part {
+        number.push('.');
+        number.push_str(&without_separators(frac_part, is_digit)?);
+    }
+    if let Some(exponent) = exponent {
+        let (sign, digits) = match exponent.strip_prefix(['+', '-']) {
+            Some(digits) => (&exponent[..1], digits),
+            None => ("", exponent),
+        };
+        number.push('e');
+        number.push_str(sign);
+        number.push_str(&without_separators(digits, is_digit)?);
+    }
+
+    if frac_part.is_none() && exponent.is_none() {
+        if let Ok(n) = number.parse() {
+            return Some(Value::Int(n));
+        }
+    }
+    number.parse().ok().map(Value::Number)
+}
+
+/// Split the source into its lines along with the line numbers that the scanner gives them.
+/// Like the scanner, a lone `\r` is also a line break and a `#line` directive sets the number
+/// of the line that follows it.
+pub(crate) fn numbered_lines(src: &str) -> Vec<(usize, &str)> {
+    let mut rest = src.strip_prefix('\u{FEFF}').unwrap_or(src);
+    let mut lines = Vec::new();
+    let mut line = 1;
+    loop {
+        let end = rest.find(is_line_end).unwrap_or(rest.len());
+        let text = &rest[..end];
+        lines.push((line, text));
+        if end == rest.len() {
+            return lines;
+        }
+        let line_break_len = if rest[end..].starts_with("\r\n") {
+            2
+        } else {
+            1
+        };
+        rest = &rest[end + line_break_len..];
+        line = match parse_line_directive(text.trim_start()) {
+            Some((number, _)) => number,
+            None => line + 1,
+        };
+    }
+}
+
+// Parse the line number and the optional file name of a `#line` directive
+fn parse_line_directive(line: &str) -> Option<(usize, Option<&str>)> {
+    let args = match line.strip_prefix("#line") {
+        Some(args) if args.starts_with([' ', '\t']) => args.trim(),
+        _ => return None,
+    };
+    let (number, file) = match args.find(char::is_whitespace) {
+        Some(idx) => (&args[..idx], Some(args[idx..].trim_start())),
+        None => (args, None),
+    };
+    let number = number.parse::<usize>().ok()?;
+    let file = match file {
+        None => None,
+        Some(f) if f.len() >= 2 && f.starts_with('"') && f.ends_with('"') => {
+            Some(&f[1..f.len() - 1])
+        }
+        Some(_) => return None,
+    };
+    Some((number, file))
+}
+
+// Return the digits without the underscores that separate them. Underscores can only be
+// placed between two digits.
+fn without_separators<F: Fn(char) -> bool>(text: &str, is_digit: F) -> Option<String> {
+    let mut digits = String::with_capacity(text.len());
+    for group in text.split('_') {
+        if group.is_empty() || !group.chars().all(&is_digit) {
+            return None;
+        }
+        digits.push_str(group);
+    }
+    Some(digits)
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
         }
     }
+    escaped.push('"');
+    escaped
+}
+
+fn is_line_end(c: char) -> bool {
+    c == '\n' || c == '\r'
 }
 
 fn is_digit(c: char) -> bool {
-    c.is_digit(10)
+    c.is_ascii_digit()
 }
 
 fn is_alpha(c: char) -> bool {
-    c.is_alphabetic() || c == '_'
+    c == '_' || c.is_xid_start()
+}
+
+fn is_alpha_numeric(c: char) -> bool {
+    c.is_xid_continue()
 }
@@ -30,6 +30,47 @@ impl<'l> Token<'l> {
 }
 
 /// Lox token types
+///
+/// ```
+/// use rlox::{Scanner, Type};
+///
+/// let src = r#"
+///     ( ) { } [ ] , . ... - -> + ; : / * % ?? ?. & | ^ ~ ~/ << >>
+///     ! != = == > >= < <= // comments are skipped
+///     name "string" r"raw" 12 3.5
+///     and break class const continue else false for fun if in nil or print return static
+///     super this true var when while
+/// "#;
+/// let mut scanner = Scanner::new(src);
+/// let mut types = Vec::new();
+/// loop {
+///     let tok = scanner.scan().unwrap();
+///     types.push(tok.typ);
+///     if tok.typ == Type::Eof {
+///         break;
+///     }
+/// }
+/// assert_eq!(
+///     types,
+///     vec![
+///         Type::LParen, Type::RParen, Type::LBrace, Type::RBrace, Type::LBracket,
+///         Type::RBracket, Type::Comma, Type::Dot, Type::DotDotDot, Type::Minus,
+///         Type::MinusGreater, Type::Plus, Type::Semicolon, Type::Colon, Type::Slash, Type::Star, Type::Percent,
+///         Type::QuestionQuestion, Type::QuestionDot, Type::Amp, Type::Pipe, Type::Caret,
+///         Type::Tilde, Type::TildeSlash, Type::LessLess, Type::GreaterGreater, Type::Bang, Type::BangEqual, Type::Equal,
+///         Type::EqualEqual, Type::Greater, Type::GreaterEqual, Type::Less, Type::LessEqual,
+///         Type::Ident, Type::String, Type::RawString, Type::Number, Type::Number, Type::And,
+///         Type::Break, Type::Class, Type::Const, Type::Continue, Type::Else, Type::False, Type::For, Type::Fun, Type::If,
+///         Type::In, Type::Nil, Type::Or, Type::Print, Type::Return, Type::Static, Type::Super,
+///         Type::This, Type::True, Type::Var, Type::When, Type::While, Type::Eof,
+///     ]
+/// );
+///
+/// // keywords are only recognized as whole words
+/// let mut scanner = Scanner::new("classy orchid");
+/// assert_eq!(scanner.scan().unwrap().typ, Type::Ident);
+/// assert_eq!(scanner.scan().unwrap().typ, Type::Ident);
+/// ```
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Type {
     /// Single character '('
@@ -40,20 +81,85 @@ pub enum Type {
     LBrace,
     /// Single character '}'
     RBrace,
+    /// Single character '['
+    LBracket,
+    /// Single character ']'
+    RBracket,
     /// Single character ','
     Comma,
     /// Single character '.'
     Dot,
+    /// Triple character '...'
+    DotDotDot,
     /// Single character '-'
     Minus,
+    /// Double character '->'
+    MinusGreater,
     /// Single character '+'
     Plus,
     /// Single character ';'
     Semicolon,
+    /// Single character ':'
+    Colon,
     /// Single character '/'
     Slash,
     /// Single character '*'
     Star,
+    /// Single character '%'
+    Percent,
+    /// Double character '??'
+    QuestionQuestion,
+    /// Double character '?.', accesses a property unless the receiver is nil, in which case
+    /// the rest of the chain of accesses and calls is skipped and evaluates to nil
+    ///
+    /// ```
+    /// let src = r#"
+    ///     class Node { init(next) { this.next = next; } }
+    ///     var a = Node(Node(nil));
+    ///     print nil?.x;
+    ///     print a?.next?.next;
+    ///     print a?.next?.next?.next;
+    ///     print a.next?.next?.next?.next;
+    /// "#;
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "nil\nnil\nnil\nnil\n");
+    ///
+    /// let src = r#"
+    ///     class Node { init(next) { this.next = next; } size() { return 1; } }
+    ///     var a = nil;
+    ///     print a?.next.next;
+    ///     print a?.next.size() == nil;
+    ///     print a?.next[0](1, 2);
+    ///     print Node(nil)?.next ?? 3;
+    ///     print Node(Node(nil))?.next.size();
+    /// "#;
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "nil\ntrue\nnil\n3\n1\n");
+    ///
+    /// assert_eq!(
+    ///     rlox::interpret_to_string("print 1?.x;").unwrap_err(),
+    ///     "Only instances have properties.\n[line 1] in script.\n"
+    /// );
+    /// ```
+    QuestionDot,
+    /// Single character '&'
+    Amp,
+    /// Single character '|'
+    Pipe,
+    /// Single character '^'
+    Caret,
+    /// Single character '~'
+    Tilde,
+    /// Double character '~/', divides and rounds the result down. `//` can't be used for it
+    /// since it starts a comment.
+    ///
+    /// ```
+    /// let src = "print 7 ~/ 2; print 7.5 ~/ 2; print -7 ~/ 2; print 7 / 2;";
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "3\n3\n-4\n3.5\n");
+    /// ```
+    TildeSlash,
+    /// Double character '<<'
+    LessLess,
+    /// Double character '>>'
+    GreaterGreater,
     /// Single character '!'
     Bang,
     /// Double character '!='
@@ -74,12 +180,69 @@ pub enum Type {
     Ident,
     /// String literal
     String,
+    /// String literal that is prefixed with 'r', its content is taken verbatim. A raw string
+    /// ends at the first double quote since nothing can be escaped in it.
+    ///
+    /// ```
+    /// use rlox::{Scanner, Type};
+    ///
+    /// let mut scanner = Scanner::new(r#"r"C:\path" r"#);
+    /// let tok = scanner.scan().unwrap();
+    /// assert_eq!(tok.typ, Type::RawString);
+    /// assert_eq!(tok.lexeme, r#"r"C:\path""#);
+    /// assert_eq!(scanner.scan().unwrap().typ, Type::Ident);
+    ///
+    /// let src = r#"print len(r"a\nb"); print r"a\nb";"#;
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "4\na\\nb\n");
+    /// ```
+    RawString,
     /// Number literal
     Number,
     /// Keyword 'and'
     And,
+    /// Keyword 'break', exits the innermost loop or the loop with the given label. Locals that
+    /// were declared inside the loop are discarded before jumping out.
+    ///
+    /// ```
+    /// use rlox::Compiler;
+    ///
+    /// let src = r#"
+    ///     outer: for (var i = 0; i < 3; i = i + 1) {
+    ///         for (var j = 0; j < 3; j = j + 1) {
+    ///             var product = i * j;
+    ///             if (product == 2) break outer;
+    ///             if (j == i) continue outer;
+    ///             print product;
+    ///         }
+    ///     }
+    ///     print "done";
+    ///     var n = 0;
+    ///     while (true) {
+    ///         n = n + 1;
+    ///         if (n < 3) continue;
+    ///         break;
+    ///     }
+    ///     print n;
+    /// "#;
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "0\n0\ndone\n3\n");
+    ///
+    /// let mut compiler = Compiler::new("while (true) { break inner; } break;");
+    /// compiler.compile();
+    /// assert_eq!(
+    ///     compiler.errors(),
+    ///     [
+    ///         "[line 1] Error at 'inner': Undefined loop label 'inner'.",
+    ///         "[line 1] Error at 'break': Can't use 'break' outside of a loop.",
+    ///     ]
+    /// );
+    /// ```
+    Break,
     /// Keyword 'class'
     Class,
+    /// Keyword 'const'
+    Const,
+    /// Keyword 'continue'
+    Continue,
     /// Keyword 'else'
     Else,
     /// Boolean literal 'false'
@@ -90,6 +253,26 @@ pub enum Type {
     Fun,
     /// Keyword 'if'
     If,
+    /// Keyword 'in', which iterates over the items of a list in a `for` loop
+    ///
+    /// ```
+    /// let src = r#"
+    ///     for (x in [10, 20, 30]) print x;
+    ///     var fs = [];
+    ///     for (x in [1, 2, 3, 4, 5]) {
+    ///         if (x == 2) continue;
+    ///         if (x == 4) break;
+    ///         fun f() { return x; }
+    ///         fs = fs + [f];
+    ///     }
+    ///     for (f in fs) print f();
+    /// "#;
+    /// assert_eq!(
+    ///     rlox::interpret_to_string(src).unwrap(),
+    ///     "10\n20\n30\n1\n3\n"
+    /// );
+    /// ```
+    In,
     /// Nothing literal 'nil'
     Nil,
     /// Keyword 'or'
@@ -98,6 +281,8 @@ pub enum Type {
     Print,
     /// Keyword 'return'
     Return,
+    /// Keyword 'static'
+    Static,
     /// Keyword 'super'
     Super,
     /// Keyword 'this'
@@ -106,6 +291,51 @@ pub enum Type {
     True,
     /// Keyword 'var'
     Var,
+    /// Keyword 'when', starts an expression that evaluates to the first branch whose condition
+    /// is truthy. The `else` branch is required.
+    ///
+    /// ```
+    /// use rlox::Compiler;
+    ///
+    /// let src = r#"
+    ///     fun sign(n) {
+    ///         return when {
+    ///             n < 0 -> "negative",
+    ///             n > 0 -> "positive",
+    ///             else -> "zero",
+    ///         };
+    ///     }
+    ///     print sign(-2);
+    ///     print sign(3);
+    ///     print sign(0);
+    ///     // only the first truthy branch is evaluated
+    ///     fun print_and(v) { print "evaluated"; return v; }
+    ///     print when { true -> 1, print_and(true) -> 2, else -> 3 };
+    /// "#;
+    /// assert_eq!(
+    ///     rlox::interpret_to_string(src).unwrap(),
+    ///     "negative\npositive\nzero\n1\n"
+    /// );
+    ///
+    /// let mut compiler = Compiler::new("print when { a -> 1, else -> 2 };");
+    /// compiler.compile();
+    /// let chunk = compiler.finish().unwrap().chunk;
+    /// let ops: Vec<_> = (0..chunk.instructions_count())
+    ///     .map(|i| chunk.read_instruction(i).0.name())
+    ///     .collect();
+    /// assert_eq!(
+    ///     ops,
+    ///     ["GetGlobal", "JumpIfFalse", "Pop", "SmallInt", "Jump", "Pop", "SmallInt", "Print", "Nil", "Return"]
+    /// );
+    ///
+    /// let mut compiler = Compiler::new("print when { false -> 1, true -> 2 };");
+    /// compiler.compile();
+    /// assert_eq!(
+    ///     compiler.errors(),
+    ///     ["[line 1] Error at '}': Expect 'else' branch in 'when' expression."]
+    /// );
+    /// ```
+    When,
     /// Keyword 'while'
     While,
     /// Special token for indicating end-of-file
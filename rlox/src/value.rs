@@ -1,10 +1,18 @@
-use std::ops;
-use std::{cell::RefCell, fmt, rc::Rc};
+use std::{cell::RefCell, fmt, hash::BuildHasherDefault, rc::Rc};
+use std::{cmp::Ordering, convert::TryFrom, ops};
+
+use indexmap::IndexMap;
+use rustc_hash::FxHasher;
 
 use crate::{
-    intern, ObjBoundMethod, ObjClass, ObjClosure, ObjFun, ObjInstance, RuntimeError, StrId,
+    intern, ObjBoundMethod, ObjClass, ObjClosure, ObjFun, ObjInstance, RuntimeError, StrId, VM,
 };
 
+/// The entries of a map value, they are kept in the order in which their keys were first
+/// inserted so that maps are always printed the same way. Keys are owned rather than interned
+/// since they are often created at runtime.
+pub type MapEntries = IndexMap<Rc<str>, Value, BuildHasherDefault<FxHasher>>;
+
 /// This represents a Lox type and its data at.
 #[derive(Debug, Clone)]
 pub enum Value {
@@ -12,11 +20,74 @@ pub enum Value {
     Nil,
     /// A boolean value in Lox
     Bool(bool),
-    /// A number value in Lox
+    /// A floating-point number value in Lox. Division by zero isn't an error, it follows IEEE 754
+    /// and gives an infinity or a NaN, which are printed as `inf`, `-inf`, and `nan`. Negative
+    /// zero is printed as `-0` and equals zero, NaN isn't equal to anything.
+    ///
+    /// ```
+    /// let src = r#"
+    ///     print 1 / 0;
+    ///     print -1 / 0;
+    ///     print 0 / 0;
+    ///     print -0.0;
+    ///     print [1 / 0, 0 / 0];
+    ///     print -0.0 == 0.0;
+    ///     var n = 0 / 0;
+    ///     print n == n;
+    ///     print 1 / 0 == 1 / 0;
+    ///     print 1 / 0 == -1 / 0;
+    /// "#;
+    /// assert_eq!(
+    ///     rlox::interpret_to_string(src).unwrap(),
+    ///     "inf\n-inf\nnan\n-0\n[inf, nan]\ntrue\nfalse\ntrue\nfalse\n"
+    /// );
+    /// ```
     Number(f64),
+    /// An integer number value in Lox. Arithmetic between integers gives an integer, except for
+    /// division which always gives a floating-point number, and negating zero which gives the
+    /// floating-point negative zero.
+    ///
+    /// ```
+    /// use rlox::Value;
+    ///
+    /// let res = (&Value::Int(1) / &Value::Int(2)).unwrap();
+    /// assert!(matches!(res, Value::Number(n) if n == 0.5));
+    ///
+    /// let res = (&Value::Int(7) % &Value::Int(3)).unwrap();
+    /// assert!(matches!(res, Value::Int(1)));
+    ///
+    /// // an integer only equals the floating-point number with the exact same value
+    /// assert_eq!(Value::Int(3), Value::Number(3.0));
+    /// assert_ne!(Value::Int(0), Value::Number(1e-20));
+    ///
+    /// let res = (-&Value::Int(0)).unwrap();
+    /// assert!(matches!(res, Value::Number(n) if n == 0.0 && n.is_sign_negative()));
+    /// assert_eq!(rlox::interpret_to_string("print -0; var z = 0; print -z;").unwrap(), "-0\n-0\n");
+    /// ```
+    Int(i64),
     /// A constant hashed string
     Str(StrId),
-    /// A heap allocated string
+    /// A heap allocated string. Strings that are created while the program runs, such as the
+    /// result of a concatenation, are not interned, so they are freed once they are unused.
+    /// Literals are interned and use [`Value::Str`] instead.
+    ///
+    /// ```
+    /// use string_interner::Symbol;
+    ///
+    /// let src = r#"
+    ///     var s = "";
+    ///     for (var i = 0; i < 10000; i = i + 1) {
+    ///         s = s + "x";
+    ///         var t = substring(s, 0, i);
+    ///     }
+    ///     print len(s);
+    /// "#;
+    /// // symbols are numbered in the order in which their strings are interned
+    /// let before = rlox::id("interned before the loop").to_usize();
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "10000\n");
+    /// let after = rlox::id("interned after the loop").to_usize();
+    /// assert!(after - before < 100);
+    /// ```
     String(Rc<str>),
     /// A native function reference
     NativeFun(NativeFun),
@@ -30,20 +101,104 @@ pub enum Value {
     Instance(Rc<RefCell<ObjInstance>>),
     /// A class instance
     BoundMethod(Rc<ObjBoundMethod>),
+    /// A list of values, two lists are equal when they have equal elements in the same order
+    ///
+    /// ```
+    /// let src = r#"
+    ///     print [1, 2] == [1, 2];
+    ///     print [1] == [1, 2];
+    ///     print [[1], {"a": 2}] == [[1], {"a": 2.0}];
+    ///     print {"a": 1, "b": 2} == {"b": 2, "a": 1};
+    ///     var l = [1];
+    ///     l[0] = l;
+    ///     print l == l;
+    ///     var m = [1];
+    ///     m[0] = m;
+    ///     print l == m;
+    /// "#;
+    /// assert_eq!(
+    ///     rlox::interpret_to_string(src).unwrap(),
+    ///     "true\nfalse\ntrue\ntrue\ntrue\ntrue\n"
+    /// );
+    /// ```
+    List(Rc<RefCell<Vec<Value>>>),
+    /// A mapping from strings to values, its entries are iterated in insertion order. Two maps
+    /// are equal when they have the same keys with equal values, regardless of the order.
+    ///
+    /// ```
+    /// let src = r#"
+    ///     print {"a": 1, "b": 2, "c": 3};
+    ///     var m = {};
+    ///     m["c"] = 3;
+    ///     m["a"] = 1;
+    ///     m["b"] = 2;
+    ///     m["c"] = 4;
+    ///     print m;
+    /// "#;
+    /// assert_eq!(
+    ///     rlox::interpret_to_string(src).unwrap(),
+    ///     "{a: 1, b: 2, c: 3}\n{c: 4, a: 1, b: 2}\n"
+    /// );
+    /// ```
+    Map(Rc<RefCell<MapEntries>>),
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        self.fmt_nested(f, false, &mut Vec::new())
+    }
+}
+
+/// Formats a value the way it's written in source code, see [`Value::repr`]
+#[derive(Debug)]
+pub struct Repr<'a>(&'a Value);
+
+impl fmt::Display for Repr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt_nested(f, true, &mut Vec::new())
+    }
+}
+
+impl Value {
+    /// Return a formatter that shows strings in quotes, with their quotes, backslashes, and
+    /// control characters escaped, including strings that are inside of lists and maps. This
+    /// is meant for inspecting values rather than for showing them to users.
+    ///
+    /// ```
+    /// use rlox::Value;
+    ///
+    /// let s = Value::String("say \"hi\"\n".into());
+    /// assert_eq!(s.repr().to_string(), r#""say \"hi\"\n""#);
+    /// assert_eq!(Value::Int(1).repr().to_string(), "1");
+    /// ```
+    pub fn repr(&self) -> Repr<'_> {
+        Repr(self)
+    }
+
+    // Lists and maps can contain themselves, so we keep track of the ones that are being
+    // formatted to not recurse forever.
+    fn fmt_nested(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        quote: bool,
+        visiting: &mut Vec<*const ()>,
+    ) -> std::result::Result<(), std::fmt::Error> {
         match self {
             Self::Nil => write!(f, "nil"),
             Self::Bool(b) => write!(f, "{}", b),
             Self::Number(n) => {
-                if n.trunc().eq(n) {
+                // the alternate form keeps the fractional part of whole floats
+                if n.is_nan() {
+                    write!(f, "nan")
+                } else if n.trunc().eq(n) && !f.alternate() {
                     write!(f, "{:.0?}", n)
                 } else {
                     write!(f, "{:?}", n)
                 }
             }
+            Self::Int(n) => write!(f, "{}", n),
+            Self::Str(s) if quote => write_quoted(f, &intern::str(*s)),
+            Self::String(s) if quote => write_quoted(f, s),
             Self::Str(s) => write!(f, "{}", intern::str(*s)),
             Self::String(s) => write!(f, "{}", s),
             Self::NativeFun(fun) => write!(f, "{}", fun),
@@ -52,8 +207,62 @@ impl fmt::Display for Value {
             Self::Class(c) => write!(f, "{}", c.borrow()),
             Self::Instance(i) => write!(f, "{}", i.borrow()),
             Self::BoundMethod(m) => write!(f, "{}", m),
+            Self::List(l) => {
+                let ptr = Rc::as_ptr(l) as *const ();
+                if visiting.contains(&ptr) {
+                    return write!(f, "[...]");
+                }
+                visiting.push(ptr);
+                write!(f, "[")?;
+                for (i, v) in l.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    v.fmt_nested(f, quote, visiting)?;
+                }
+                visiting.pop();
+                write!(f, "]")
+            }
+            Self::Map(m) => {
+                let ptr = Rc::as_ptr(m) as *const ();
+                if visiting.contains(&ptr) {
+                    return write!(f, "{{...}}");
+                }
+                visiting.push(ptr);
+                write!(f, "{{")?;
+                for (i, (k, v)) in m.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    if quote {
+                        write_quoted(f, k)?;
+                        write!(f, ": ")?;
+                    } else {
+                        write!(f, "{}: ", k)?;
+                    }
+                    v.fmt_nested(f, quote, visiting)?;
+                }
+                visiting.pop();
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_quoted(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{{{:04x}}}", c as u32)?,
+            c => write!(f, "{}", c)?,
         }
     }
+    write!(f, "\"")
 }
 
 impl ops::Add for &Value {
@@ -61,7 +270,6 @@ impl ops::Add for &Value {
 
     fn add(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 + n2)),
             (Value::Str(s1), Value::Str(s2)) => {
                 let res = Rc::from(intern::str(*s1) + intern::str(*s2).as_str());
                 Ok(Value::String(res))
@@ -78,9 +286,18 @@ impl ops::Add for &Value {
                 let res = Rc::from(s1.as_ref().to_string() + s2.as_ref());
                 Ok(Value::String(res))
             }
-            _ => Err(RuntimeError(
-                "Operands must be two numbers or two strings".to_string(),
-            )),
+            (Value::List(l1), Value::List(l2)) => {
+                let mut res = l1.borrow().clone();
+                res.extend(l2.borrow().iter().cloned());
+                Ok(Value::List(Rc::new(RefCell::new(res))))
+            }
+            _ => self
+                .arithmetic(rhs, i64::checked_add, |n1, n2| n1 + n2)
+                .map_err(|_| {
+                    RuntimeError::TypeMismatch(
+                        "Operands must be two numbers or two strings".to_string(),
+                    )
+                }),
         }
     }
 }
@@ -89,10 +306,7 @@ impl ops::Sub for &Value {
     type Output = Result<Value, RuntimeError>;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 - n2)),
-            _ => Err(RuntimeError("Operands must be numbers".to_string())),
-        }
+        self.arithmetic(rhs, i64::checked_sub, |n1, n2| n1 - n2)
     }
 }
 
@@ -100,10 +314,7 @@ impl ops::Mul for &Value {
     type Output = Result<Value, RuntimeError>;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 * n2)),
-            _ => Err(RuntimeError("Operands must be numbers".to_string())),
-        }
+        self.arithmetic(rhs, i64::checked_mul, |n1, n2| n1 * n2)
     }
 }
 
@@ -111,22 +322,82 @@ impl ops::Div for &Value {
     type Output = Result<Value, RuntimeError>;
 
     fn div(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 / n2)),
-            _ => Err(RuntimeError("Operands must be numbers".to_string())),
+        // dividing integers always gives a float so that `1 / 2` is `0.5`
+        match (self.as_f64(), rhs.as_f64()) {
+            (Some(n1), Some(n2)) => Ok(Value::Number(n1 / n2)),
+            _ => Err(RuntimeError::TypeMismatch(
+                "Operands must be numbers".to_string(),
+            )),
         }
     }
 }
 
+impl ops::Rem for &Value {
+    type Output = Result<Value, RuntimeError>;
+
+    fn rem(self, rhs: Self) -> Self::Output {
+        self.arithmetic(rhs, i64::checked_rem, |n1, n2| n1 % n2)
+    }
+}
+
+impl ops::BitAnd for &Value {
+    type Output = Result<Value, RuntimeError>;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        let (n1, n2) = self.int_operands(rhs)?;
+        Ok(Value::Int(n1 & n2))
+    }
+}
+
+impl ops::BitOr for &Value {
+    type Output = Result<Value, RuntimeError>;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        let (n1, n2) = self.int_operands(rhs)?;
+        Ok(Value::Int(n1 | n2))
+    }
+}
+
+impl ops::BitXor for &Value {
+    type Output = Result<Value, RuntimeError>;
+
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        let (n1, n2) = self.int_operands(rhs)?;
+        Ok(Value::Int(n1 ^ n2))
+    }
+}
+
+impl ops::Shl for &Value {
+    type Output = Result<Value, RuntimeError>;
+
+    fn shl(self, rhs: Self) -> Self::Output {
+        let (n1, n2) = self.int_operands(rhs)?;
+        u32::try_from(n2)
+            .ok()
+            .and_then(|n2| n1.checked_shl(n2))
+            .map(Value::Int)
+            .ok_or_else(|| RuntimeError::Message("Shift amount out of range".to_string()))
+    }
+}
+
+impl ops::Shr for &Value {
+    type Output = Result<Value, RuntimeError>;
+
+    fn shr(self, rhs: Self) -> Self::Output {
+        let (n1, n2) = self.int_operands(rhs)?;
+        u32::try_from(n2)
+            .ok()
+            .and_then(|n2| n1.checked_shr(n2))
+            .map(Value::Int)
+            .ok_or_else(|| RuntimeError::Message("Shift amount out of range".to_string()))
+    }
+}
+
 impl ops::Not for &Value {
     type Output = Value;
 
     fn not(self) -> Self::Output {
-        Value::Bool(match self {
-            Value::Bool(b) => !b,
-            Value::Nil => true,
-            _ => false,
-        })
+        Value::Bool(self.is_falsey())
     }
 }
 
@@ -136,17 +407,39 @@ impl ops::Neg for &Value {
     fn neg(self) -> Self::Output {
         match self {
             Value::Number(n) => Ok(Value::Number(-n)),
-            _ => Err(RuntimeError("Operand must be a number".to_string())),
+            // integers have no negative zero
+            Value::Int(0) => Ok(Value::Number(-0.0)),
+            Value::Int(n) => Ok(n
+                .checked_neg()
+                .map(Value::Int)
+                .unwrap_or(Value::Number(-(*n as f64)))),
+            _ => Err(RuntimeError::TypeMismatch(
+                "Operand must be a number".to_string(),
+            )),
         }
     }
 }
 
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
+        self.eq_nested(other, &mut Vec::new())
+    }
+}
+
+impl Value {
+    // Lists, maps, and instances are compared by their content. They can contain themselves, so
+    // we keep track of the pairs that are being compared and assume that they are equal if we
+    // come across them again, any difference is found while comparing the rest of the pair.
+    fn eq_nested(&self, other: &Self, visiting: &mut Vec<(*const (), *const ())>) -> bool {
         match (self, other) {
             (Self::Nil, Self::Nil) => true,
             (Self::Bool(v1), Self::Bool(v2)) => v1 == v2,
-            (Self::Number(v1), Self::Number(v2)) => (v1 - v2).abs() < f64::EPSILON,
+            // infinities are only equal to themselves, their difference isn't a number
+            (Self::Number(v1), Self::Number(v2)) => v1 == v2 || (v1 - v2).abs() < f64::EPSILON,
+            (Self::Int(v1), Self::Int(v2)) => v1 == v2,
+            (Self::Int(v1), Self::Number(v2)) | (Self::Number(v2), Self::Int(v1)) => {
+                *v1 as f64 == *v2
+            }
             (Self::Str(s1), Self::Str(s2)) => s1 == s2,
             (Self::String(s1), Self::Str(s2)) => s1.as_ref() == intern::str(*s2),
             (Self::Str(s1), Self::String(s2)) => intern::str(*s1) == s2.as_ref(),
@@ -155,32 +448,117 @@ impl PartialEq for Value {
             (Self::Closure(c1), Self::Closure(c2)) => Rc::ptr_eq(c1, c2),
             (Self::Fun(f1), Self::Fun(f2)) => Rc::ptr_eq(f1, f2),
             (Self::Class(c1), Self::Class(c2)) => Rc::ptr_eq(c1, c2),
+            (Self::BoundMethod(b1), Self::BoundMethod(b2)) => Rc::ptr_eq(b1, b2),
             (Self::Instance(i1), Self::Instance(i2)) => {
+                if Rc::ptr_eq(i1, i2) {
+                    return true;
+                }
+                let pair = (Rc::as_ptr(i1) as *const (), Rc::as_ptr(i2) as *const ());
+                if visiting.contains(&pair) {
+                    return true;
+                }
                 let i1 = i1.borrow();
                 let i2 = i2.borrow();
-                if !Rc::ptr_eq(&i1.class, &i2.class) {
+                if !Rc::ptr_eq(&i1.class, &i2.class) || i1.fields.len() != i2.fields.len() {
                     return false;
                 }
-                if i1.fields.len() != i2.fields.len() {
+                visiting.push(pair);
+                let equal = i1.fields.iter().all(|(k, v1)| {
+                    i2.fields
+                        .get(k)
+                        .is_some_and(|v2| v1.eq_nested(v2, visiting))
+                });
+                visiting.pop();
+                equal
+            }
+            (Self::List(l1), Self::List(l2)) => {
+                if Rc::ptr_eq(l1, l2) {
+                    return true;
+                }
+                let pair = (Rc::as_ptr(l1) as *const (), Rc::as_ptr(l2) as *const ());
+                if visiting.contains(&pair) {
+                    return true;
+                }
+                let l1 = l1.borrow();
+                let l2 = l2.borrow();
+                if l1.len() != l2.len() {
                     return false;
                 }
-                for k in i1.fields.keys() {
-                    if !i2.fields.contains_key(k) {
-                        return false;
-                    }
-                    if i1.fields[k] != i2.fields[k] {
-                        return false;
-                    }
+                visiting.push(pair);
+                let equal = l1
+                    .iter()
+                    .zip(l2.iter())
+                    .all(|(v1, v2)| v1.eq_nested(v2, visiting));
+                visiting.pop();
+                equal
+            }
+            (Self::Map(m1), Self::Map(m2)) => {
+                if Rc::ptr_eq(m1, m2) {
+                    return true;
                 }
-                true
+                let pair = (Rc::as_ptr(m1) as *const (), Rc::as_ptr(m2) as *const ());
+                if visiting.contains(&pair) {
+                    return true;
+                }
+                let m1 = m1.borrow();
+                let m2 = m2.borrow();
+                if m1.len() != m2.len() {
+                    return false;
+                }
+                visiting.push(pair);
+                let equal = m1
+                    .iter()
+                    .all(|(k, v1)| m2.get(k).is_some_and(|v2| v1.eq_nested(v2, visiting)));
+                visiting.pop();
+                equal
             }
-            (Self::BoundMethod(b1), Self::BoundMethod(b2)) => Rc::ptr_eq(b1, b2),
             _ => false,
         }
     }
 }
 
 impl Value {
+    /// Get the name of the value's type as seen by Lox programs
+    ///
+    /// ```
+    /// use rlox::Value;
+    ///
+    /// assert_eq!(Value::Int(1).type_name(), "number");
+    /// assert_eq!(Value::String("x".into()).type_name(), "string");
+    ///
+    /// let src = r#"print type(1); print type("x");"#;
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "number\nstring\n");
+    /// ```
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Nil => "nil",
+            Self::Bool(_) => "bool",
+            Self::Number(_) | Self::Int(_) => "number",
+            Self::Str(_) | Self::String(_) => "string",
+            Self::NativeFun(_) | Self::Closure(_) | Self::Fun(_) | Self::BoundMethod(_) => {
+                "function"
+            }
+            Self::Class(_) => "class",
+            Self::Instance(_) => "instance",
+            Self::List(_) => "list",
+            Self::Map(_) => "map",
+        }
+    }
+
+    /// Return true if the value is `nil` or `false`, every other value is truthy
+    ///
+    /// ```
+    /// use rlox::Value;
+    ///
+    /// assert!(Value::Nil.is_falsey());
+    /// assert!(Value::Bool(false).is_falsey());
+    /// assert!(!Value::Bool(true).is_falsey());
+    /// assert!(!Value::Int(0).is_falsey());
+    /// ```
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
     /// Return true if the value is holding a closure object
     pub fn is_closure(&self) -> bool {
         matches!(self, Value::Closure(_))
@@ -250,19 +628,142 @@ impl Value {
         }
     }
 
-    /// Check if the current value is less than the given value
+    /// Divide the current value by the given value and round the result down. Dividing two
+    /// integers gives an integer, unlike `/` which always gives a floating-point number.
+    ///
+    /// ```
+    /// use rlox::Value;
+    ///
+    /// let res = Value::Int(-7).floor_div(&Value::Int(2)).unwrap();
+    /// assert!(matches!(res, Value::Int(-4)));
+    /// let res = Value::Number(7.5).floor_div(&Value::Int(2)).unwrap();
+    /// assert!(matches!(res, Value::Number(n) if n == 3.0));
+    /// assert!(Value::Nil.floor_div(&Value::Int(2)).is_err());
+    /// ```
+    pub fn floor_div(&self, rhs: &Value) -> Result<Value, RuntimeError> {
+        self.arithmetic(
+            rhs,
+            |n1, n2| {
+                let q = n1.checked_div(n2)?;
+                // integer division rounds toward zero, which is up for negative results
+                if n1 % n2 != 0 && (n1 < 0) != (n2 < 0) {
+                    Some(q - 1)
+                } else {
+                    Some(q)
+                }
+            },
+            |n1, n2| (n1 / n2).floor(),
+        )
+    }
+
+    /// Check if the current value is less than the given value. Strings are ordered
+    /// lexicographically.
+    ///
+    /// ```
+    /// use rlox::{RuntimeError, Value};
+    ///
+    /// let apple = Value::String("apple".into());
+    /// let banana = Value::String("banana".into());
+    /// assert_eq!(apple.lt(&banana).unwrap(), Value::Bool(true));
+    /// assert_eq!(banana.lt(&apple).unwrap(), Value::Bool(false));
+    ///
+    /// let err = apple.lt(&Value::Number(1.0)).unwrap_err();
+    /// assert!(matches!(err, RuntimeError::TypeMismatch(_)));
+    /// ```
     pub fn lt(&self, rhs: &Value) -> Result<Value, RuntimeError> {
         match (self, rhs) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Bool(n1 < n2)),
-            _ => Err(RuntimeError("Operands must be numbers".to_string())),
+            (Value::Int(n1), Value::Int(n2)) => Ok(Value::Bool(n1 < n2)),
+            _ if self.is_numeric() || rhs.is_numeric() => {
+                let (n1, n2) = self.numeric_operands(rhs)?;
+                Ok(Value::Bool(n1 < n2))
+            }
+            _ => Ok(Value::Bool(self.cmp_strings(rhs)? == Ordering::Less)),
         }
     }
 
-    /// Check if the current value is greater than the given value
+    /// Check if the current value is greater than the given value. Strings are ordered
+    /// lexicographically.
     pub fn gt(&self, rhs: &Value) -> Result<Value, RuntimeError> {
         match (self, rhs) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Bool(n1 > n2)),
-            _ => Err(RuntimeError("Operands must be numbers".to_string())),
+            (Value::Int(n1), Value::Int(n2)) => Ok(Value::Bool(n1 > n2)),
+            _ if self.is_numeric() || rhs.is_numeric() => {
+                let (n1, n2) = self.numeric_operands(rhs)?;
+                Ok(Value::Bool(n1 > n2))
+            }
+            _ => Ok(Value::Bool(self.cmp_strings(rhs)? == Ordering::Greater)),
+        }
+    }
+
+    /// Return true if the value is holding an integer or a floating-point number
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, Self::Number(_) | Self::Int(_))
+    }
+
+    /// Get the value as a floating-point number, integers are converted
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Number(n) => Some(*n),
+            Self::Int(n) => Some(*n as f64),
+            _ => None,
+        }
+    }
+
+    /// Flip the bits of an integer value
+    pub fn bit_not(&self) -> Result<Value, RuntimeError> {
+        match self {
+            Value::Int(n) => Ok(Value::Int(!n)),
+            _ => Err(RuntimeError::TypeMismatch(
+                "Operand must be an integer".to_string(),
+            )),
+        }
+    }
+
+    fn int_operands(&self, rhs: &Value) -> Result<(i64, i64), RuntimeError> {
+        match (self, rhs) {
+            (Value::Int(n1), Value::Int(n2)) => Ok((*n1, *n2)),
+            _ => Err(RuntimeError::TypeMismatch(
+                "Operands must be integers".to_string(),
+            )),
+        }
+    }
+
+    fn numeric_operands(&self, rhs: &Value) -> Result<(f64, f64), RuntimeError> {
+        match (self.as_f64(), rhs.as_f64()) {
+            (Some(n1), Some(n2)) => Ok((n1, n2)),
+            _ => Err(RuntimeError::TypeMismatch(
+                "Operands must be numbers".to_string(),
+            )),
+        }
+    }
+
+    /// Apply an arithmetic operation on two numeric values. Integers stay integers unless the
+    /// operation overflows, mixing an integer with a float promotes the integer to a float.
+    fn arithmetic(
+        &self,
+        rhs: &Value,
+        int_op: fn(i64, i64) -> Option<i64>,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Result<Value, RuntimeError> {
+        if let (Value::Int(n1), Value::Int(n2)) = (self, rhs) {
+            if let Some(n) = int_op(*n1, *n2) {
+                return Ok(Value::Int(n));
+            }
+        }
+        let (n1, n2) = self.numeric_operands(rhs)?;
+        Ok(Value::Number(float_op(n1, n2)))
+    }
+
+    fn cmp_strings(&self, rhs: &Value) -> Result<Ordering, RuntimeError> {
+        let text = |v: &Value| match v {
+            Value::Str(s) => Some(intern::str(*s)),
+            Value::String(s) => Some(s.to_string()),
+            _ => None,
+        };
+        match (text(self), text(rhs)) {
+            (Some(s1), Some(s2)) => Ok(s1.cmp(&s2)),
+            _ => Err(RuntimeError::TypeMismatch(
+                "Operands must be numbers".to_string(),
+            )),
         }
     }
 }
@@ -275,9 +776,13 @@ pub struct NativeFun {
     /// Number of parameters
     pub arity: u8,
     /// Native function reference
-    pub call: fn(&[Value]) -> Value,
+    pub call: NativeFunCall,
 }
 
+/// The signature of the Rust function that implements a native function. It gets access to the
+/// virtual machine that calls it and the arguments that are given.
+pub type NativeFunCall = fn(&mut VM, &[Value]) -> Result<Value, RuntimeError>;
+
 impl fmt::Display for NativeFun {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         write!(f, "<native fn>")
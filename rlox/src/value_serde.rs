@@ -0,0 +1,164 @@
+//! Serialization support for Lox values.
+//!
+//! Strings are serialized as their text, lists as sequences, and maps as maps. Objects that
+//! can't be represented as data, such as functions, classes, and instances, are serialized as
+//! a map with a single entry from their type name to their printed form, e.g. `{"function":
+//! "<fn add>"}`. Deserializing such placeholder gives back a map value.
+//!
+//! ```
+//! use rlox::Value;
+//!
+//! let json = serde_json::to_string(&Value::Number(1.5)).unwrap();
+//! assert_eq!(json, "1.5");
+//! let value: Value = serde_json::from_str(&json).unwrap();
+//! assert!(matches!(value, Value::Number(n) if n == 1.5));
+//!
+//! let value: Value = serde_json::from_str("[null, true, 2]").unwrap();
+//! assert_eq!(value.to_string(), "[nil, true, 2]");
+//! ```
+
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    ser::{self, SerializeMap, SerializeSeq},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::{intern, MapEntries, Value};
+
+thread_local! {
+    // Lists and maps that are currently being serialized, used for detecting cycles
+    static VISITING: RefCell<Vec<*const ()>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Nil => serializer.serialize_unit(),
+            Self::Bool(b) => serializer.serialize_bool(*b),
+            Self::Number(n) => serializer.serialize_f64(*n),
+            Self::Int(n) => serializer.serialize_i64(*n),
+            Self::Str(s) => serializer.serialize_str(&intern::str(*s)),
+            Self::String(s) => serializer.serialize_str(s),
+            Self::List(l) => {
+                let _guard = VisitGuard::enter::<S::Error>(Rc::as_ptr(l) as *const ())?;
+                let l = l.borrow();
+                let mut seq = serializer.serialize_seq(Some(l.len()))?;
+                for v in l.iter() {
+                    seq.serialize_element(v)?;
+                }
+                seq.end()
+            }
+            Self::Map(m) => {
+                let _guard = VisitGuard::enter::<S::Error>(Rc::as_ptr(m) as *const ())?;
+                let m = m.borrow();
+                let mut map = serializer.serialize_map(Some(m.len()))?;
+                for (k, v) in m.iter() {
+                    map.serialize_entry(&**k, v)?;
+                }
+                map.end()
+            }
+            Self::NativeFun(_)
+            | Self::Closure(_)
+            | Self::Fun(_)
+            | Self::Class(_)
+            | Self::Instance(_)
+            | Self::BoundMethod(_) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(self.type_name(), &self.to_string())?;
+                map.end()
+            }
+        }
+    }
+}
+
+/// Marks a list or map as being serialized until it gets dropped
+struct VisitGuard;
+
+impl VisitGuard {
+    fn enter<E: ser::Error>(ptr: *const ()) -> Result<Self, E> {
+        VISITING.with(|visiting| {
+            let mut visiting = visiting.borrow_mut();
+            if visiting.contains(&ptr) {
+                return Err(E::custom("can't serialize a value that contains itself"));
+            }
+            visiting.push(ptr);
+            Ok(Self)
+        })
+    }
+}
+
+impl Drop for VisitGuard {
+    fn drop(&mut self) {
+        VISITING.with(|visiting| visiting.borrow_mut().pop());
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a Lox value")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Deserialize::deserialize(deserializer)
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::Int(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+        if v > i64::MAX as u64 {
+            Ok(Value::Number(v as f64))
+        } else {
+            Ok(Value::Int(v as i64))
+        }
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+        Ok(Value::Number(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::String(Rc::from(v)))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut list = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(v) = seq.next_element()? {
+            list.push(v);
+        }
+        Ok(Value::List(Rc::new(RefCell::new(list))))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Value, A::Error> {
+        let mut map = MapEntries::default();
+        while let Some((k, v)) = access.next_entry::<String, Value>()? {
+            map.insert(Rc::from(k), v);
+        }
+        Ok(Value::Map(Rc::new(RefCell::new(map))))
+    }
+}
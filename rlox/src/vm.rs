@@ -1,11 +1,21 @@
-use std::ops::{Add, Div, Mul, Neg, Not, Sub};
-use std::{cell::RefCell, rc::Rc};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Write};
+use std::ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Neg, Not, Rem, Shl, Shr, Sub};
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    fmt,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 use rustc_hash::FxHashMap;
 
 use crate::{
-    intern, Compiler, Error, NativeFun, ObjBoundMethod, ObjClass, ObjClosure, ObjInstance,
-    ObjUpvalue, RuntimeError, StrId, Upvalue, Value, MAX_FRAMES, MAX_STACK,
+    heap::{Heap, Marker},
+    intern, parse_number_literal, Chunk, Compiler, Error, MapEntries, NativeFun, NativeFunCall,
+    ObjBoundMethod, ObjClass, ObjClosure, ObjFun, ObjInstance, ObjUpvalue, Position, RuntimeError,
+    StrId, Upvalue, Value, MAX_FRAMES, MAX_STACK,
 };
 
 #[cfg(debug_assertions)]
@@ -21,6 +31,24 @@ fn print_stack(stack: &[Value]) {
     println!();
 }
 
+/// Function that gets called before each instruction with the chunk being run, the index of
+/// the instruction, and the stack
+pub type TraceHook = Box<dyn FnMut(&Chunk, usize, &[Value])>;
+
+// Debug builds print the stack and the instruction to the standard output
+#[cfg(debug_assertions)]
+fn default_trace_hook() -> Option<TraceHook> {
+    Some(Box::new(|chunk, ip, stack| {
+        print_stack(stack);
+        disassemble_instruction(chunk, ip);
+    }))
+}
+
+#[cfg(not(debug_assertions))]
+fn default_trace_hook() -> Option<TraceHook> {
+    None
+}
+
 /// OpCode is a number that specifies the type of the instruction.
 ///
 /// # Notes
@@ -42,6 +70,26 @@ fn print_stack(stack: &[Value]) {
 pub enum OpCode {
     /// Load a constant
     Constant(u8),
+    /// Load an integer that is small enough to be stored in the instruction, the compiler
+    /// emits it for such integer literals instead of adding them to the constant table
+    ///
+    /// ```
+    /// use rlox::{Compiler, OpCode};
+    ///
+    /// let mut compiler = Compiler::new("print 1; print -127; print 128;");
+    /// compiler.compile();
+    /// let fun = compiler.finish().unwrap();
+    /// assert!(matches!(fun.chunk.read_instruction(0).0, OpCode::SmallInt(1)));
+    /// assert!(matches!(fun.chunk.read_instruction(2).0, OpCode::SmallInt(-127)));
+    /// assert!(matches!(fun.chunk.read_instruction(4).0, OpCode::Constant(0)));
+    /// assert_eq!(fun.chunk.const_count(), 1);
+    ///
+    /// assert_eq!(
+    ///     rlox::interpret_to_string("print 0 + 1; print -(-5); print 127 + 1;").unwrap(),
+    ///     "1\n5\n128\n"
+    /// );
+    /// ```
+    SmallInt(i8),
     /// Load a `nil` value
     Nil,
     /// Load a `true` value
@@ -50,25 +98,235 @@ pub enum OpCode {
     False,
     /// Pop the top of the stack
     Pop,
+    /// Push a copy of the top of the stack
+    ///
+    /// ```
+    /// use rlox::{ObjFun, OpCode, Position, SharedBuffer, Value, VM};
+    ///
+    /// let mut fun = ObjFun::new(rlox::id(""));
+    /// let seven = fun.chunk.write_const(Value::Int(7)) as u8;
+    /// for op in vec![
+    ///     OpCode::Constant(seven),
+    ///     OpCode::Dup,
+    ///     OpCode::Equal,
+    ///     OpCode::Print,
+    ///     OpCode::Nil,
+    ///     OpCode::Return,
+    /// ] {
+    ///     fun.chunk.write_instruction(op, Position::default());
+    /// }
+    ///
+    /// let buf = SharedBuffer::default();
+    /// let mut vm = VM::default().with_output(buf.clone());
+    /// vm.interpret_fun(fun).unwrap();
+    /// assert_eq!(buf.contents(), "true\n");
+    /// ```
+    Dup,
+    /// Exchange the two values at the top of the stack
+    ///
+    /// ```
+    /// use std::io;
+    ///
+    /// use rlox::{Error, ObjFun, OpCode, Position, SharedBuffer, Value, VM};
+    ///
+    /// fn script(ops: Vec<OpCode>) -> ObjFun {
+    ///     let mut fun = ObjFun::new(rlox::id(""));
+    ///     fun.chunk.write_const(Value::Int(1));
+    ///     fun.chunk.write_const(Value::Int(2));
+    ///     for op in ops {
+    ///         fun.chunk.write_instruction(op, Position::default());
+    ///     }
+    ///     fun
+    /// }
+    ///
+    /// let buf = SharedBuffer::default();
+    /// let mut vm = VM::default()
+    ///     .with_output(buf.clone())
+    ///     .with_error_output(io::sink());
+    /// let fun = script(vec![
+    ///     OpCode::Constant(0),
+    ///     OpCode::Constant(1),
+    ///     OpCode::Swap,
+    ///     OpCode::Subtract,
+    ///     OpCode::Print,
+    ///     OpCode::Nil,
+    ///     OpCode::Return,
+    /// ]);
+    /// vm.interpret_fun(fun).unwrap();
+    /// assert_eq!(buf.contents(), "1\n");
+    ///
+    /// let fun = script(vec![OpCode::Constant(0), OpCode::Swap, OpCode::Nil, OpCode::Return]);
+    /// assert!(matches!(vm.interpret_fun(fun), Err(Error::Runtime)));
+    /// ```
+    Swap,
     /// Set the value of a global variable
     GetLocal(u8),
     /// Set the value of a local variable
     SetLocal(u8),
+    /// Add one to a local variable without pushing anything. A `for` loop whose increment
+    /// clause is `i = i + 1`, with `i` being a local variable, uses this instruction for it.
+    ///
+    /// ```
+    /// use rlox::{Compiler, OpCode};
+    ///
+    /// let counting = "var s = 0; for (var i = 0; i < 10; i = i + 1) s = s + i; print s;";
+    /// let mut compiler = Compiler::new(counting);
+    /// compiler.compile();
+    /// let chunk = compiler.finish().unwrap().chunk;
+    /// let ops: Vec<_> = (0..chunk.instructions_count())
+    ///     .map(|i| chunk.read_instruction(i).0.name())
+    ///     .collect();
+    /// assert!(ops.contains(&"IncLocal"));
+    /// assert!(ops.contains(&"LessLocalConst"));
+    /// assert!(!ops.contains(&"Less"));
+    ///
+    /// // the generic instructions give the same result
+    /// let generic = "var s = 0; for (var i = 0; 10 > i; i = 1 + i) s = s + i; print s;";
+    /// let mut compiler = Compiler::new(generic);
+    /// compiler.compile();
+    /// let chunk = compiler.finish().unwrap().chunk;
+    /// assert!((0..chunk.instructions_count())
+    ///     .all(|i| !matches!(chunk.read_instruction(i).0, OpCode::IncLocal(_))));
+    /// assert_eq!(
+    ///     rlox::interpret_to_string(counting).unwrap(),
+    ///     rlox::interpret_to_string(generic).unwrap(),
+    /// );
+    ///
+    /// let src = "for (var i = 0.5; i < 3; i = i + 1) print i;";
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "0.5\n1.5\n2.5\n");
+    /// let src = r#"for (var i = ""; i < 1; i = i + 1) {}"#;
+    /// let err = rlox::interpret_to_string(src).unwrap_err();
+    /// assert!(err.starts_with("Operands must be numbers."));
+    /// ```
+    IncLocal(u8),
+    /// Compare if a local variable is less than a number constant. A `for` loop whose condition
+    /// is `i < n`, with `i` being a local variable and `n` a number literal, uses this
+    /// instruction for it.
+    ///
+    /// ```
+    /// // the loop still compiles when the bound doesn't fit in the constant table
+    /// let mut src: String = (0..256).map(|i| format!("{}.5;", i)).collect();
+    /// src.push_str("for (var i = 0; i < 3; i = i + 1) print i;");
+    /// assert_eq!(rlox::interpret_to_string(&src).unwrap(), "0\n1\n2\n");
+    /// ```
+    LessLocalConst(u8, u8),
     /// Get the value of a global variable
     GetGlobal(u8),
     /// Pop the top of the stack and define a variable initialized with that value.
     DefineGlobal(u8),
     /// Set the value of a global variable
     SetGlobal(u8),
+    /// Get the value of a global variable through the slot that was assigned to it when it was
+    /// declared
+    ///
+    /// ```
+    /// use rlox::{SharedBuffer, VM};
+    ///
+    /// let src = r#"
+    ///     var step = 3;
+    ///     var total = 0;
+    ///     for (var i = 0; i < 100; i = i + 1) total = total + step;
+    ///     print total;
+    /// "#;
+    /// let buf = SharedBuffer::default();
+    /// let mut vm = VM::default().with_output(buf.clone());
+    /// vm.set_profile(true);
+    /// vm.set_resolve_global_slots(true);
+    /// vm.interpret(src).unwrap();
+    /// assert_eq!(buf.contents(), "300\n");
+    /// assert_eq!(vm.opcode_stats()["GetGlobalSlot"], 201);
+    /// assert!(!vm.opcode_stats().contains_key("GetGlobal"));
+    ///
+    /// // globals are looked up by name unless slots are enabled
+    /// let mut vm = VM::default().with_output(SharedBuffer::default());
+    /// vm.set_profile(true);
+    /// vm.interpret(src).unwrap();
+    /// assert!(!vm.opcode_stats().contains_key("GetGlobalSlot"));
+    /// ```
+    GetGlobalSlot(u8),
     /// Get a variable through its upvalue
     GetUpvalue(u8),
-    /// Set a variable through its upvalue
+    /// Set a variable through its upvalue. Closures that capture the same variable share its
+    /// upvalue, so they all see the change, whether the variable is still on the stack or not.
+    ///
+    /// ```
+    /// let src = r#"
+    ///     var inc;
+    ///     var get;
+    ///     fun counter() {
+    ///         var count = 0;
+    ///         fun i() { count = count + 1; }
+    ///         fun g() { return count; }
+    ///         inc = i;
+    ///         get = g;
+    ///         inc();
+    ///         print get();
+    ///         print count;
+    ///     }
+    ///     counter();
+    ///     inc();
+    ///     inc();
+    ///     print get();
+    /// "#;
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "1\n1\n3\n");
+    /// ```
     SetUpvalue(u8),
-    /// Get the value of a property on the class instance
+    /// Get the value of a property on the class instance. A field shadows the method of the same
+    /// name, otherwise the method is bound to the instance, or the getter is called.
+    ///
+    /// ```
+    /// let src = r#"
+    ///     class A {
+    ///         init() { this.name = "a"; }
+    ///         m() { return "method"; }
+    ///         name() { return "shadowed"; }
+    ///         size { return 2; }
+    ///     }
+    ///     var a = A();
+    ///     print a.name;
+    ///     var m = a.m;
+    ///     print m();
+    ///     print a.size + 1;
+    ///     print [a.size, a.size];
+    ///     a.m = "field";
+    ///     print a.m;
+    /// "#;
+    /// assert_eq!(
+    ///     rlox::interpret_to_string(src).unwrap(),
+    ///     "a\nmethod\n3\n[2, 2]\nfield\n"
+    /// );
+    ///
+    /// let err = rlox::interpret_to_string("class A {} print A().m;").unwrap_err();
+    /// assert!(err.starts_with("Undefined property 'm'."));
+    ///
+    /// // getters of the instance and of its super class resume the caller where it left off
+    /// let src = r#"
+    ///     class A { size { return 2; } }
+    ///     class B < A { size { return super.size * 10; } }
+    ///     fun total(b) { return b.size + b.size; }
+    ///     print total(B());
+    /// "#;
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "40\n");
+    /// ```
     GetProperty(u8),
     /// Set the value of a property on the class instance
     SetProperty(u8),
-    /// Get the super class instance of the current class
+    /// Get a method of the super class bound to the current instance, or call the getter of the
+    /// super class.
+    ///
+    /// ```
+    /// let src = r#"
+    ///     class R {
+    ///         init(w, h) { this.w = w; this.h = h; }
+    ///         area { return this.w * this.h; }
+    ///     }
+    ///     class S < R {
+    ///         area { return super.area + 1; }
+    ///     }
+    ///     print S(2, 3).area;
+    /// "#;
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "7\n");
+    /// ```
     GetSuper(u8),
     /// Check for equality between 2 operands.
     Equal,
@@ -76,7 +334,32 @@ pub enum OpCode {
     Greater,
     /// Compare if the first operand is less than the second
     Less,
-    /// Add two number operands or two string operands
+    /// Add two number operands or two string operands, or concatenate two list operands into a
+    /// new list
+    ///
+    /// ```
+    /// let src = "var a = [1, 2]; var b = [3]; var c = a + b; print c; print a; print b;";
+    /// assert_eq!(
+    ///     rlox::interpret_to_string(src).unwrap(),
+    ///     "[1, 2, 3]\n[1, 2]\n[3]\n"
+    /// );
+    ///
+    /// let err = rlox::interpret_to_string("print [1] + 2;").unwrap_err();
+    /// assert!(err.starts_with("Operands must be two numbers or two strings."));
+    /// let err = rlox::interpret_to_string(r#"print "a" + [1];"#).unwrap_err();
+    /// assert!(err.starts_with("Operands must be two numbers or two strings."));
+    ///
+    /// // string literals are joined at compile time
+    /// let mut compiler = rlox::Compiler::new(r#"print "foo" + "bar" + "!";"#);
+    /// compiler.compile();
+    /// let chunk = compiler.finish().unwrap().chunk;
+    /// let (op, _) = chunk.read_instruction(0);
+    /// assert!(matches!(op, rlox::OpCode::Constant(_)));
+    /// assert_eq!(chunk.read_instruction(1).0.name(), "Print");
+    /// if let rlox::OpCode::Constant(const_id) = op {
+    ///     assert_eq!(chunk.read_const(*const_id as usize).to_string(), "foobar!");
+    /// }
+    /// ```
     Add,
     /// Subtract two number operands
     Subtract,
@@ -84,44 +367,548 @@ pub enum OpCode {
     Multiply,
     /// Divide two number operands
     Divide,
-    /// Apply logical `not` to a single boolean operand
+    /// Get the remainder of dividing two number operands
+    Modulo,
+    /// Divide two number operands and round the result down, see [`Value::floor_div`]
+    FloorDivide,
+    /// Apply logical `not` to a single boolean operand. The compiler folds it into a `true`,
+    /// `false`, or `nil` literal operand.
+    ///
+    /// ```
+    /// use rlox::{Compiler, OpCode};
+    ///
+    /// let mut compiler = Compiler::new("print !!true; print !nil;");
+    /// compiler.compile();
+    /// let fun = compiler.finish().unwrap();
+    /// assert!(matches!(fun.chunk.read_instruction(0).0, OpCode::True));
+    /// assert!(matches!(fun.chunk.read_instruction(1).0, OpCode::Print));
+    /// assert!(matches!(fun.chunk.read_instruction(2).0, OpCode::True));
+    /// assert!(matches!(fun.chunk.read_instruction(3).0, OpCode::Print));
+    ///
+    /// assert_eq!(
+    ///     rlox::interpret_to_string("var a = 1; print !!a; print !!false; print !(!a);").unwrap(),
+    ///     "true\nfalse\ntrue\n"
+    /// );
+    /// ```
     Not,
-    /// Negate a single number operand
+    /// Negate a single number operand. The compiler folds it into a number literal operand.
+    ///
+    /// ```
+    /// use rlox::{Compiler, OpCode, Value};
+    ///
+    /// let mut compiler = Compiler::new("print -500; print -5;");
+    /// compiler.compile();
+    /// let fun = compiler.finish().unwrap();
+    /// assert!(matches!(fun.chunk.read_instruction(0).0, OpCode::Constant(0)));
+    /// assert!(matches!(fun.chunk.read_const(0), Value::Int(-500)));
+    /// assert!(matches!(fun.chunk.read_instruction(1).0, OpCode::Print));
+    /// assert!(matches!(fun.chunk.read_instruction(2).0, OpCode::SmallInt(-5)));
+    ///
+    /// assert_eq!(
+    ///     rlox::interpret_to_string("var a = 2; print --5; print -(2.5); print -a;").unwrap(),
+    ///     "5\n-2.5\n-2\n"
+    /// );
+    /// assert!(rlox::interpret_to_string("print -\"a\";").is_err());
+    /// ```
     Negate,
+    /// Bitwise and of two integer operands
+    ///
+    /// ```
+    /// use rlox::{Compiler, OpCode};
+    ///
+    /// let mut compiler = Compiler::new("1 | 2 & 3;");
+    /// compiler.compile();
+    /// let script = compiler.finish().unwrap();
+    ///
+    /// // `&` binds tighter than `|`
+    /// assert!(matches!(script.chunk.read_instruction(3).0, OpCode::BitAnd));
+    /// assert!(matches!(script.chunk.read_instruction(4).0, OpCode::BitOr));
+    ///
+    /// let src = "print 6 & 3; print 6 | 3; print 6 ^ 3; print 1 << 4; print -16 >> 2; print ~5;";
+    /// assert_eq!(
+    ///     rlox::interpret_to_string(src).unwrap(),
+    ///     "2\n7\n5\n16\n-4\n-6\n"
+    /// );
+    /// let err = rlox::interpret_to_string("print 1.5 & 1;").unwrap_err();
+    /// assert!(err.starts_with("Operands must be integers."));
+    /// ```
+    BitAnd,
+    /// Bitwise or of two integer operands
+    BitOr,
+    /// Bitwise exclusive or of two integer operands
+    BitXor,
+    /// Shift the bits of an integer operand to the left
+    Shl,
+    /// Shift the bits of an integer operand to the right
+    Shr,
+    /// Flip the bits of a single integer operand
+    BitNot,
     /// Print an expression in human readable format
     Print,
-    /// Jump forward for n instructions
+    /// Print the given number of values from the top of the stack on one line, separated by
+    /// spaces. `print` uses this instruction when it's given more than one value.
+    ///
+    /// ```
+    /// use rlox::{Compiler, OpCode};
+    ///
+    /// let mut compiler = Compiler::new("print 1, \"a\", nil;");
+    /// compiler.compile();
+    /// let chunk = compiler.finish().unwrap().chunk;
+    /// assert!(matches!(chunk.read_instruction(3).0, OpCode::PrintN(3)));
+    ///
+    /// let src = "print 1, 2; print [1, 2], 3.5, true; print 1;";
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "1 2\n[1, 2] 3.5 true\n1\n");
+    /// ```
+    PrintN(u8),
+    /// Jump forward for n instructions. In an `if`/`else if`/`else` chain, each branch jumps
+    /// straight to the end of the chain and each condition jumps straight to the next one.
+    ///
+    /// ```
+    /// use rlox::{Compiler, OpCode};
+    ///
+    /// let src = "if (a) print 1; else if (b) print 2; else print 3;";
+    /// let mut compiler = Compiler::new(src);
+    /// compiler.compile();
+    /// let chunk = compiler.finish().unwrap().chunk;
+    /// let op = |idx: usize| chunk.read_instruction(idx).0;
+    /// let end = chunk.instructions_count() - 2;
+    /// assert!(matches!(op(end), OpCode::Nil));
+    ///
+    /// let mut conditional_targets = Vec::new();
+    /// for idx in 0..chunk.instructions_count() {
+    ///     match op(idx) {
+    ///         OpCode::Jump(offset) => assert_eq!(idx + 1 + *offset as usize, end),
+    ///         OpCode::JumpIfFalse(offset) => conditional_targets.push(idx + 1 + *offset as usize),
+    ///         _ => continue,
+    ///     }
+    /// }
+    /// // the first condition fails into the second one, which fails into the else branch,
+    /// // after popping the condition
+    /// assert_eq!(conditional_targets.len(), 2);
+    /// assert!(matches!(op(conditional_targets[0]), OpCode::Pop));
+    /// assert!(matches!(op(conditional_targets[0] + 1), OpCode::GetGlobal(_)));
+    /// assert!(matches!(op(conditional_targets[1]), OpCode::Pop));
+    /// assert!(matches!(op(conditional_targets[1] + 1), OpCode::SmallInt(3)));
+    /// ```
     Jump(u16),
-    /// Jump forward for n instructions if current stack top is falsey
+    /// Jump forward for n instructions if current stack top is falsey. The offset is counted
+    /// from the instruction that follows the jump.
+    ///
+    /// ```
+    /// use rlox::{Compiler, OpCode};
+    ///
+    /// let mut compiler = Compiler::new("if (true) print 1; else print 2;");
+    /// compiler.compile();
+    /// let chunk = compiler.finish().unwrap().chunk;
+    /// let target = |idx: usize| match chunk.read_instruction(idx).0 {
+    ///     OpCode::Jump(offset) | OpCode::JumpIfFalse(offset) => idx + 1 + *offset as usize,
+    ///     _ => panic!("not a jump"),
+    /// };
+    ///
+    /// // the condition jumps to the else branch, which starts by popping the condition
+    /// let else_start = target(1);
+    /// assert!(matches!(chunk.read_instruction(else_start).0, OpCode::Pop));
+    /// assert!(matches!(chunk.read_instruction(else_start + 1).0, OpCode::SmallInt(2)));
+    ///
+    /// // the then branch jumps over the else branch
+    /// let then_end = else_start - 1;
+    /// assert_eq!(target(then_end), else_start + 3);
+    /// assert!(matches!(chunk.read_instruction(else_start + 3).0, OpCode::Nil));
+    /// ```
     JumpIfFalse(u16),
+    /// Jump forward for n instructions if current stack top is nil. This is used by the `??`
+    /// operator, which only evaluates its right operand when the left one is nil.
+    ///
+    /// ```
+    /// let src = r#"
+    ///     fun f() { print "called"; return 1; }
+    ///     print nil ?? 5;
+    ///     print 3 ?? f();
+    ///     print nil ?? f();
+    /// "#;
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "5\n3\ncalled\n1\n");
+    /// ```
+    JumpIfNil(u16),
     /// Jump backward for n instructions
     Loop(u16),
-    /// Make a function call
+    /// Make a function call with the given number of arguments, which sit on the stack above
+    /// the callee. It's shown as `OP_CALL` when disassembled.
+    ///
+    /// ```
+    /// use rlox::{Compiler, OpCode};
+    ///
+    /// let mut compiler = Compiler::new("fun f(a, b) { return a + b; } f(1, 2); clock();");
+    /// compiler.compile();
+    /// let chunk = compiler.finish().unwrap().chunk;
+    /// let calls: Vec<u8> = (0..chunk.instructions_count())
+    ///     .filter_map(|idx| match chunk.read_instruction(idx).0 {
+    ///         OpCode::Call(argc) => Some(*argc),
+    ///         _ => None,
+    ///     })
+    ///     .collect();
+    /// assert_eq!(calls, vec![2, 0]);
+    /// ```
     Call(u8),
-    /// Invoke method call directly without going though an access operation
+    /// Invoke method call directly without going though an access operation. When the property
+    /// is a field instead of a method, the field's value gets called.
+    ///
+    /// ```
+    /// use rlox::{Compiler, OpCode};
+    ///
+    /// let mut compiler = Compiler::new("var obj; obj.m(1);");
+    /// compiler.compile();
+    /// let script = compiler.finish().unwrap();
+    /// let invokes = (0..script.chunk.instructions_count())
+    ///     .filter(|&idx| matches!(script.chunk.read_instruction(idx).0, OpCode::Invoke(_, 1)))
+    ///     .count();
+    /// assert_eq!(invokes, 1);
+    ///
+    /// let src = r#"
+    ///     class A { m(n) { return "method " + type(n); } }
+    ///     fun f(n) { return "field"; }
+    ///     var a = A();
+    ///     print a.m(1);
+    ///     a.m = f;
+    ///     print a.m(1);
+    /// "#;
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "method number\nfield\n");
+    /// ```
     Invoke(u8, u8),
     /// Invoke super call directly without going though an access operation
     SuperInvoke(u8, u8),
     /// Add a new closure
     Closure(u8, Vec<Upvalue>),
-    /// Move captured value to the heap
+    /// Move captured value to the heap. The compiler emits this instead of `Pop` for locals
+    /// that are captured by a closure when they go out of scope.
+    ///
+    /// ```
+    /// let src = r#"
+    ///     var get;
+    ///     {
+    ///         var x = "captured";
+    ///         fun f() { return x; }
+    ///         get = f;
+    ///     }
+    ///     var y = "overwrite the stack slot";
+    ///     print get();
+    /// "#;
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "captured\n");
+    /// ```
     CloseUpvalue,
-    /// Return from the current function
+    /// Return from the current function. Functions that end with a `return` statement don't get
+    /// the implicit `return nil;` at the end of their body.
+    ///
+    /// ```
+    /// use rlox::{Compiler, OpCode, Value};
+    ///
+    /// let src = "fun f(x) { return x; } fun g(x) { if (x) return 1; }";
+    /// let mut compiler = Compiler::new(src);
+    /// compiler.compile();
+    /// let chunk = compiler.finish().unwrap().chunk;
+    /// let funs: Vec<_> = (0..chunk.instructions_count())
+    ///     .filter_map(|idx| match chunk.read_instruction(idx).0 {
+    ///         OpCode::Closure(id, _) => match chunk.read_const(*id as usize) {
+    ///             Value::Fun(fun) => Some(fun.clone()),
+    ///             _ => None,
+    ///         },
+    ///         _ => None,
+    ///     })
+    ///     .collect();
+    /// let ops = |idx: usize| {
+    ///     let chunk = &funs[idx].chunk;
+    ///     (0..chunk.instructions_count())
+    ///         .map(|i| chunk.read_instruction(i).0.name())
+    ///         .collect::<Vec<_>>()
+    /// };
+    /// assert_eq!(ops(0), vec!["GetLocal", "Return"]);
+    /// assert!(ops(1).ends_with(&["Pop", "Nil", "Return"]));
+    /// ```
     Return,
     /// Create a class and bind it to a name
     Class(u8),
     /// Create a inheritance relation between two classes
     Inherit,
     /// Define a method
-    Method(u8),
+    Method(u8, MethodKind),
+    /// Create a list from the given number of values on top of the stack
+    BuildList(u8),
+    /// Create a map from the given number of key-value pairs on top of the stack
+    ///
+    /// ```
+    /// let src = r#"
+    ///     var m = {"a": 1, "b": [2]};
+    ///     print m["a"];
+    ///     m["c"] = 3;
+    ///     var key = "b";
+    ///     print m[key][0];
+    ///     print m["missing"];
+    ///     print m;
+    /// "#;
+    /// assert_eq!(
+    ///     rlox::interpret_to_string(src).unwrap(),
+    ///     "1\n2\nnil\n{a: 1, b: [2], c: 3}\n"
+    /// );
+    ///
+    /// // keys that are built at runtime aren't interned
+    /// let src = r#"
+    ///     var m = {};
+    ///     var key = "build_map_key";
+    ///     for (var i = 0; i < 100; i = i + 1) {
+    ///         key = key + "!";
+    ///         m[key] = i;
+    ///     }
+    ///     print m[key];
+    ///     print len(m);
+    /// "#;
+    /// let before = rlox::interned_len();
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "99\n100\n");
+    /// assert!(rlox::interned_len() < before + 100);
+    ///
+    /// // a map literal can't start a clause of a for loop
+    /// assert!(rlox::interpret_to_string("for ({}; false;) {}").is_err());
+    /// assert!(rlox::interpret_to_string("for (; {};) {}").is_err());
+    /// assert!(rlox::interpret_to_string("for (;; {}) {}").is_err());
+    /// assert!(rlox::interpret_to_string("var m; for (m = {}; false;) {}").is_ok());
+    /// ```
+    BuildMap(u8),
+    /// Get the element at an index of a list or a map. List indices must be whole numbers from
+    /// zero up to the length of the list, negative indices are out of range.
+    ///
+    /// ```
+    /// let src = "var a = [1, 2, 3]; print a[1.0]; a[2.0] = 4; print a;";
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "2\n[1, 2, 4]\n");
+    ///
+    /// let errors = [
+    ///     ("print [1, 2][1.5];", "List index must be an integer."),
+    ///     ("var a = [1]; a[0.5] = 2;", "List index must be an integer."),
+    ///     ("print [1, 2][2];", "List index out of range."),
+    ///     ("print [1, 2][-1.0];", "List index out of range."),
+    ///     ("var a = [1]; a[1] = 2;", "List index out of range."),
+    /// ];
+    /// for (src, msg) in errors.iter() {
+    ///     let err = rlox::interpret_to_string(src).unwrap_err();
+    ///     assert!(err.starts_with(msg), "{}: {}", src, err);
+    /// }
+    /// ```
+    Index,
+    /// Set the element at an index of a list or a map
+    SetIndex,
+    /// Replace the value on top of the stack with its length, this is used by `for-in` loops
+    /// so they don't depend on the `len` global that the script can redefine
+    ///
+    /// ```
+    /// let src = "var len = 1; for (x in [1, 2]) print x;";
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "1\n2\n");
+    /// ```
+    Len,
+}
+
+impl OpCode {
+    /// Return the name of the instruction without its operands
+    ///
+    /// ```
+    /// use rlox::OpCode;
+    ///
+    /// assert_eq!(OpCode::Loop(3).name(), "Loop");
+    /// assert_eq!(OpCode::Return.name(), "Return");
+    /// ```
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Constant(_) => "Constant",
+            Self::SmallInt(_) => "SmallInt",
+            Self::Nil => "Nil",
+            Self::True => "True",
+            Self::False => "False",
+            Self::Pop => "Pop",
+            Self::Dup => "Dup",
+            Self::Swap => "Swap",
+            Self::GetLocal(_) => "GetLocal",
+            Self::SetLocal(_) => "SetLocal",
+            Self::IncLocal(_) => "IncLocal",
+            Self::LessLocalConst(_, _) => "LessLocalConst",
+            Self::GetGlobal(_) => "GetGlobal",
+            Self::DefineGlobal(_) => "DefineGlobal",
+            Self::SetGlobal(_) => "SetGlobal",
+            Self::GetGlobalSlot(_) => "GetGlobalSlot",
+            Self::GetUpvalue(_) => "GetUpvalue",
+            Self::SetUpvalue(_) => "SetUpvalue",
+            Self::GetProperty(_) => "GetProperty",
+            Self::SetProperty(_) => "SetProperty",
+            Self::GetSuper(_) => "GetSuper",
+            Self::Equal => "Equal",
+            Self::Greater => "Greater",
+            Self::Less => "Less",
+            Self::Add => "Add",
+            Self::Subtract => "Subtract",
+            Self::Multiply => "Multiply",
+            Self::Divide => "Divide",
+            Self::Modulo => "Modulo",
+            Self::FloorDivide => "FloorDivide",
+            Self::Not => "Not",
+            Self::Negate => "Negate",
+            Self::BitAnd => "BitAnd",
+            Self::BitOr => "BitOr",
+            Self::BitXor => "BitXor",
+            Self::Shl => "Shl",
+            Self::Shr => "Shr",
+            Self::BitNot => "BitNot",
+            Self::Print => "Print",
+            Self::PrintN(_) => "PrintN",
+            Self::Jump(_) => "Jump",
+            Self::JumpIfFalse(_) => "JumpIfFalse",
+            Self::JumpIfNil(_) => "JumpIfNil",
+            Self::Loop(_) => "Loop",
+            Self::Call(_) => "Call",
+            Self::Invoke(_, _) => "Invoke",
+            Self::SuperInvoke(_, _) => "SuperInvoke",
+            Self::Closure(_, _) => "Closure",
+            Self::CloseUpvalue => "CloseUpvalue",
+            Self::Return => "Return",
+            Self::Class(_) => "Class",
+            Self::Inherit => "Inherit",
+            Self::Method(_, _) => "Method",
+            Self::BuildList(_) => "BuildList",
+            Self::BuildMap(_) => "BuildMap",
+            Self::Index => "Index",
+            Self::SetIndex => "SetIndex",
+            Self::Len => "Len",
+        }
+    }
+}
+
+/// The different kinds of method that can be defined on a class.
+///
+/// ```
+/// use rlox::{Compiler, MethodKind, OpCode};
+///
+/// let mut compiler = Compiler::new("class Rect { area { return 1; } scale(n) {} static unit() {} }");
+/// compiler.compile();
+/// let script = compiler.finish().unwrap();
+///
+/// let kinds: Vec<MethodKind> = (0..script.chunk.instructions_count())
+///     .filter_map(|idx| match script.chunk.read_instruction(idx).0 {
+///         OpCode::Method(_, kind) => Some(*kind),
+///         _ => None,
+///     })
+///     .collect();
+/// assert_eq!(
+///     kinds,
+///     vec![MethodKind::Getter, MethodKind::Method, MethodKind::Static]
+/// );
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MethodKind {
+    /// A method that is called with a list of arguments
+    Method,
+    /// A method that is declared without a parameters list and is called when it gets accessed.
+    /// A getter and a method can't share a name, so defining one replaces the other, including
+    /// the ones that were inherited.
+    ///
+    /// ```
+    /// let src = r#"
+    ///     class Shape { area { return 1; } scale(n) { return n; } }
+    ///     class Square < Shape { area(n) { return n * n; } scale { return 2; } }
+    ///     var s = Square();
+    ///     print s.area(3);
+    ///     print s.scale;
+    /// "#;
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "9\n2\n");
+    /// ```
+    Getter,
+    /// A method that is bound to the class object instead of its instances. Other properties
+    /// of the class object can't be accessed since it isn't an instance.
+    ///
+    /// ```
+    /// use rlox::{SharedBuffer, VM};
+    ///
+    /// let src = "class Math { static twice(n) { return n * 2; } } print Math.twice(2);";
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "4\n");
+    ///
+    /// for (src, message) in [
+    ///     ("class Math {} Math.pi;", "Only instances have properties."),
+    ///     ("class Math {} Math.pi();", "Only instances have methods."),
+    /// ]
+    /// .iter()
+    /// {
+    ///     let errors = SharedBuffer::default();
+    ///     let mut vm = VM::default().with_error_output(errors.clone());
+    ///     assert!(vm.interpret(src).is_err());
+    ///     assert!(errors.contents().starts_with(message), "{}", src);
+    /// }
+    /// ```
+    Static,
 }
 
-fn clock_native(_args: &[Value]) -> Value {
+/// How numbers are printed by the virtual machine
+///
+/// ```
+/// let src = "print 1.0; print 1; print 2.5; print [3.0];";
+/// assert_eq!(rlox::interpret_to_string(src).unwrap(), "1\n1\n2.5\n[3]\n");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumberFormat {
+    /// Floating-point numbers without a fractional part are printed like integers, which is
+    /// how Lox prints numbers. This is the default.
+    Lox,
+    /// Floating-point numbers are always printed with a fractional part, so `1.0` is printed
+    /// as `1.0` while the integer `1` is printed as `1`.
+    Float,
+}
+
+fn clock_native(vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Number((vm.clock)()))
+}
+
+fn wall_clock() -> f64 {
     let start = std::time::SystemTime::now();
     let since_epoch = start
         .duration_since(std::time::UNIX_EPOCH)
         .expect("Time went backwards");
-    Value::Number(since_epoch.as_secs_f64())
+    since_epoch.as_secs_f64()
+}
+
+fn len_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let len = match &args[0] {
+        Value::List(l) => l.borrow().len(),
+        Value::Map(m) => m.borrow().len(),
+        Value::Str(s) => intern::str(*s).chars().count(),
+        Value::String(s) => s.chars().count(),
+        _ => {
+            return Err(RuntimeError::TypeMismatch(
+                "Can only get the length of lists, maps, and strings".to_string(),
+            ))
+        }
+    };
+    Ok(Value::Int(len as i64))
+}
+
+// Runtime strings are used as keys without being interned so they can be freed with the map
+fn map_key(key: &Value) -> Result<Rc<str>, RuntimeError> {
+    match key {
+        Value::Str(s) => Ok(Rc::from(intern::str(*s))),
+        Value::String(s) => Ok(Rc::clone(s)),
+        _ => Err(RuntimeError::TypeMismatch(
+            "Map keys must be strings".to_string(),
+        )),
+    }
+}
+
+// Floating-point numbers can be used as indices as long as they are whole. Negative indices
+// are out of range rather than counting from the end.
+fn list_index(index: &Value, len: usize) -> Result<usize, RuntimeError> {
+    let n = match index {
+        Value::Int(n) => *n,
+        Value::Number(n) if n.fract() == 0.0 && n.is_finite() => *n as i64,
+        _ => {
+            return Err(RuntimeError::TypeMismatch(
+                "List index must be an integer".to_string(),
+            ))
+        }
+    };
+    match usize::try_from(n) {
+        Ok(n) if n < len => Ok(n),
+        _ => Err(RuntimeError::Message("List index out of range".to_string())),
+    }
 }
 
 #[derive(Debug)]
@@ -131,14 +918,386 @@ struct CallFrame {
     slot: usize,
 }
 
+impl CallFrame {
+    /// Return the position of the instruction that was read last
+    fn current_pos(&self) -> Position {
+        let (_, pos) = self
+            .closure
+            .fun
+            .chunk
+            .read_instruction(self.ip.saturating_sub(1));
+        *pos
+    }
+}
+
+fn substring_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let s = string_arg(&args[0])?;
+    let (start, len) = (int_arg(&args[1])?, int_arg(&args[2])?);
+    let count = s.chars().count() as i64;
+    if start < 0 || len < 0 || start > count || len > count - start {
+        return Err(RuntimeError::Message("Substring out of range".to_string()));
+    }
+    let sub: String = s.chars().skip(start as usize).take(len as usize).collect();
+    Ok(Value::String(Rc::from(sub)))
+}
+
+fn index_of_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let (s, needle) = (string_arg(&args[0])?, string_arg(&args[1])?);
+    let index = match s.find(&needle) {
+        Some(byte_idx) => s[..byte_idx].chars().count() as i64,
+        None => -1,
+    };
+    Ok(Value::Int(index))
+}
+
+fn contains_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let (s, needle) = (string_arg(&args[0])?, string_arg(&args[1])?);
+    Ok(Value::Bool(s.contains(&needle)))
+}
+
+fn num_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let text = string_arg(&args[0])?;
+    let text = text.trim();
+    // the smallest integer can't be parsed as the negation of its magnitude
+    if let (false, Ok(n)) = (text.starts_with('+'), text.parse::<i64>()) {
+        return Ok(Value::Int(n));
+    }
+    let value = match text.strip_prefix('-') {
+        Some(digits) => parse_number_literal(digits).and_then(|n| (-&n).ok()),
+        None => parse_number_literal(text),
+    };
+    Ok(value.unwrap_or(Value::Nil))
+}
+
+fn read_line_native(vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+    let mut line = String::new();
+    if vm.input.read_line(&mut line).map_err(io_error)? == 0 {
+        return Ok(Value::Nil);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(Value::String(Rc::from(line)))
+}
+
+fn dump_native(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    writeln!(vm.output, "{}", args[0].repr()).map_err(io_error)?;
+    Ok(Value::Nil)
+}
+
+fn exit_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[0] {
+        Value::Int(status) => match i32::try_from(status) {
+            Ok(status) => Err(RuntimeError::Exit(status)),
+            Err(_) => Err(RuntimeError::Message(
+                "Exit status out of range".to_string(),
+            )),
+        },
+        _ => Err(RuntimeError::TypeMismatch(
+            "Exit status must be an integer".to_string(),
+        )),
+    }
+}
+
+fn string_arg(arg: &Value) -> Result<String, RuntimeError> {
+    match arg {
+        Value::Str(s) => Ok(intern::str(*s)),
+        Value::String(s) => Ok(s.to_string()),
+        _ => Err(RuntimeError::TypeMismatch(
+            "Argument must be a string".to_string(),
+        )),
+    }
+}
+
+fn int_arg(arg: &Value) -> Result<i64, RuntimeError> {
+    match arg {
+        Value::Int(n) => Ok(*n),
+        _ => Err(RuntimeError::TypeMismatch(
+            "Argument must be an integer".to_string(),
+        )),
+    }
+}
+
+fn type_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Str(intern::id(args[0].type_name())))
+}
+
+fn is_number_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Bool(args[0].type_name() == "number"))
+}
+
+fn is_string_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Bool(args[0].type_name() == "string"))
+}
+
+fn is_nil_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Bool(args[0].type_name() == "nil"))
+}
+
+fn is_bool_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Bool(args[0].type_name() == "bool"))
+}
+
+fn is_list_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Bool(args[0].type_name() == "list"))
+}
+
+fn is_function_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Bool(args[0].type_name() == "function"))
+}
+
+fn write_native(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    match vm.number_format {
+        NumberFormat::Lox => write!(vm.output, "{}", args[0]),
+        NumberFormat::Float => write!(vm.output, "{:#}", args[0]),
+    }
+    .map_err(io_error)?;
+    vm.output.flush().map_err(io_error)?;
+    Ok(Value::Nil)
+}
+
+/// Run the source code on a new virtual machine and return everything that it prints. If the
+/// program fails, the error messages are returned instead. Nothing is written to the standard
+/// output or the standard error, even the disassembly and the trace of debug builds are turned
+/// off. The program gets its own string interner, so the strings that it creates are released
+/// once it returns and the thread-local interner is left as it was.
+///
+/// ```
+/// assert_eq!(rlox::interpret_to_string("print 1; print 2;").unwrap(), "1\n2\n");
+/// assert_eq!(
+///     rlox::interpret_to_string("print x;").unwrap_err(),
+///     "Undefined variable 'x'.\n[line 1] in script.\n"
+/// );
+///
+/// let name = rlox::id("interpret_to_string");
+/// let before = rlox::interned_len();
+/// let src = r#"var interpret_to_string_var = "a new string"; print interpret_to_string_var;"#;
+/// assert_eq!(rlox::interpret_to_string(src).unwrap(), "a new string\n");
+/// assert_eq!(rlox::interned_len(), before);
+/// assert_eq!(rlox::str(name), "interpret_to_string");
+/// ```
+pub fn interpret_to_string(src: &str) -> Result<String, String> {
+    intern::with_scoped_interner(|| {
+        let output = SharedBuffer::default();
+        let error_output = SharedBuffer::default();
+        let mut vm = VM::default()
+            .with_output(output.clone())
+            .with_error_output(error_output.clone());
+        vm.set_trace_hook(None);
+        vm.set_print_code(false);
+        match vm.interpret(src) {
+            Ok(_) | Err(Error::Exit(0)) => Ok(output.contents()),
+            Err(_) => Err(error_output.contents()),
+        }
+    })
+}
+
+/// An in-memory writer whose content can be read after giving a clone of it to the virtual
+/// machine, the clones share the same content. This is mostly useful for testing.
+///
+/// ```
+/// use rlox::{SharedBuffer, VM};
+///
+/// let output = SharedBuffer::default();
+/// let mut vm = VM::default().with_output(output.clone());
+/// vm.interpret("print 1 + 2;").unwrap();
+/// assert_eq!(output.contents(), "3\n");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    /// Get everything that was written so far, invalid UTF-8 is replaced
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn io_error(err: io::Error) -> RuntimeError {
+    RuntimeError::Message(err.to_string())
+}
+
+fn undefined_variable(name: StrId) -> RuntimeError {
+    RuntimeError::Message(format!("Undefined variable '{}'", intern::str(name)))
+}
+
+/// Counts the instructions that get executed and the time spent on each kind of instruction
+#[derive(Debug, Default)]
+struct Profile {
+    counts: HashMap<&'static str, u64>,
+    times: HashMap<&'static str, Duration>,
+    // the instruction that is being executed and when it started
+    current: Option<(&'static str, Instant)>,
+}
+
+impl Profile {
+    fn start(&mut self, name: &'static str) {
+        *self.counts.entry(name).or_default() += 1;
+        self.current = Some((name, Instant::now()));
+    }
+
+    fn stop(&mut self) {
+        if let Some((name, started)) = self.current.take() {
+            *self.times.entry(name).or_default() += started.elapsed();
+        }
+    }
+}
+
 /// A bytecode virtual machine for the Lox programming language
-#[derive(Debug)]
+///
+/// The string functions `substring(s, start, len)`, `indexOf(s, needle)`, and
+/// `contains(s, needle)` count indices in characters rather than bytes. `indexOf` returns -1
+/// when the needle can't be found.
+///
+/// ```
+/// let src = r#"
+///     print substring("héllo", 1, 3);
+///     print indexOf("héllo", "llo");
+///     print indexOf("abc", "z");
+///     print contains("abc", "bc");
+/// "#;
+/// assert_eq!(rlox::interpret_to_string(src).unwrap(), "éll\n2\n-1\ntrue\n");
+/// assert_eq!(
+///     rlox::interpret_to_string("substring(\"abc\", 2, 2);").unwrap_err(),
+///     "Substring out of range.\n[line 1] in script.\n"
+/// );
+/// assert_eq!(
+///     rlox::interpret_to_string("contains(1, \"a\");").unwrap_err(),
+///     "Argument must be a string.\n[line 1] in script.\n"
+/// );
+/// ```
+///
+/// `dump(value)` prints a value for debugging, strings are quoted and their special characters
+/// are escaped.
+///
+/// ```
+/// let src = "print \"a\nb\"; dump(\"a\nb\"); dump([\"x\", 1]);";
+/// assert_eq!(
+///     rlox::interpret_to_string(src).unwrap(),
+///     "a\nb\n\"a\\nb\"\n[\"x\", 1]\n"
+/// );
+/// ```
+///
+/// `num(s)` parses a string written like a number literal, optionally negative and surrounded
+/// by whitespace, and returns `nil` if it isn't a number.
+///
+/// ```
+/// let src = r#"
+///     print num("3.5") + 1;
+///     print num(" -42 ");
+///     print num("abc");
+///     print num("1.");
+///     print num("-9223372036854775808") + 1;
+///     print num("+1");
+/// "#;
+/// assert_eq!(
+///     rlox::interpret_to_string(src).unwrap(),
+///     "4.5\n-42\nnil\nnil\n-9223372036854775807\nnil\n"
+/// );
+/// ```
+///
+/// `is_number(v)`, `is_string(v)`, `is_nil(v)`, `is_bool(v)`, `is_list(v)`, and `is_function(v)`
+/// check the type of a value without comparing the name returned by `type(v)`.
+///
+/// ```
+/// let src = r#"
+///     print is_number(1);
+///     print is_number("x");
+///     print is_string("x");
+///     print is_nil(nil);
+///     print is_bool(false);
+///     print is_list([]);
+///     print is_function(clock);
+///     print is_function(is_number);
+///     print is_list({});
+/// "#;
+/// assert_eq!(
+///     rlox::interpret_to_string(src).unwrap(),
+///     "true\nfalse\ntrue\ntrue\ntrue\ntrue\ntrue\ntrue\nfalse\n"
+/// );
+/// ```
 pub struct VM {
     stack: Vec<Value>,
     frames: Vec<CallFrame>,
     open_upvalues: Vec<Rc<RefCell<ObjUpvalue>>>,
     globals: FxHashMap<StrId, Value>,
+    // Globals that were assigned a slot by the compiler are stored here instead of in the map,
+    // a slot is empty until its variable gets defined
+    global_slots: FxHashMap<StrId, u8>,
+    global_slot_names: Vec<StrId>,
+    global_slot_values: Vec<Option<Value>>,
+    resolve_global_slots: bool,
     init_string: StrId,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    error_output: Box<dyn Write>,
+    clock: Box<dyn Fn() -> f64>,
+    heap: Heap,
+    // Maximum number of instructions that a script can execute, 0 if there's no limit
+    instruction_budget: u64,
+    instruction_count: u64,
+    // Maximum number of characters in a string literal, 0 if there's no limit
+    max_string_len: usize,
+    stress_gc: bool,
+    trace_gc: bool,
+    trace_hook: Option<TraceHook>,
+    print_code: bool,
+    profile: Option<Profile>,
+    // Lines of the instructions that were executed, only recorded when coverage is enabled
+    coverage: Option<BTreeSet<usize>>,
+    number_format: NumberFormat,
+    allow_top_level_return: bool,
+    echo_expressions: bool,
+    // Reading an undefined global gives nil instead of an error
+    lenient_globals: bool,
+    warn_undefined_globals: bool,
+    // Chunk of the last script that ran, which is reused by the next compilation
+    spare_chunk: Option<Chunk>,
+}
+
+impl fmt::Debug for VM {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VM")
+            .field("stack", &self.stack)
+            .field("frames", &self.frames)
+            .field("open_upvalues", &self.open_upvalues)
+            .field("globals", &self.globals)
+            .field("global_slots", &self.global_slots)
+            .field("global_slot_names", &self.global_slot_names)
+            .field("global_slot_values", &self.global_slot_values)
+            .field("resolve_global_slots", &self.resolve_global_slots)
+            .field("init_string", &self.init_string)
+            .field("heap", &self.heap)
+            .field("instruction_budget", &self.instruction_budget)
+            .field("max_string_len", &self.max_string_len)
+            .field("instruction_count", &self.instruction_count)
+            .field("stress_gc", &self.stress_gc)
+            .field("trace_gc", &self.trace_gc)
+            .field("print_code", &self.print_code)
+            .field("profile", &self.profile)
+            .field("coverage", &self.coverage)
+            .field("number_format", &self.number_format)
+            .field("allow_top_level_return", &self.allow_top_level_return)
+            .field("echo_expressions", &self.echo_expressions)
+            .field("lenient_globals", &self.lenient_globals)
+            .field("warn_undefined_globals", &self.warn_undefined_globals)
+            .field("spare_chunk", &self.spare_chunk)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Default for VM {
@@ -148,98 +1307,745 @@ impl Default for VM {
             frames: Vec::with_capacity(MAX_FRAMES),
             open_upvalues: Vec::new(),
             globals: FxHashMap::default(),
+            global_slots: FxHashMap::default(),
+            global_slot_names: Vec::new(),
+            global_slot_values: Vec::new(),
+            resolve_global_slots: false,
             init_string: intern::id("init"),
+            input: Box::new(io::BufReader::new(io::stdin())),
+            output: Box::new(io::stdout()),
+            error_output: Box::new(io::stderr()),
+            clock: Box::new(wall_clock),
+            heap: Heap::default(),
+            instruction_budget: 0,
+            max_string_len: 0,
+            instruction_count: 0,
+            stress_gc: false,
+            trace_gc: false,
+            trace_hook: default_trace_hook(),
+            print_code: cfg!(debug_assertions),
+            profile: None,
+            coverage: None,
+            number_format: NumberFormat::Lox,
+            allow_top_level_return: false,
+            echo_expressions: false,
+            lenient_globals: false,
+            warn_undefined_globals: false,
+            spare_chunk: None,
         };
         vm.define_native("clock", 0, clock_native);
+        vm.define_native("len", 1, len_native);
+        vm.define_native("write", 1, write_native);
+        vm.define_native("type", 1, type_native);
+        vm.define_native("substring", 3, substring_native);
+        vm.define_native("indexOf", 2, index_of_native);
+        vm.define_native("contains", 2, contains_native);
+        vm.define_native("num", 1, num_native);
+        vm.define_native("read_line", 0, read_line_native);
+        vm.define_native("exit", 1, exit_native);
+        vm.define_native("dump", 1, dump_native);
+        vm.define_native("is_number", 1, is_number_native);
+        vm.define_native("is_string", 1, is_string_native);
+        vm.define_native("is_nil", 1, is_nil_native);
+        vm.define_native("is_bool", 1, is_bool_native);
+        vm.define_native("is_list", 1, is_list_native);
+        vm.define_native("is_function", 1, is_function_native);
         vm
     }
 }
 
 impl VM {
-    /// Load and run the virtual machine on the given chunk
-    pub fn interpret(&mut self, src: &str) -> Result<(), Error> {
-        let mut compiler = Compiler::new(src);
+    /// Read the program's input from the given reader instead of the standard input. This is
+    /// used by the `read_line` native function, which returns the next line without its line
+    /// break, or `nil` once the input has been exhausted.
+    ///
+    /// ```
+    /// use rlox::SharedBuffer;
+    /// use std::io::Cursor;
+    ///
+    /// let buf = SharedBuffer::default();
+    /// let mut vm = rlox::VM::default()
+    ///     .with_output(buf.clone())
+    ///     .with_input(Box::new(Cursor::new("hello\n")));
+    /// vm.interpret("print read_line(); print read_line();").unwrap();
+    /// assert_eq!(buf.contents(), "hello\nnil\n");
+    /// ```
+    pub fn with_input(mut self, input: Box<dyn BufRead>) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// Write the program's output to the given writer instead of the standard output. This is
+    /// used by `print` statements and the `write` native function, which writes a value without
+    /// a trailing newline.
+    ///
+    /// ```
+    /// use rlox::SharedBuffer;
+    ///
+    /// let buf = SharedBuffer::default();
+    /// let mut vm = rlox::VM::default().with_output(buf.clone());
+    /// vm.interpret("write(\"a\"); write(\"b\"); print 1;").unwrap();
+    /// assert_eq!(buf.contents(), "ab1\n");
+    /// ```
+    pub fn with_output<W: Write + 'static>(mut self, output: W) -> Self {
+        self.output = Box::new(output);
+        self
+    }
+
+    /// Write compilation errors, warnings, and runtime errors to the given writer instead of
+    /// the standard error.
+    pub fn with_error_output<W: Write + 'static>(mut self, error_output: W) -> Self {
+        self.error_output = Box::new(error_output);
+        self
+    }
+
+    /// Use the given function as the time source of the `clock` native function instead of the
+    /// number of seconds since the Unix epoch.
+    ///
+    /// ```
+    /// use rlox::SharedBuffer;
+    ///
+    /// let buf = SharedBuffer::default();
+    /// let mut vm = rlox::VM::default()
+    ///     .with_output(buf.clone())
+    ///     .with_clock(Box::new(|| 123.0));
+    /// vm.interpret("print clock();").unwrap();
+    /// assert_eq!(buf.contents(), "123\n");
+    /// ```
+    pub fn with_clock(mut self, clock: Box<dyn Fn() -> f64>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Load and run the virtual machine on the given chunk. Calling the `exit` native function
+    /// stops the program and returns its status as [`Error::Exit`] without exiting the
+    /// process. The value returned by the script is given back, which is only possible when
+    /// returning from the top level is allowed, see [`VM::set_allow_top_level_return`].
+    ///
+    /// ```
+    /// use rlox::{Error, SharedBuffer, VM};
+    ///
+    /// let buf = SharedBuffer::default();
+    /// let mut vm = VM::default().with_output(buf.clone());
+    /// assert!(matches!(vm.interpret("print 1; exit(3); print 2;"), Err(Error::Exit(3))));
+    /// assert_eq!(buf.contents(), "1\n");
+    /// assert!(matches!(vm.interpret("exit(1.5);"), Err(Error::Runtime)));
+    /// assert!(matches!(vm.interpret("exit(4294967296);"), Err(Error::Runtime)));
+    /// assert!(vm.interpret("print 1;").is_ok());
+    ///
+    /// let src = r#"
+    ///     fun step(n) { return n * 3 % 7; }
+    ///     var sum = 0;
+    ///     for (var i = 0; i < 10000; i = i + 1) {
+    ///         if (i % 2 == 0) sum = sum + step(i); else sum = sum - i / 4;
+    ///     }
+    ///     print sum;
+    /// "#;
+    /// assert_eq!(rlox::interpret_to_string(src).unwrap(), "-6235000\n");
+    /// ```
+    pub fn interpret(&mut self, src: &str) -> Result<Option<Value>, Error> {
+        let mut global_slots = std::mem::take(&mut self.global_slots);
+        let mut compiler = Compiler::new(src).with_defined_globals(self.globals.keys().copied());
+        if self.resolve_global_slots {
+            compiler = compiler.with_global_slots(&mut global_slots);
+        }
+        compiler.set_warn_undefined_globals(self.warn_undefined_globals && !self.lenient_globals);
+        compiler.set_allow_top_level_return(self.allow_top_level_return);
+        compiler.set_echo_expressions(self.echo_expressions);
+        compiler.set_print_code(self.print_code);
+        compiler.set_max_string_len(self.max_string_len);
+        if let Some(chunk) = self.spare_chunk.take() {
+            compiler = compiler.with_chunk(chunk);
+        }
         compiler.compile();
+        for warning in compiler.warnings() {
+            self.report(warning);
+        }
+        for err in compiler.errors() {
+            self.report(err);
+        }
 
-        let fun = compiler.finish().ok_or(Error::Compile)?;
-        let fun = Rc::new(fun);
+        let fun = compiler.finish();
+        self.global_slots = global_slots;
+        self.move_globals_to_slots();
+        self.interpret_fun(fun.ok_or(Error::Compile)?)
+    }
 
-        || -> Result<(), RuntimeError> {
-            let closure = Rc::new(ObjClosure::new(fun, Vec::new()));
+    /// Run a script function whose chunk was compiled beforehand or built by hand. The chunk is
+    /// trusted to be valid, it must end by returning from the script. The value returned by the
+    /// script is given back unless it's `nil`.
+    pub fn interpret_fun(&mut self, fun: ObjFun) -> Result<Option<Value>, Error> {
+        let fun = Rc::new(fun);
+        self.instruction_count = 0;
+        let result = || -> Result<Value, RuntimeError> {
+            let closure = Rc::new(ObjClosure::new(Rc::clone(&fun), Vec::new()));
             self.push(Value::Closure(Rc::clone(&closure)))?;
             self.call_closure(closure, 0)?;
             self.run()
-        }()
-        .map_err(|err| {
-            eprintln!("{}", err);
+        }();
+        if let Some(profile) = &mut self.profile {
+            profile.stop();
+        }
+        let result = result.map_err(|err| {
+            if let RuntimeError::Exit(status) = err {
+                self.reset_stack();
+                return Error::Exit(status);
+            }
+            let budget_exceeded = matches!(err, RuntimeError::BudgetExceeded);
+            self.report(err);
             self.print_stack_trace();
             self.reset_stack();
-            Error::Runtime
-        })
+            if budget_exceeded {
+                Error::BudgetExceeded
+            } else {
+                Error::Runtime
+            }
+        });
+        let result = result.map(|val| match val {
+            Value::Nil => None,
+            val => Some(val),
+        });
+        // nothing refers to the script once it's done, so its chunk can be reused
+        if let Ok(fun) = Rc::try_unwrap(fun) {
+            self.spare_chunk = Some(fun.chunk);
+        }
+        result
+    }
+
+    /// Collect garbage before every instruction, so every allocation happens right after a
+    /// collection. This makes bugs in the collector show up deterministically. This is disabled
+    /// by default.
+    ///
+    /// ```
+    /// use rlox::SharedBuffer;
+    ///
+    /// let src = r#"
+    ///     class Pair { init(a, b) { this.a = a; this.b = b; } }
+    ///     fun make(n) {
+    ///         var p = Pair(n, [n, {"n": n}]);
+    ///         p.self = p;
+    ///         fun get() { return p; }
+    ///         return get;
+    ///     }
+    ///     var total = 0;
+    ///     for (var i = 0; i < 20; i = i + 1) total = total + make(i)().b[1]["n"];
+    ///     print total;
+    /// "#;
+    /// let expected = rlox::interpret_to_string(src).unwrap();
+    ///
+    /// let buf = SharedBuffer::default();
+    /// let mut vm = rlox::VM::default().with_output(buf.clone());
+    /// vm.set_stress_gc(true);
+    /// vm.interpret(src).unwrap();
+    /// assert_eq!(buf.contents(), expected);
+    /// ```
+    pub fn set_stress_gc(&mut self, enabled: bool) {
+        self.stress_gc = enabled;
+    }
+
+    /// Log the number of freed objects to the error output after each collection. This is
+    /// disabled by default.
+    pub fn set_trace_gc(&mut self, enabled: bool) {
+        self.trace_gc = enabled;
+    }
+
+    /// Call the given function before each instruction with the chunk being run, the index of
+    /// the instruction, and the stack, or stop calling it when given `None`. Debug builds trace
+    /// every instruction to the standard output by default, and this replaces that behavior.
+    ///
+    /// ```
+    /// use std::{cell::Cell, io, rc::Rc};
+    ///
+    /// use rlox::VM;
+    ///
+    /// let count = Rc::new(Cell::new(0));
+    /// let mut vm = VM::default().with_output(io::sink());
+    /// let counter = Rc::clone(&count);
+    /// vm.set_trace_hook(Some(Box::new(move |chunk, ip, _stack| {
+    ///     assert!(ip < chunk.instructions_count());
+    ///     counter.set(counter.get() + 1);
+    /// })));
+    /// vm.interpret("print 1+2;").unwrap();
+    /// // the sum is folded into one constant, then it's printed and the script returns nil
+    /// assert_eq!(count.get(), 4);
+    ///
+    /// vm.set_trace_hook(None);
+    /// vm.interpret("print 1+2;").unwrap();
+    /// assert_eq!(count.get(), 4);
+    /// ```
+    pub fn set_trace_hook(&mut self, hook: Option<TraceHook>) {
+        self.trace_hook = hook;
+    }
+
+    /// Print the disassembly of each function to the standard output once it's compiled, see
+    /// [`Compiler::set_print_code`]. This is enabled by default in debug builds, release builds
+    /// never print it.
+    pub fn set_print_code(&mut self, enabled: bool) {
+        self.print_code = enabled;
+    }
+
+    /// Abort a script with [`Error::BudgetExceeded`] once it has executed the given number of
+    /// instructions, so untrusted code can't hang the host. The count starts over for every
+    /// script. A budget of 0 means there's no limit, which is the default.
+    ///
+    /// ```
+    /// use rlox::{Error, SharedBuffer, VM};
+    ///
+    /// let buf = SharedBuffer::default();
+    /// let mut vm = VM::default().with_error_output(buf.clone());
+    /// vm.set_instruction_budget(1000);
+    /// assert!(matches!(vm.interpret("while (true) {}"), Err(Error::BudgetExceeded)));
+    /// assert_eq!(
+    ///     buf.contents(),
+    ///     "Instruction budget exceeded.\n[line 1] in script.\n"
+    /// );
+    ///
+    /// assert!(vm.interpret("var i = 0; while (i < 10) i = i + 1;").is_ok());
+    /// ```
+    pub fn set_instruction_budget(&mut self, budget: u64) {
+        self.instruction_budget = budget;
+    }
+
+    /// Limit the number of characters in the string literals of a script, a longer literal is
+    /// a compile error. Together with [`VM::set_instruction_budget`], this helps running
+    /// untrusted scripts. A limit of 0 means there's no limit, which is the default.
+    ///
+    /// ```
+    /// use std::io;
+    ///
+    /// use rlox::{Error, VM};
+    ///
+    /// let mut vm = VM::default().with_error_output(io::sink());
+    /// vm.set_max_string_len(10);
+    /// assert!(vm.interpret("print \"short\";").is_ok());
+    /// assert!(matches!(
+    ///     vm.interpret("print \"not so short\";"),
+    ///     Err(Error::Compile)
+    /// ));
+    /// ```
+    pub fn set_max_string_len(&mut self, len: usize) {
+        self.max_string_len = len;
+    }
+
+    /// Let the top-level code use `return` to end the script early, [`VM::interpret`] gives
+    /// back the returned value. This is disabled by default.
+    ///
+    /// ```
+    /// use rlox::{Value, VM};
+    ///
+    /// let mut vm = VM::default();
+    /// assert!(vm.interpret("return 42;").is_err());
+    ///
+    /// vm.set_allow_top_level_return(true);
+    /// assert!(matches!(vm.interpret("return 42;"), Ok(Some(Value::Int(42)))));
+    /// assert!(matches!(
+    ///     vm.interpret("{ var a = 1.5; if (a > 1) return a; } return 0;"),
+    ///     Ok(Some(Value::Number(n))) if n == 1.5
+    /// ));
+    /// assert!(matches!(vm.interpret("print 1;"), Ok(None)));
+    /// ```
+    pub fn set_allow_top_level_return(&mut self, enabled: bool) {
+        self.allow_top_level_return = enabled;
+    }
+
+    /// Print the value of an expression that ends a script without a semicolon, like a
+    /// calculator. The REPL enables this, a trailing semicolon still discards the value. This
+    /// is disabled by default.
+    ///
+    /// ```
+    /// use std::io;
+    ///
+    /// use rlox::{SharedBuffer, VM};
+    ///
+    /// let output = SharedBuffer::default();
+    /// let mut vm = VM::default()
+    ///     .with_output(output.clone())
+    ///     .with_error_output(io::sink());
+    /// assert!(vm.interpret("1 + 2").is_err());
+    ///
+    /// vm.set_echo_expressions(true);
+    /// vm.interpret("1 + 2\n").unwrap();
+    /// vm.interpret("1 + 2;\n").unwrap();
+    /// vm.interpret("var a = 4; a * 2").unwrap();
+    /// assert_eq!(output.contents(), "3\n8\n");
+    /// ```
+    pub fn set_echo_expressions(&mut self, enabled: bool) {
+        self.echo_expressions = enabled;
+    }
+
+    /// Make reading a global variable that was never defined give back `nil` instead of failing
+    /// with a runtime error. Assigning to such variable is still an error. This is disabled by
+    /// default.
+    ///
+    /// ```
+    /// use std::io;
+    /// use rlox::{Error, SharedBuffer, VM};
+    ///
+    /// let buf = SharedBuffer::default();
+    /// let mut vm = VM::default()
+    ///     .with_output(buf.clone())
+    ///     .with_error_output(io::sink());
+    /// assert!(matches!(vm.interpret("print undefined_var;"), Err(Error::Runtime)));
+    ///
+    /// vm.set_lenient_globals(true);
+    /// assert!(vm.interpret("print undefined_var;").is_ok());
+    /// assert!(vm.interpret("fun f() { return later; } print f(); var later = 1;").is_ok());
+    /// assert!(matches!(vm.interpret("undefined_var = 1;"), Err(Error::Runtime)));
+    /// assert_eq!(buf.contents(), "nil\nnil\n");
+    /// ```
+    pub fn set_lenient_globals(&mut self, enabled: bool) {
+        self.lenient_globals = enabled;
+    }
+
+    /// Report a warning when a function reads a global variable that isn't declared anywhere,
+    /// see [`Compiler::set_warn_undefined_globals`]. Globals from earlier calls to
+    /// [`VM::interpret`] count as declared. This is disabled by default, and has no effect when
+    /// lenient globals are enabled.
+    ///
+    /// ```
+    /// use std::io;
+    /// use rlox::{SharedBuffer, VM};
+    ///
+    /// let src = "fun f() { return missing; }";
+    /// let buf = SharedBuffer::default();
+    /// let mut vm = VM::default()
+    ///     .with_output(io::sink())
+    ///     .with_error_output(buf.clone());
+    /// assert!(vm.interpret(src).is_ok());
+    /// assert_eq!(buf.contents(), "");
+    ///
+    /// vm.set_warn_undefined_globals(true);
+    /// assert!(vm.interpret(src).is_ok());
+    /// assert_eq!(buf.contents(), "[line 1] Warning: Undefined variable 'missing'.\n");
+    /// ```
+    pub fn set_warn_undefined_globals(&mut self, enabled: bool) {
+        self.warn_undefined_globals = enabled;
+    }
+
+    /// Give a slot to every global variable that gets declared, so the instructions that come
+    /// after the declaration read the variable through its slot instead of looking up its name,
+    /// see [`OpCode::GetGlobalSlot`]. This is disabled by default.
+    ///
+    /// ```
+    /// use rlox::{Error, SharedBuffer, VM};
+    ///
+    /// let errors = SharedBuffer::default();
+    /// let mut vm = VM::default().with_error_output(errors.clone());
+    /// vm.set_resolve_global_slots(true);
+    /// // the slot is given when compiling, the variable is defined when running
+    /// assert!(matches!(vm.interpret("exit(0); var later = 1;"), Err(Error::Exit(0))));
+    /// assert!(matches!(vm.interpret("print later;"), Err(Error::Runtime)));
+    /// assert!(errors.contents().starts_with("Undefined variable 'later'."));
+    /// ```
+    pub fn set_resolve_global_slots(&mut self, enabled: bool) {
+        self.resolve_global_slots = enabled;
+    }
+
+    /// Choose how `print` and the `write` native function format numbers. This is
+    /// [`NumberFormat::Lox`] by default.
+    ///
+    /// ```
+    /// use rlox::{NumberFormat, SharedBuffer, VM};
+    ///
+    /// let buf = SharedBuffer::default();
+    /// let mut vm = VM::default().with_output(buf.clone());
+    /// vm.set_number_format(NumberFormat::Float);
+    /// vm.interpret("print 1.0; print 1; print 2.5; write([3.0]);").unwrap();
+    /// assert_eq!(buf.contents(), "1.0\n1\n2.5\n[3.0]");
+    /// ```
+    pub fn set_number_format(&mut self, format: NumberFormat) {
+        self.number_format = format;
+    }
+
+    /// Count the executed instructions and measure the time spent on them. Enabling or
+    /// disabling profiling discards the collected statistics. This is disabled by default.
+    ///
+    /// ```
+    /// use rlox::VM;
+    ///
+    /// let mut vm = VM::default();
+    /// vm.set_profile(true);
+    /// vm.interpret("var i = 0; while (i < 10) i = i + 1;").unwrap();
+    /// assert_eq!(vm.opcode_stats()["Loop"], 10);
+    /// assert_eq!(vm.opcode_stats()["Less"], 11);
+    /// assert!(vm.opcode_times().contains_key("Loop"));
+    /// ```
+    pub fn set_profile(&mut self, enabled: bool) {
+        self.profile = if enabled {
+            Some(Profile::default())
+        } else {
+            None
+        };
+    }
+
+    /// Return how many times each kind of instruction was executed since profiling was
+    /// enabled, keyed by the instruction's name.
+    pub fn opcode_stats(&self) -> HashMap<&'static str, u64> {
+        self.profile
+            .as_ref()
+            .map(|profile| profile.counts.clone())
+            .unwrap_or_default()
+    }
+
+    /// Record the source line of every instruction that gets executed, the lines can be read
+    /// with [`VM::covered_lines`]. Enabling coverage again starts a new record. This is disabled
+    /// by default.
+    ///
+    /// ```
+    /// use std::collections::BTreeSet;
+    ///
+    /// use rlox::VM;
+    ///
+    /// let src = r#"var a = 1;
+    /// if (a > 0) {
+    ///     a = 2;
+    /// } else {
+    ///     a = 3;
+    /// }
+    /// "#;
+    /// let mut vm = VM::default();
+    /// vm.set_coverage(true);
+    /// vm.interpret(src).unwrap();
+    /// assert_eq!(vm.covered_lines(), [1, 2, 3, 4, 6].iter().copied().collect::<BTreeSet<_>>());
+    ///
+    /// vm.set_coverage(false);
+    /// assert!(vm.covered_lines().is_empty());
+    /// ```
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.coverage = if enabled { Some(BTreeSet::new()) } else { None };
+    }
+
+    /// Return the source lines that were executed since coverage was enabled
+    pub fn covered_lines(&self) -> BTreeSet<usize> {
+        self.coverage.clone().unwrap_or_default()
+    }
+
+    /// Return the time spent on each kind of instruction since profiling was enabled, keyed by
+    /// the instruction's name.
+    pub fn opcode_times(&self) -> HashMap<&'static str, Duration> {
+        self.profile
+            .as_ref()
+            .map(|profile| profile.times.clone())
+            .unwrap_or_default()
+    }
+
+    /// Return the number of values that the stack can hold without allocating. The stack is
+    /// allocated up front for [`MAX_STACK`] values, which is the most it can ever hold.
+    ///
+    /// ```
+    /// use rlox::{VM, MAX_STACK};
+    ///
+    /// let mut vm = VM::default();
+    /// assert!(vm.stack_capacity() >= MAX_STACK);
+    /// vm.interpret("fun f(n) { if (n > 0) f(n - 1); } f(50);").unwrap();
+    /// assert!(vm.stack_capacity() >= MAX_STACK);
+    /// ```
+    pub fn stack_capacity(&self) -> usize {
+        self.stack.capacity()
+    }
+
+    /// Return the source position of the instruction that is being run by the innermost call
+    /// frame, which is where runtime errors are reported. The default position is returned when
+    /// nothing is running.
+    ///
+    /// ```
+    /// use rlox::{SharedBuffer, VM};
+    ///
+    /// let errors = SharedBuffer::default();
+    /// let mut vm = VM::default().with_error_output(errors.clone());
+    /// assert_eq!(vm.current_pos().line, 1);
+    ///
+    /// vm.interpret("var a = 1;\nvar b = nil;\nprint a / b;\nprint a;").unwrap_err();
+    /// let errors = errors.contents();
+    /// assert!(errors.ends_with("[line 3] in script.\n"));
+    /// ```
+    pub fn current_pos(&self) -> Position {
+        self.frames
+            .last()
+            .map(CallFrame::current_pos)
+            .unwrap_or_default()
+    }
+
+    /// Free the objects that can't be reached from the program anymore but are kept alive by
+    /// reference cycles, and return how many objects were freed.
+    ///
+    /// ```
+    /// use rlox::VM;
+    ///
+    /// let mut vm = VM::default();
+    /// vm.interpret("var l = [1]; l[0] = l; l = nil;").unwrap();
+    /// assert_eq!(vm.object_count(), 1);
+    /// assert_eq!(vm.collect_garbage(), 1);
+    /// assert_eq!(vm.object_count(), 0);
+    ///
+    /// // objects that the host holds are kept, along with what they reference
+    /// vm.set_allow_top_level_return(true);
+    /// let list = vm.interpret("return [1, [2, 3]];").unwrap().unwrap();
+    /// vm.interpret("for (var i = 0; i < 5000; i = i + 1) { var l = [i]; }").unwrap();
+    /// vm.collect_garbage();
+    /// assert_eq!(list.to_string(), "[1, [2, 3]]");
+    /// ```
+    pub fn collect_garbage(&mut self) -> usize {
+        let mut marker = Marker::default();
+        self.stack.iter().for_each(|v| marker.mark_value(v));
+        self.globals.values().for_each(|v| marker.mark_value(v));
+        self.global_slot_values
+            .iter()
+            .flatten()
+            .for_each(|v| marker.mark_value(v));
+        self.frames
+            .iter()
+            .for_each(|frame| marker.mark_closure(&frame.closure));
+        self.open_upvalues
+            .iter()
+            .for_each(|upvalue| marker.mark_upvalue(upvalue));
+        marker.trace();
+        let freed = self.heap.sweep(&mut marker);
+        if self.trace_gc {
+            let remaining = self.heap.object_count();
+            self.report(format!(
+                "-- gc freed {} objects, {} remaining",
+                freed, remaining
+            ));
+        }
+        freed
+    }
+
+    /// Return the number of lists, maps, classes, instances, and upvalues that are alive
+    pub fn object_count(&self) -> usize {
+        self.heap.object_count()
     }
 
     /// Run the virtual machine with it currently given chunk.
-    fn run(&mut self) -> Result<(), RuntimeError> {
+    fn run(&mut self) -> Result<Value, RuntimeError> {
+        let mut ip = self.frame().ip;
+        let result = self.run_from(&mut ip);
+        // the stack trace reads the position of the failing instruction from the frame
+        if let Some(frame) = self.frames.last_mut() {
+            frame.ip = ip;
+        }
+        result
+    }
+
+    // The closure and the instruction pointer of the current frame are kept in locals, so they
+    // don't have to be read from the frame on every instruction. The instruction pointer is
+    // only written back to the frame before a call can push another frame.
+    fn run_from(&mut self, ip: &mut usize) -> Result<Value, RuntimeError> {
+        let mut closure = Rc::clone(&self.frame().closure);
         loop {
-            #[cfg(debug_assertions)]
-            {
-                print_stack(&self.stack);
-                disassemble_instruction(&self.frame().closure.fun.chunk, self.frame().ip as usize);
+            if let Some(profile) = &mut self.profile {
+                profile.stop();
             }
 
-            let opcode = self.next_instruction().clone();
-            match opcode {
+            // every value that is in use is reachable from the roots in between instructions, so
+            // this is the only place where it's safe to collect
+            if self.stress_gc || self.heap.should_collect() {
+                self.collect_garbage();
+            }
+
+            if let Some(hook) = &mut self.trace_hook {
+                hook(&closure.fun.chunk, *ip, &self.stack);
+            }
+
+            let (opcode, pos) = closure.fun.chunk.read_instruction(*ip);
+            *ip += 1;
+            if let Some(lines) = &mut self.coverage {
+                lines.insert(pos.line);
+            }
+            self.instruction_count += 1;
+            if self.instruction_budget > 0 && self.instruction_count > self.instruction_budget {
+                return Err(RuntimeError::BudgetExceeded);
+            }
+            if let Some(profile) = &mut self.profile {
+                profile.start(opcode.name());
+            }
+            match *opcode {
                 OpCode::Constant(ref const_id) => {
-                    let val = self.read_const(*const_id as usize).clone();
+                    let val = closure.fun.chunk.read_const(*const_id as usize).clone();
                     self.push(val)?;
                 }
+                OpCode::SmallInt(ref n) => self.push(Value::Int(*n as i64))?,
                 OpCode::Nil => self.push(Value::Nil)?,
                 OpCode::True => self.push(Value::Bool(true))?,
                 OpCode::False => self.push(Value::Bool(false))?,
                 OpCode::Pop => {
                     self.pop();
                 }
+                OpCode::Dup => {
+                    let top = self.peek(0).clone();
+                    self.push(top)?;
+                }
+                OpCode::Swap => {
+                    // the compiler never emits a swap without two values above the callee
+                    if self.stack.len() - self.frame().slot < 3 {
+                        return Err(RuntimeError::Message(
+                            "Internal error: not enough values to swap".to_string(),
+                        ));
+                    }
+                    let len = self.stack.len();
+                    self.stack.swap(len - 1, len - 2);
+                }
                 OpCode::GetLocal(ref slot) => {
                     let local = self.stack[self.frame().slot + *slot as usize].clone();
                     self.push(local)?;
                 }
+                OpCode::IncLocal(ref slot) => {
+                    let offset = self.frame().slot + *slot as usize;
+                    self.stack[offset] = self.stack[offset].add(&Value::Int(1))?;
+                }
+                OpCode::LessLocalConst(ref slot, ref const_id) => {
+                    let local = &self.stack[self.frame().slot + *slot as usize];
+                    let res = local.lt(closure.fun.chunk.read_const(*const_id as usize))?;
+                    self.push(res)?;
+                }
                 OpCode::SetLocal(ref slot) => {
                     let val = self.peek(0);
                     let offset = self.frame().slot + *slot as usize;
                     self.stack[offset] = val.clone();
                 }
                 OpCode::GetGlobal(ref const_id) => {
-                    let name = self.read_const(*const_id as usize).as_str();
-                    let val = self
-                        .globals
-                        .get(name)
-                        .ok_or_else(|| {
-                            RuntimeError(format!("Undefined variable '{}'", intern::str(*name)))
-                        })?
-                        .clone();
+                    let name = *closure.fun.chunk.read_const(*const_id as usize).as_str();
+                    let val = match self.global_mut(name).map(|val| val.clone()) {
+                        Some(val) => val,
+                        None if self.lenient_globals => Value::Nil,
+                        None => return Err(undefined_variable(name)),
+                    };
                     self.push(val)?;
                 }
                 OpCode::DefineGlobal(ref const_id) => {
-                    let name = *self.read_const(*const_id as usize).as_str();
+                    let name = *closure.fun.chunk.read_const(*const_id as usize).as_str();
                     let val = self.pop();
-                    self.globals.insert(name, val);
+                    match self.global_slots.get(&name) {
+                        Some(slot) => self.global_slot_values[*slot as usize] = Some(val),
+                        None => {
+                            self.globals.insert(name, val);
+                        }
+                    }
                 }
                 OpCode::SetGlobal(ref const_id) => {
-                    let name = *self.read_const(*const_id as usize).as_str();
+                    let name = *closure.fun.chunk.read_const(*const_id as usize).as_str();
                     let val = self.peek(0).clone();
-                    if !self.globals.contains_key(&name) {
-                        return Err(RuntimeError(format!(
-                            "Undefined variable '{}'",
-                            intern::str(name)
-                        )));
+                    match self.global_mut(name) {
+                        Some(global) => *global = val,
+                        None => return Err(undefined_variable(name)),
                     }
-                    self.globals.insert(name, val);
+                }
+                OpCode::GetGlobalSlot(ref slot) => {
+                    let val = match self.global_slot_values.get(*slot as usize) {
+                        Some(Some(val)) => val.clone(),
+                        _ if self.lenient_globals => Value::Nil,
+                        _ => {
+                            let name = self.global_slot_names[*slot as usize];
+                            return Err(undefined_variable(name));
+                        }
+                    };
+                    self.push(val)?;
                 }
                 OpCode::GetUpvalue(ref slot) => {
                     let slot = *slot as usize;
-                    let upvalue = Rc::clone(&self.frame().closure.upvalues[slot]);
+                    let upvalue = Rc::clone(&closure.upvalues[slot]);
                     let value = match &*upvalue.borrow() {
-                        ObjUpvalue::Open(loc) => self.stack[*loc as usize].clone(),
+                        ObjUpvalue::Open(loc) => self.stack[*loc].clone(),
                         ObjUpvalue::Closed(val) => val.clone(),
                     };
                     self.push(value)?;
@@ -247,34 +2053,52 @@ impl VM {
                 OpCode::SetUpvalue(ref slot) => {
                     let value = self.peek(0).clone();
                     let slot = *slot as usize;
-                    let upvalue = Rc::clone(&self.frame().closure.upvalues[slot]);
+                    let upvalue = Rc::clone(&closure.upvalues[slot]);
                     match &mut *upvalue.borrow_mut() {
-                        ObjUpvalue::Open(loc) => self.stack[*loc as usize] = value,
+                        ObjUpvalue::Open(loc) => self.stack[*loc] = value,
                         ObjUpvalue::Closed(val) => *val = value,
                     };
                 }
                 OpCode::GetProperty(ref const_id) => {
-                    let instance = self.peek(0);
-                    if !instance.is_instance() {
-                        return Err(RuntimeError("Only instances have properties".to_string()));
-                    }
-                    let instance = Rc::clone(instance.as_instance());
-                    let prop_name = *self.read_const(*const_id as usize).as_str();
-                    match instance.borrow().fields.get(&prop_name) {
+                    let prop_name = *closure.fun.chunk.read_const(*const_id as usize).as_str();
+                    let instance = match self.peek(0) {
+                        Value::Instance(instance) => Rc::clone(instance),
+                        Value::Class(class) => {
+                            let class = Rc::clone(class);
+                            self.bind_static_method(class, prop_name)?;
+                            continue;
+                        }
+                        _ => {
+                            return Err(RuntimeError::Message(
+                                "Only instances have properties".to_string(),
+                            ))
+                        }
+                    };
+                    let field = instance.borrow().fields.get(&prop_name).cloned();
+                    match field {
                         Some(val) => {
                             self.pop();
-                            self.push(val.clone())?;
+                            self.push(val)?;
+                        }
+                        None => {
+                            // a getter pushes a new frame
+                            let class = Rc::clone(&instance.borrow().class);
+                            self.frame_mut().ip = *ip;
+                            self.get_class_property(class, prop_name)?;
+                            closure = Rc::clone(&self.frame().closure);
+                            *ip = self.frame().ip;
                         }
-                        None => self.bind_method(Rc::clone(&instance.borrow().class), prop_name)?,
                     };
                 }
                 OpCode::SetProperty(ref const_id) => {
                     let value = self.pop();
                     let instance = self.pop();
                     if !instance.is_instance() {
-                        return Err(RuntimeError("Only instances have fields".to_string()));
+                        return Err(RuntimeError::Message(
+                            "Only instances have fields".to_string(),
+                        ));
                     }
-                    let prop_name = *self.read_const(*const_id as usize).as_str();
+                    let prop_name = *closure.fun.chunk.read_const(*const_id as usize).as_str();
                     instance
                         .as_instance()
                         .borrow_mut()
@@ -283,9 +2107,13 @@ impl VM {
                     self.push(value)?;
                 }
                 OpCode::GetSuper(ref const_id) => {
-                    let name = *self.read_const(*const_id as usize).as_str();
+                    let name = *closure.fun.chunk.read_const(*const_id as usize).as_str();
                     let superclass = self.pop();
-                    self.bind_method(Rc::clone(superclass.as_class()), name)?;
+                    // a getter pushes a new frame
+                    self.frame_mut().ip = *ip;
+                    self.get_class_property(Rc::clone(superclass.as_class()), name)?;
+                    closure = Rc::clone(&self.frame().closure);
+                    *ip = self.frame().ip;
                 }
                 OpCode::Equal => {
                     let v2 = self.pop();
@@ -306,6 +2134,10 @@ impl VM {
                     let v2 = self.pop();
                     let v1 = self.peek_mut(0);
                     *v1 = v1.add(&v2)?;
+                    if let Value::List(_) = v1 {
+                        let list = v1.clone();
+                        self.heap.track(&list);
+                    }
                 }
                 OpCode::Subtract => {
                     let v2 = self.pop();
@@ -322,6 +2154,16 @@ impl VM {
                     let v1 = self.peek_mut(0);
                     *v1 = v1.div(&v2)?;
                 }
+                OpCode::Modulo => {
+                    let v2 = self.pop();
+                    let v1 = self.peek_mut(0);
+                    *v1 = v1.rem(&v2)?;
+                }
+                OpCode::FloorDivide => {
+                    let v2 = self.pop();
+                    let v1 = self.peek_mut(0);
+                    *v1 = v1.floor_div(&v2)?;
+                }
                 OpCode::Not => {
                     let v = self.peek_mut(0);
                     *v = v.not();
@@ -330,40 +2172,101 @@ impl VM {
                     let v = self.peek_mut(0);
                     *v = v.neg()?;
                 }
+                OpCode::BitAnd => {
+                    let v2 = self.pop();
+                    let v1 = self.peek_mut(0);
+                    *v1 = v1.bitand(&v2)?;
+                }
+                OpCode::BitOr => {
+                    let v2 = self.pop();
+                    let v1 = self.peek_mut(0);
+                    *v1 = v1.bitor(&v2)?;
+                }
+                OpCode::BitXor => {
+                    let v2 = self.pop();
+                    let v1 = self.peek_mut(0);
+                    *v1 = v1.bitxor(&v2)?;
+                }
+                OpCode::Shl => {
+                    let v2 = self.pop();
+                    let v1 = self.peek_mut(0);
+                    *v1 = v1.shl(&v2)?;
+                }
+                OpCode::Shr => {
+                    let v2 = self.pop();
+                    let v1 = self.peek_mut(0);
+                    *v1 = v1.shr(&v2)?;
+                }
+                OpCode::BitNot => {
+                    let v = self.peek_mut(0);
+                    *v = v.bit_not()?;
+                }
                 OpCode::Print => {
                     let v = self.pop();
-                    println!("{}", v);
+                    match self.number_format {
+                        NumberFormat::Lox => writeln!(self.output, "{}", v),
+                        NumberFormat::Float => writeln!(self.output, "{:#}", v),
+                    }
+                    .map_err(io_error)?;
+                }
+                OpCode::PrintN(ref count) => {
+                    let values = self.stack.split_off(self.stack.len() - *count as usize);
+                    let line: Vec<String> = values
+                        .iter()
+                        .map(|v| match self.number_format {
+                            NumberFormat::Lox => format!("{}", v),
+                            NumberFormat::Float => format!("{:#}", v),
+                        })
+                        .collect();
+                    writeln!(self.output, "{}", line.join(" ")).map_err(io_error)?;
                 }
                 OpCode::Jump(ref offset) => {
-                    self.frame_mut().ip += *offset as usize;
+                    *ip += *offset as usize;
                 }
                 OpCode::JumpIfFalse(ref offset) => {
-                    if self.peek(0).not().as_bool() {
-                        self.frame_mut().ip += *offset as usize;
+                    if self.peek(0).is_falsey() {
+                        *ip += *offset as usize;
+                    }
+                }
+                OpCode::JumpIfNil(ref offset) => {
+                    if let Value::Nil = self.peek(0) {
+                        *ip += *offset as usize;
                     }
                 }
                 OpCode::Loop(ref offset) => {
-                    self.frame_mut().ip -= *offset as usize;
+                    *ip -= *offset as usize;
                 }
                 OpCode::Call(ref argc) => {
-                    self.call_value(self.peek(*argc as usize).clone(), *argc)?;
+                    let argc = *argc;
+                    self.frame_mut().ip = *ip;
+                    self.call_value(self.peek(argc as usize).clone(), argc)?;
+                    closure = Rc::clone(&self.frame().closure);
+                    *ip = self.frame().ip;
                 }
                 OpCode::Invoke(ref const_id, ref argc) => {
-                    let name = *self.read_const(*const_id as usize).as_str();
-                    self.invoke(name, *argc)?;
+                    let argc = *argc;
+                    let name = *closure.fun.chunk.read_const(*const_id as usize).as_str();
+                    self.frame_mut().ip = *ip;
+                    self.invoke(name, argc)?;
+                    closure = Rc::clone(&self.frame().closure);
+                    *ip = self.frame().ip;
                 }
                 OpCode::SuperInvoke(ref const_id, ref argc) => {
-                    let method = *self.read_const(*const_id as usize).as_str();
+                    let argc = *argc;
+                    let method = *closure.fun.chunk.read_const(*const_id as usize).as_str();
                     let superclass = self.pop();
-                    self.invoke_from_class(Rc::clone(superclass.as_class()), method, *argc)?;
+                    self.frame_mut().ip = *ip;
+                    self.invoke_from_class(Rc::clone(superclass.as_class()), method, argc)?;
+                    closure = Rc::clone(&self.frame().closure);
+                    *ip = self.frame().ip;
                 }
                 OpCode::Closure(ref fun_idx, ref upvalues) => {
-                    let fun = Rc::clone(self.read_const(*fun_idx as usize).as_fun());
+                    let fun = Rc::clone(closure.fun.chunk.read_const(*fun_idx as usize).as_fun());
                     let upvalues = upvalues.iter().map(|upvalue| {
                         if upvalue.is_local {
                             self.capture_upvalue(self.frame().slot + upvalue.index as usize)
                         } else {
-                            Rc::clone(&self.frame().closure.upvalues[upvalue.index as usize])
+                            Rc::clone(&closure.upvalues[upvalue.index as usize])
                         }
                     });
                     let closure = Rc::new(ObjClosure::new(fun, upvalues.collect()));
@@ -378,46 +2281,140 @@ impl VM {
                     self.close_upvalues(self.frame().slot);
                     let frame = self.frames.pop().expect("Frames empty");
                     if self.frames.is_empty() {
-                        self.pop();
-                        return Ok(());
+                        // the script can return from inside a block that still has locals
+                        self.popn(self.stack.len() - frame.slot);
+                        return Ok(val);
                     }
                     self.popn(self.stack.len() - frame.slot);
                     self.push(val)?;
+                    closure = Rc::clone(&self.frame().closure);
+                    *ip = self.frame().ip;
                 }
                 OpCode::Class(ref const_id) => {
-                    let name = self.read_const(*const_id as usize).as_str();
-                    let class = Rc::new(RefCell::new(ObjClass::new(*name)));
-                    self.push(Value::Class(class))?;
+                    let name = closure.fun.chunk.read_const(*const_id as usize).as_str();
+                    let class = Value::Class(Rc::new(RefCell::new(ObjClass::new(*name))));
+                    self.heap.track(&class);
+                    self.push(class)?;
                 }
                 OpCode::Inherit => {
                     let subclass = self.pop();
                     let superclass = if self.peek(0).is_class() {
                         Rc::clone(self.peek(0).as_class())
                     } else {
-                        return Err(RuntimeError("Superclass must be a class".to_string()));
+                        return Err(RuntimeError::Message(
+                            "Superclass must be a class".to_string(),
+                        ));
                     };
                     // Upon inheritance, we copy all method references from the superclass
                     // to the subclass. This technique does not work in languages that support
                     // "monkey patching" lik Python or Ruby, where user can change the behaviors
                     // of a class at runtme.
-                    subclass.as_class().borrow_mut().methods.extend(
+                    let superclass = superclass.borrow();
+                    let mut subclass = subclass.as_class().borrow_mut();
+                    subclass
+                        .methods
+                        .extend(superclass.methods.iter().map(|(k, v)| (*k, v.clone())));
+                    subclass
+                        .getters
+                        .extend(superclass.getters.iter().map(|(k, v)| (*k, v.clone())));
+                    subclass.static_methods.extend(
                         superclass
-                            .borrow()
-                            .methods
+                            .static_methods
                             .iter()
                             .map(|(k, v)| (*k, v.clone())),
                     );
                 }
-                OpCode::Method(ref const_id) => {
-                    let name = *self.read_const(*const_id as usize).as_str();
-                    self.define_method(name);
+                OpCode::BuildList(ref len) => {
+                    let items = self.stack.split_off(self.stack.len() - *len as usize);
+                    let list = Value::List(Rc::new(RefCell::new(items)));
+                    self.heap.track(&list);
+                    self.push(list)?;
+                }
+                OpCode::BuildMap(ref len) => {
+                    let entries = self.stack.split_off(self.stack.len() - 2 * *len as usize);
+                    let mut map = MapEntries::default();
+                    for entry in entries.chunks(2) {
+                        map.insert(map_key(&entry[0])?, entry[1].clone());
+                    }
+                    let map = Value::Map(Rc::new(RefCell::new(map)));
+                    self.heap.track(&map);
+                    self.push(map)?;
+                }
+                OpCode::Index => {
+                    let index = self.pop();
+                    let target = self.pop();
+                    let value = match target {
+                        Value::List(list) => {
+                            let list = list.borrow();
+                            list[list_index(&index, list.len())?].clone()
+                        }
+                        Value::Map(map) => map
+                            .borrow()
+                            .get(&map_key(&index)?)
+                            .cloned()
+                            .unwrap_or(Value::Nil),
+                        _ => {
+                            return Err(RuntimeError::Message(
+                                "Only lists and maps can be indexed".to_string(),
+                            ))
+                        }
+                    };
+                    self.push(value)?;
+                }
+                OpCode::Len => {
+                    let value = self.pop();
+                    let len = len_native(self, std::slice::from_ref(&value))?;
+                    self.push(len)?;
+                }
+                OpCode::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let target = self.pop();
+                    match target {
+                        Value::List(list) => {
+                            let mut list = list.borrow_mut();
+                            let idx = list_index(&index, list.len())?;
+                            list[idx] = value.clone();
+                        }
+                        Value::Map(map) => {
+                            map.borrow_mut().insert(map_key(&index)?, value.clone());
+                        }
+                        _ => {
+                            return Err(RuntimeError::Message(
+                                "Only lists and maps can be indexed".to_string(),
+                            ))
+                        }
+                    };
+                    self.push(value)?;
+                }
+                OpCode::Method(ref const_id, ref kind) => {
+                    let name = *closure.fun.chunk.read_const(*const_id as usize).as_str();
+                    self.define_method(name, *kind);
                 }
             }
         }
     }
 
     fn invoke(&mut self, name: StrId, argc: u8) -> Result<(), RuntimeError> {
-        let receiver = Rc::clone(self.peek(argc as usize).as_instance());
+        let receiver = match self.peek(argc as usize) {
+            Value::Instance(instance) => Rc::clone(instance),
+            Value::Class(class) => {
+                let method = class
+                    .borrow()
+                    .static_methods
+                    .get(&name)
+                    .cloned()
+                    .ok_or_else(|| {
+                        RuntimeError::Message("Only instances have methods".to_string())
+                    })?;
+                return self.call_closure(Rc::clone(method.as_closure()), argc);
+            }
+            _ => {
+                return Err(RuntimeError::Message(
+                    "Only instances have methods".to_string(),
+                ))
+            }
+        };
         let receiver = receiver.borrow();
 
         match receiver.fields.get(&name) {
@@ -436,10 +2433,15 @@ impl VM {
         argc: u8,
     ) -> Result<(), RuntimeError> {
         let class = class.borrow();
-        let method = class
-            .methods
-            .get(&name)
-            .ok_or_else(|| RuntimeError(format!("Undefined property '{}'", intern::str(name))))?;
+        if class.getters.contains_key(&name) {
+            return Err(RuntimeError::Message(format!(
+                "Can't call getter '{}' with arguments",
+                intern::str(name)
+            )));
+        }
+        let method = class.methods.get(&name).ok_or_else(|| {
+            RuntimeError::Message(format!("Undefined property '{}'", intern::str(name)))
+        })?;
         let method = Rc::clone(method.as_closure());
         self.call_closure(method, argc)
     }
@@ -450,7 +2452,7 @@ impl VM {
             Value::NativeFun(f) => self.call_native(f, argc),
             Value::Class(c) => self.call_class(c, argc),
             Value::BoundMethod(m) => self.call_bound_method(Rc::clone(&m), argc),
-            _ => Err(RuntimeError(
+            _ => Err(RuntimeError::Message(
                 "Can only call functions and classes".to_string(),
             )),
         }
@@ -465,6 +2467,7 @@ impl VM {
             }
         }
         let upvalue = Rc::new(RefCell::new(ObjUpvalue::Open(location)));
+        self.heap.track_upvalue(&upvalue);
         self.open_upvalues.push(Rc::clone(&upvalue));
         upvalue
     }
@@ -486,20 +2489,38 @@ impl VM {
     }
 
     fn call_closure(&mut self, closure: Rc<ObjClosure>, argc: u8) -> Result<(), RuntimeError> {
-        if argc != closure.fun.arity {
-            return Err(RuntimeError(format!(
-                "Expected {} arguments but got {}",
-                closure.fun.arity, argc
-            )));
+        let required = closure.fun.required_arity();
+        let max = if closure.fun.variadic {
+            None
+        } else {
+            Some(closure.fun.arity)
+        };
+        if argc < required || max.is_some_and(|max| argc > max) {
+            return Err(RuntimeError::ArityMismatch {
+                expected: required,
+                max,
+                got: argc,
+            });
+        }
+
+        let mut argc = argc;
+        if closure.fun.variadic && argc >= closure.fun.arity {
+            // pack the remaining arguments into the rest parameter
+            let rest_count = (argc - closure.fun.arity + 1) as usize;
+            let rest = self.stack.split_off(self.stack.len() - rest_count);
+            let rest = Value::List(Rc::new(RefCell::new(rest)));
+            self.heap.track(&rest);
+            self.push(rest)?;
+            argc = closure.fun.arity;
         }
 
         if self.frames.len() == MAX_FRAMES {
-            return Err(RuntimeError("Stack overflow".to_string()));
+            return Err(RuntimeError::Message("Stack overflow".to_string()));
         }
 
         let frame = CallFrame {
+            ip: closure.fun.entry(argc),
             closure,
-            ip: 0,
             slot: self.stack.len() - argc as usize - 1,
         };
         self.frames.push(frame);
@@ -508,34 +2529,64 @@ impl VM {
 
     fn call_native(&mut self, fun: NativeFun, argc: u8) -> Result<(), RuntimeError> {
         if argc != fun.arity {
-            return Err(RuntimeError(format!(
-                "Expected {} arguments but got {}",
-                fun.arity, argc
-            )));
+            return Err(RuntimeError::ArityMismatch {
+                expected: fun.arity,
+                max: Some(fun.arity),
+                got: argc,
+            });
         }
-        let argc = argc as usize;
-        let args = &self.stack[self.stack.len() - argc..];
+        // the arguments are taken off the stack so the native function can use the virtual machine
+        let args: Vec<Value> = self
+            .stack
+            .drain(self.stack.len() - argc as usize..)
+            .collect();
         let call = fun.call;
-        let res = call(args);
-        self.popn(argc + 1);
+        let res = call(self, &args)?;
+        self.pop();
         self.push(res)
     }
 
-    fn define_native(&mut self, name: &str, arity: u8, call: fn(&[Value]) -> Value) {
+    // Return the value of a defined global variable, wherever it is stored
+    fn global_mut(&mut self, name: StrId) -> Option<&mut Value> {
+        match self.global_slots.get(&name) {
+            Some(slot) => self.global_slot_values[*slot as usize].as_mut(),
+            None => self.globals.get_mut(&name),
+        }
+    }
+
+    // Give storage to the slots that the compiler assigned since the last time, globals that
+    // were already defined by name are moved into their slot.
+    fn move_globals_to_slots(&mut self) {
+        let start = self.global_slot_values.len();
+        self.global_slot_values
+            .resize(self.global_slots.len(), None);
+        self.global_slot_names
+            .resize(self.global_slots.len(), self.init_string);
+        for (name, slot) in self.global_slots.iter() {
+            if *slot as usize >= start {
+                self.global_slot_names[*slot as usize] = *name;
+                self.global_slot_values[*slot as usize] = self.globals.remove(name);
+            }
+        }
+    }
+
+    fn define_native(&mut self, name: &str, arity: u8, call: NativeFunCall) {
         let name = intern::id(name);
         self.globals
             .insert(name, Value::NativeFun(NativeFun { name, arity, call }));
     }
 
     fn call_class(&mut self, class: Rc<RefCell<ObjClass>>, argc: u8) -> Result<(), RuntimeError> {
-        *self.peek_mut(argc as usize) =
-            Value::Instance(Rc::new(RefCell::new(ObjInstance::new(Rc::clone(&class)))));
+        let instance = Value::Instance(Rc::new(RefCell::new(ObjInstance::new(Rc::clone(&class)))));
+        self.heap.track(&instance);
+        *self.peek_mut(argc as usize) = instance;
 
         match class.borrow().methods.get(&self.init_string) {
-            None if argc != 0 => Err(RuntimeError(format!(
-                "Expected 0 arguments but got {}",
-                argc
-            ))),
+            None if argc != 0 => Err(RuntimeError::ArityMismatch {
+                expected: 0,
+                max: Some(0),
+                got: argc,
+            }),
             Some(init) => self.call_closure(Rc::clone(init.as_closure()), argc),
             _ => Ok(()),
         }
@@ -550,10 +2601,57 @@ impl VM {
         self.call_closure(Rc::clone(&bound.method), argc)
     }
 
-    fn define_method(&mut self, name: StrId) {
+    fn define_method(&mut self, name: StrId, kind: MethodKind) {
         let method = self.pop();
         let class = Rc::clone(self.peek(0).as_class());
-        class.borrow_mut().methods.insert(name, method);
+        let mut class = class.borrow_mut();
+        match kind {
+            MethodKind::Method => {
+                class.getters.remove(&name);
+                class.methods.insert(name, method);
+            }
+            MethodKind::Getter => {
+                class.methods.remove(&name);
+                class.getters.insert(name, method);
+            }
+            MethodKind::Static => {
+                class.static_methods.insert(name, method);
+            }
+        }
+    }
+
+    /// Replace the instance on top of the stack with the result of the getter if there's one with
+    /// the given name, otherwise, bind the method with the given name to the instance.
+    fn get_class_property(
+        &mut self,
+        class: Rc<RefCell<ObjClass>>,
+        name: StrId,
+    ) -> Result<(), RuntimeError> {
+        let getter = class.borrow().getters.get(&name).cloned();
+        match getter {
+            Some(getter) => self.call_closure(Rc::clone(getter.as_closure()), 0),
+            None => self.bind_method(class, name),
+        }
+    }
+
+    fn bind_static_method(
+        &mut self,
+        class: Rc<RefCell<ObjClass>>,
+        name: StrId,
+    ) -> Result<(), RuntimeError> {
+        let method = class
+            .borrow()
+            .static_methods
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| RuntimeError::Message("Only instances have properties".to_string()))?;
+        // The class itself is the receiver of its static methods
+        let receiver = self.pop();
+        let bound = Rc::new(ObjBoundMethod::new(
+            receiver,
+            Rc::clone(method.as_closure()),
+        ));
+        self.push(Value::BoundMethod(bound))
     }
 
     fn bind_method(
@@ -569,24 +2667,13 @@ impl VM {
                 self.push(Value::BoundMethod(bound))?;
                 Ok(())
             }
-            None => Err(RuntimeError(format!(
+            None => Err(RuntimeError::Message(format!(
                 "Undefined property '{}'",
                 intern::str(name)
             ))),
         }
     }
 
-    fn next_instruction(&mut self) -> &OpCode {
-        let frame = self.frame_mut();
-        let (opcode, _) = frame.closure.fun.chunk.read_instruction(frame.ip);
-        frame.ip += 1;
-        &opcode
-    }
-
-    fn read_const(&self, idx: usize) -> &Value {
-        self.frame().closure.fun.chunk.read_const(idx)
-    }
-
     fn frame(&self) -> &CallFrame {
         self.frames.last().expect("Frames empty")
     }
@@ -608,7 +2695,7 @@ impl VM {
 
     fn push(&mut self, val: Value) -> Result<(), RuntimeError> {
         if self.stack.len() == MAX_STACK {
-            return Err(RuntimeError("Stack overflow".to_string()));
+            return Err(RuntimeError::Message("Stack overflow".to_string()));
         }
         self.stack.push(val);
         Ok(())
@@ -630,15 +2717,21 @@ impl VM {
     }
 
     /// Print out where execution stop right before the error
-    fn print_stack_trace(&self) {
+    fn print_stack_trace(&mut self) {
         for frame in self.frames.iter().rev() {
-            let (_, pos) = frame.closure.fun.chunk.read_instruction(frame.ip - 1);
+            let pos = frame.current_pos();
             let fname = intern::str(frame.closure.fun.name);
             if fname.is_empty() {
-                eprintln!("{} in script.", pos);
+                writeln!(self.error_output, "{} in script.", pos).ok();
             } else {
-                eprintln!("{} in {}().", pos, fname);
+                writeln!(self.error_output, "{} in {}().", pos, fname).ok();
             }
         }
     }
+
+    /// Write a problem to the error output
+    fn report<D: fmt::Display>(&mut self, problem: D) {
+        // there's nowhere left to report the problem if the error output fails
+        writeln!(self.error_output, "{}", problem).ok();
+    }
 }